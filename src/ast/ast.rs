@@ -0,0 +1,164 @@
+use crate::diagnostics::diagnostics::Span;
+use crate::symbols::symbols::{Conversion, Type};
+
+/// A whole translation unit: the top-level declarations in source order.
+/// `globals_size` is the number of bytes its global variables take.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unit {
+    pub items: Vec<Item>,
+    pub globals_size: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Struct(StructDecl),
+    Func(FuncDecl),
+    Var(VarDecl),
+    /// An `extern` variable, defined by another unit.
+    Extern(VarDecl),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructDecl {
+    pub name: String,
+    pub members: Vec<VarDecl>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncDecl {
+    pub name: String,
+    pub ret_type: Type,
+    pub params: Vec<VarDecl>,
+    /// `None` for a prototype.
+    pub body: Option<Box<Stmt>>,
+    pub span: Span,
+}
+
+/// A variable, struct member or function argument. `dim` is the expression
+/// written between the brackets of an array declaration, and `offset` is where
+/// the parser placed the variable, as in [`Symbol::offset`](crate::symbols::symbols::Symbol::offset).
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarDecl {
+    pub name: String,
+    pub r#type: Type,
+    pub dim: Option<Expr>,
+    pub offset: i64,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Compound(Vec<Stmt>),
+    Var(VarDecl),
+    If {
+        cond: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While {
+        cond: Expr,
+        body: Box<Stmt>,
+    },
+    For {
+        init: Option<Expr>,
+        cond: Option<Expr>,
+        step: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    Break,
+    Return(Option<Expr>),
+    Expr(Option<Expr>),
+}
+
+/// An expression together with the type computed for it by the parser.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub r#type: Option<Type>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Int(i64),
+    Real(f64),
+    Char(char),
+    Str(String),
+    Var(String),
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+    Index {
+        array: Box<Expr>,
+        index: Box<Expr>,
+    },
+    Member {
+        base: Box<Expr>,
+        member: String,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Assign {
+        target: Box<Expr>,
+        value: Box<Expr>,
+    },
+    Cast {
+        r#type: Type,
+        expr: Box<Expr>,
+    },
+    /// An implicit conversion, inserted by the parser where a value of one type is used
+    /// as another.
+    Convert {
+        conversion: Conversion,
+        expr: Box<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    NotEq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, r#type: Option<Type>, span: Span) -> Expr {
+        Expr { kind, r#type, span }
+    }
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Stmt {
+        Stmt { kind, span }
+    }
+}
@@ -0,0 +1,346 @@
+use std::fmt;
+
+use crate::lexer::dump::DumpFormat;
+use crate::vm::trace::TraceFormat;
+
+pub const USAGE: &str = "\
+usage: mycompiler <command> [options] <file>...
+
+commands:
+    lex              tokenize <file> and write one token per line
+    parse            parse <file> and print its syntax tree
+    check            parse every <file> and only report whether they are valid
+    run              compile every <file>, link them and execute the program in the VM
+    dump-bytecode    compile every <file>, link them and print the VM instructions
+
+options:
+    -o, --output <file>    write the command output to <file> instead of stdout
+    --color <when>         color diagnostics: auto, always or never (default auto)
+    --format <fmt>         lex: token dump format: text or json (default text)
+    --check <dump>         lex: compare the tokens with a saved dump instead of writing them
+    --trace <file>         run: write a record of every executed instruction to <file>
+    --trace-format <fmt>   run: trace record format: text or jsonl (default text)
+    -h, --help             print this message
+
+exit codes:
+    0    success
+    1    the program has compile or link errors, or its tokens differ from the --check dump
+    2    invalid command line
+    3    an input or output file could not be accessed
+    4    the program failed while running in the VM";
+
+pub const EXIT_SUCCESS: u8 = 0;
+pub const EXIT_COMPILE_ERROR: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_IO: u8 = 3;
+pub const EXIT_RUNTIME_ERROR: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Lex,
+    Parse,
+    Check,
+    Run,
+    DumpBytecode,
+}
+
+impl Command {
+    /// Whether the command accepts several input files, compiled separately.
+    fn takes_many_inputs(self) -> bool {
+        !matches!(self, Command::Lex | Command::Parse)
+    }
+
+    /// Whether the command uses `option`; the others are common to every command.
+    fn uses_option(self, option: &str) -> bool {
+        match option {
+            "--format" | "--check" => self == Command::Lex,
+            "--trace" | "--trace-format" => self == Command::Run,
+            _ => true,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Command::Lex => "lex",
+            Command::Parse => "parse",
+            Command::Check => "check",
+            Command::Run => "run",
+            Command::DumpBytecode => "dump-bytecode",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Command> {
+        match name {
+            "lex" => Some(Command::Lex),
+            "parse" => Some(Command::Parse),
+            "check" => Some(Command::Check),
+            "run" => Some(Command::Run),
+            "dump-bytecode" => Some(Command::DumpBytecode),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn from_name(name: &str) -> Option<ColorChoice> {
+        match name {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+}
+
+fn dump_format_from_name(name: &str) -> Option<DumpFormat> {
+    match name {
+        "text" => Some(DumpFormat::Text),
+        "json" => Some(DumpFormat::Json),
+        _ => None,
+    }
+}
+
+fn trace_format_from_name(name: &str) -> Option<TraceFormat> {
+    match name {
+        "text" => Some(TraceFormat::Text),
+        "jsonl" => Some(TraceFormat::JsonLines),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub command: Command,
+    /// The input files, in the order they are linked. `lex` and `parse` take exactly one.
+    pub inputs: Vec<String>,
+    pub output: Option<String>,
+    pub color: ColorChoice,
+    pub format: DumpFormat,
+    pub check: Option<String>,
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CliError {
+    Help,
+    MissingCommand,
+    UnknownCommand(String),
+    UnknownOption(String),
+    /// An option used only by other commands.
+    UnusedOption(String, Command),
+    MissingValue(String),
+    InvalidValue(String, String),
+    MissingInput,
+    UnexpectedArgument(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Help => write!(f, "{}", USAGE),
+            CliError::MissingCommand => write!(f, "missing command"),
+            CliError::UnknownCommand(name) => write!(f, "unknown command '{}'", name),
+            CliError::UnknownOption(name) => write!(f, "unknown option '{}'", name),
+            CliError::UnusedOption(name, command) => {
+                write!(f, "option '{}' does not apply to the '{}' command", name, command.name())
+            }
+            CliError::MissingValue(name) => write!(f, "option '{}' expects a value", name),
+            CliError::InvalidValue(name, value) => write!(f, "invalid value '{}' for option '{}'", value, name),
+            CliError::MissingInput => write!(f, "missing input file"),
+            CliError::UnexpectedArgument(arg) => write!(f, "unexpected argument '{}'", arg),
+        }
+    }
+}
+
+/// Parses the command line arguments (without the program name).
+pub fn parse_args<I>(args: I) -> Result<Options, CliError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+
+    let command = match args.next() {
+        Some(arg) if arg == "-h" || arg == "--help" => return Err(CliError::Help),
+        Some(arg) => Command::from_name(&arg).ok_or(CliError::UnknownCommand(arg))?,
+        None => return Err(CliError::MissingCommand),
+    };
+
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut color = ColorChoice::Auto;
+    let mut format = DumpFormat::Text;
+    let mut check = None;
+    let mut trace = None;
+    let mut trace_format = TraceFormat::Text;
+
+    while let Some(arg) = args.next() {
+        let option = arg.split('=').next().unwrap_or_default();
+        if !command.uses_option(option) {
+            return Err(CliError::UnusedOption(option.to_string(), command));
+        }
+
+        match arg.as_str() {
+            "-h" | "--help" => return Err(CliError::Help),
+            "-o" | "--output" => {
+                output = Some(args.next().ok_or(CliError::MissingValue(arg))?);
+            }
+            _ if arg.starts_with("--output=") => {
+                output = Some(arg["--output=".len()..].to_string());
+            }
+            "--color" => {
+                let value = args.next().ok_or(CliError::MissingValue(arg))?;
+                color = ColorChoice::from_name(&value)
+                    .ok_or(CliError::InvalidValue(String::from("--color"), value))?;
+            }
+            _ if arg.starts_with("--color=") => {
+                let value = &arg["--color=".len()..];
+                color = ColorChoice::from_name(value)
+                    .ok_or(CliError::InvalidValue(String::from("--color"), value.to_string()))?;
+            }
+            "--format" => {
+                let value = args.next().ok_or(CliError::MissingValue(arg))?;
+                format = dump_format_from_name(&value)
+                    .ok_or(CliError::InvalidValue(String::from("--format"), value))?;
+            }
+            _ if arg.starts_with("--format=") => {
+                let value = &arg["--format=".len()..];
+                format = dump_format_from_name(value)
+                    .ok_or(CliError::InvalidValue(String::from("--format"), value.to_string()))?;
+            }
+            "--check" => {
+                check = Some(args.next().ok_or(CliError::MissingValue(arg))?);
+            }
+            _ if arg.starts_with("--check=") => {
+                check = Some(arg["--check=".len()..].to_string());
+            }
+            "--trace" => {
+                trace = Some(args.next().ok_or(CliError::MissingValue(arg))?);
+            }
+            _ if arg.starts_with("--trace=") => {
+                trace = Some(arg["--trace=".len()..].to_string());
+            }
+            "--trace-format" => {
+                let value = args.next().ok_or(CliError::MissingValue(arg))?;
+                trace_format = trace_format_from_name(&value)
+                    .ok_or(CliError::InvalidValue(String::from("--trace-format"), value))?;
+            }
+            _ if arg.starts_with("--trace-format=") => {
+                let value = &arg["--trace-format=".len()..];
+                trace_format = trace_format_from_name(value)
+                    .ok_or(CliError::InvalidValue(String::from("--trace-format"), value.to_string()))?;
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => return Err(CliError::UnknownOption(arg)),
+            _ => {
+                if !inputs.is_empty() && !command.takes_many_inputs() {
+                    return Err(CliError::UnexpectedArgument(arg));
+                }
+                inputs.push(arg);
+            }
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err(CliError::MissingInput);
+    }
+
+    Ok(Options {
+        command,
+        inputs,
+        output,
+        color,
+        format,
+        check,
+        trace,
+        trace_format,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(args(&["lex", "res/9.c", "-o", "res/tokens.txt"])).unwrap();
+        assert_eq!(options.command, Command::Lex);
+        assert_eq!(options.inputs, ["res/9.c"]);
+        assert_eq!(options.output.as_deref(), Some("res/tokens.txt"));
+        assert_eq!(options.format, DumpFormat::Text);
+
+        let options = parse_args(args(&["lex", "--format=json", "--check", "res/tokens.json", "res/9.c"])).unwrap();
+        assert_eq!(options.format, DumpFormat::Json);
+        assert_eq!(options.check.as_deref(), Some("res/tokens.json"));
+
+        let options = parse_args(args(&["dump-bytecode", "--output=out.txt", "res/0.c"])).unwrap();
+        assert_eq!(options.command, Command::DumpBytecode);
+        assert_eq!(options.output.as_deref(), Some("out.txt"));
+        assert_eq!(options.color, ColorChoice::Auto);
+
+        let options = parse_args(args(&["check", "--color=never", "res/0.c"])).unwrap();
+        assert_eq!(options.color, ColorChoice::Never);
+        assert_eq!(options.trace, None);
+
+        let options = parse_args(args(&["run", "res/0.c", "--trace", "trace.txt"])).unwrap();
+        assert_eq!(options.trace.as_deref(), Some("trace.txt"));
+        assert_eq!(options.trace_format, TraceFormat::Text);
+
+        let options = parse_args(args(&["run", "--trace=trace.jsonl", "--trace-format=jsonl", "res/0.c"])).unwrap();
+        assert_eq!(options.trace.as_deref(), Some("trace.jsonl"));
+        assert_eq!(options.trace_format, TraceFormat::JsonLines);
+
+        let options = parse_args(args(&["run", "main.c", "-o", "out.txt", "list.c"])).unwrap();
+        assert_eq!(options.inputs, ["main.c", "list.c"]);
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert_eq!(parse_args(args(&[])), Err(CliError::MissingCommand));
+        assert_eq!(parse_args(args(&["compile", "a.c"])), Err(CliError::UnknownCommand(String::from("compile"))));
+        assert_eq!(parse_args(args(&["run"])), Err(CliError::MissingInput));
+        assert_eq!(parse_args(args(&["lex", "a.c", "b.c"])), Err(CliError::UnexpectedArgument(String::from("b.c"))));
+        assert_eq!(parse_args(args(&["parse", "a.c", "b.c"])), Err(CliError::UnexpectedArgument(String::from("b.c"))));
+        assert_eq!(parse_args(args(&["run", "a.c", "-o"])), Err(CliError::MissingValue(String::from("-o"))));
+        assert_eq!(parse_args(args(&["run", "--fast", "a.c"])), Err(CliError::UnknownOption(String::from("--fast"))));
+        assert_eq!(
+            parse_args(args(&["run", "--color", "blue", "a.c"])),
+            Err(CliError::InvalidValue(String::from("--color"), String::from("blue")))
+        );
+        assert_eq!(
+            parse_args(args(&["lex", "--format", "xml", "a.c"])),
+            Err(CliError::InvalidValue(String::from("--format"), String::from("xml")))
+        );
+        assert_eq!(parse_args(args(&["run", "a.c", "--trace"])), Err(CliError::MissingValue(String::from("--trace"))));
+        assert_eq!(
+            parse_args(args(&["run", "--trace-format", "xml", "a.c"])),
+            Err(CliError::InvalidValue(String::from("--trace-format"), String::from("xml")))
+        );
+        assert_eq!(
+            parse_args(args(&["run", "--format", "json", "a.c"])),
+            Err(CliError::UnusedOption(String::from("--format"), Command::Run))
+        );
+        assert_eq!(
+            parse_args(args(&["check", "a.c", "--check=tokens.txt"])),
+            Err(CliError::UnusedOption(String::from("--check"), Command::Check))
+        );
+        assert_eq!(
+            parse_args(args(&["lex", "--trace", "trace.txt", "a.c"])),
+            Err(CliError::UnusedOption(String::from("--trace"), Command::Lex))
+        );
+        assert_eq!(
+            parse_args(args(&["dump-bytecode", "--trace-format=jsonl", "a.c"])),
+            Err(CliError::UnusedOption(String::from("--trace-format"), Command::DumpBytecode))
+        );
+    }
+}
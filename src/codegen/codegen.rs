@@ -0,0 +1,849 @@
+use std::collections::HashMap;
+
+use crate::ast::ast::{BinaryOp, Expr, ExprKind, FuncDecl, Item, Stmt, StmtKind, UnaryOp, Unit, VarDecl};
+use crate::diagnostics::diagnostics::{Diagnostic, Span};
+use crate::linker::linker::{Export, Import, LinkType, Linkage, Signature};
+use crate::symbols::symbols::{Class, Conversion, SymbolTable, Type, TypeBase, PTR_SIZE};
+use crate::vm::vm::{Opcode, Vm};
+
+/// The shape of a value left on the VM stack by an expression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Val {
+    Int,
+    Double,
+    Char,
+    Addr,
+    Struct(i64),
+    Void,
+}
+
+impl Val {
+    fn size(self) -> i64 {
+        match self {
+            Val::Int | Val::Double | Val::Addr => 8,
+            Val::Char => 1,
+            Val::Struct(size) => size,
+            Val::Void => 0,
+        }
+    }
+
+    /// How the value is named in diagnostics, in terms of source types.
+    fn describe(self) -> &'static str {
+        match self {
+            Val::Int => "an int",
+            Val::Double => "a double",
+            Val::Char => "a char",
+            Val::Addr => "an array",
+            Val::Struct(_) => "a structure",
+            Val::Void => "a void value",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Location {
+    Global(usize),
+    /// An offset from the frame pointer: locals are above it, arguments below.
+    Frame(i64),
+    /// An `extern` variable, whose address is filled in by the linker.
+    Extern(String),
+}
+
+/// A variable visible to the generator. `n` is the element count of an
+/// array (`-1` for scalars); `by_ref` marks array arguments, whose slot
+/// holds the address of the array instead of the array itself.
+#[derive(Debug, Clone)]
+struct Var {
+    r#type: Type,
+    n: i64,
+    location: Location,
+    by_ref: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Callee {
+    /// The `ENTER` instruction of a compiled function, or the index of an external one.
+    /// `None` for a prototype, whose calls are resolved by the linker.
+    target: Option<usize>,
+    external: bool,
+    params: Vec<Type>,
+    ret: Type,
+}
+
+/// Lowers a parsed [`Unit`] into the instructions of a [`Vm`].
+pub struct CodeGen<'a> {
+    vm: &'a mut Vm,
+    symbols: &'a SymbolTable,
+    scopes: Vec<HashMap<String, Var>>,
+    funcs: HashMap<String, Callee>,
+    /// The address of the globals of the unit being generated.
+    globals: usize,
+    frame_size: i64,
+    args_size: i64,
+    ret_type: Type,
+    breaks: Vec<usize>,
+    labels: Vec<usize>,
+    fixups: Vec<(usize, usize)>,
+    linkage: Linkage,
+}
+
+impl<'a> CodeGen<'a> {
+    /// Creates a generator emitting into `vm`, which knows the external functions declared in `symbols`.
+    pub fn new(vm: &'a mut Vm, symbols: &'a SymbolTable) -> CodeGen<'a> {
+        let mut funcs = HashMap::new();
+        for symbol in symbols.iter().filter(|symbol| symbol.class == Class::ExtFunc) {
+            if let Some(target) = vm.native(&symbol.name) {
+                let params = symbol.args.iter()
+                    .flat_map(|args| args.iter())
+                    .filter_map(|arg| arg.r#type.clone())
+                    .collect();
+                let ret = symbol.r#type.clone().unwrap_or(Type::new(TypeBase::Void, -1));
+                funcs.insert(symbol.name.clone(), Callee { target: Some(target), external: true, params, ret });
+            }
+        }
+
+        CodeGen {
+            vm,
+            symbols,
+            scopes: vec![HashMap::new()],
+            funcs,
+            globals: 0,
+            frame_size: 0,
+            args_size: 0,
+            ret_type: Type::new(TypeBase::Void, -1),
+            breaks: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+            linkage: Linkage::default(),
+        }
+    }
+
+    /// Generates one unit, returning the symbols it defines and those it needs from the
+    /// [`Linker`](crate::linker::linker::Linker).
+    pub fn unit(&mut self, unit: &Unit) -> Result<Linkage, Diagnostic> {
+        self.globals = self.vm.alloc_global(unit.globals_size as usize)
+            .map_err(|e| Diagnostic::program_error(e.to_string()))?;
+
+        for item in &unit.items {
+            match item {
+                // the parser has already laid out the members in the symbol table
+                Item::Struct(_) => {}
+                Item::Var(decl) => self.decl_global(decl)?,
+                Item::Extern(decl) => self.decl_extern(decl)?,
+                Item::Func(decl) => self.decl_func(decl)?,
+            }
+        }
+
+        Ok(std::mem::take(&mut self.linkage))
+    }
+
+    fn decl_global(&mut self, decl: &VarDecl) -> Result<(), Diagnostic> {
+        let n = self.elements(decl, false)?;
+        let addr = self.globals + decl.offset as usize;
+        self.define(decl, n, Location::Global(addr), false);
+        self.linkage.exports.push(Export {
+            name: decl.name.clone(),
+            signature: Signature::Var(self.link_type(&decl.r#type)),
+            target: addr,
+            span: decl.span,
+        });
+        Ok(())
+    }
+
+    fn decl_extern(&mut self, decl: &VarDecl) -> Result<(), Diagnostic> {
+        if self.lookup(&decl.name).is_some_and(|var| matches!(var.location, Location::Global(_))) {
+            return Ok(());
+        }
+        let n = self.elements(decl, true)?;
+        self.define(decl, n, Location::Extern(decl.name.clone()), false);
+        Ok(())
+    }
+
+    fn decl_local(&mut self, decl: &VarDecl) -> Result<(), Diagnostic> {
+        let n = self.elements(decl, false)?;
+        self.frame_size = self.frame_size.max(decl.offset + self.symbols.type_size(&decl.r#type));
+        self.define(decl, n, Location::Frame(decl.offset), false);
+        Ok(())
+    }
+
+    fn decl_func(&mut self, decl: &FuncDecl) -> Result<(), Diagnostic> {
+        let Some(body) = &decl.body else {
+            self.funcs.entry(decl.name.clone()).or_insert(Callee {
+                target: None,
+                external: false,
+                params: decl.params.iter().map(|param| param.r#type.clone()).collect(),
+                ret: decl.ret_type.clone(),
+            });
+            return Ok(());
+        };
+
+        let mut params = Vec::new();
+        for param in &decl.params {
+            params.push((param, self.elements(param, true)?));
+        }
+
+        let enter = self.op_i(Opcode::OEnter, 0);
+        let callee = Callee {
+            target: Some(enter),
+            external: false,
+            params: decl.params.iter().map(|param| param.r#type.clone()).collect(),
+            ret: decl.ret_type.clone(),
+        };
+        self.linkage.exports.push(Export {
+            name: decl.name.clone(),
+            signature: self.signature(&callee),
+            target: enter,
+            span: decl.span,
+        });
+        self.funcs.insert(decl.name.clone(), callee);
+
+        self.scopes.push(HashMap::new());
+        self.args_size = decl.params.iter().map(|param| self.symbols.arg_size(&param.r#type)).sum();
+        self.frame_size = 0;
+        self.ret_type = decl.ret_type.clone();
+
+        for (param, n) in params {
+            self.define(param, n, Location::Frame(param.offset), n >= 0);
+        }
+
+        self.stm(body)?;
+        // a function returning a value must not fall off its end into whatever is on the stack
+        if self.ret_type.type_base == TypeBase::Void {
+            self.op_ii(Opcode::ORet, self.args_size, 0);
+        } else {
+            self.op(Opcode::ONoReturn);
+        }
+        self.vm.set_arg_i(enter, self.frame_size);
+
+        self.scopes.pop();
+        self.resolve_labels();
+        Ok(())
+    }
+
+    fn stm(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        match &stmt.kind {
+            StmtKind::Compound(stmts) => {
+                self.scopes.push(HashMap::new());
+                for stmt in stmts {
+                    self.stm(stmt)?;
+                }
+                self.scopes.pop();
+            }
+            StmtKind::Var(decl) => self.decl_local(decl)?,
+            StmtKind::If { cond, then_branch, else_branch } => {
+                let else_label = self.new_label();
+                self.cond_jump(cond, false, else_label)?;
+                self.stm(then_branch)?;
+                match else_branch {
+                    Some(else_branch) => {
+                        let end_label = self.new_label();
+                        self.jump(Opcode::OJmp, end_label);
+                        self.bind(else_label);
+                        self.stm(else_branch)?;
+                        self.bind(end_label);
+                    }
+                    None => self.bind(else_label),
+                }
+            }
+            StmtKind::While { cond, body } => {
+                let start_label = self.new_label();
+                let end_label = self.new_label();
+                self.bind(start_label);
+                self.cond_jump(cond, false, end_label)?;
+                self.breaks.push(end_label);
+                self.stm(body)?;
+                self.breaks.pop();
+                self.jump(Opcode::OJmp, start_label);
+                self.bind(end_label);
+            }
+            StmtKind::For { init, cond, step, body } => {
+                if let Some(init) = init {
+                    self.expr_stm(init)?;
+                }
+                let start_label = self.new_label();
+                let end_label = self.new_label();
+                self.bind(start_label);
+                if let Some(cond) = cond {
+                    self.cond_jump(cond, false, end_label)?;
+                }
+                self.breaks.push(end_label);
+                self.stm(body)?;
+                self.breaks.pop();
+                if let Some(step) = step {
+                    self.expr_stm(step)?;
+                }
+                self.jump(Opcode::OJmp, start_label);
+                self.bind(end_label);
+            }
+            StmtKind::Break => match self.breaks.last() {
+                Some(&end_label) => self.jump(Opcode::OJmp, end_label),
+                None => return Err(Diagnostic::error("break outside of a loop", stmt.span)),
+            },
+            StmtKind::Return(value) => {
+                let ret_size = match value {
+                    Some(value) => {
+                        let want = self.val_of(&self.ret_type.clone());
+                        let val = self.expr(value)?;
+                        self.convert(val, want, value.span)?;
+                        want.size()
+                    }
+                    None => 0,
+                };
+                self.op_ii(Opcode::ORet, self.args_size, ret_size);
+            }
+            StmtKind::Expr(Some(expr)) => self.expr_stm(expr)?,
+            StmtKind::Expr(None) => (),
+        }
+        Ok(())
+    }
+
+    /// Generates an expression whose value is not used.
+    fn expr_stm(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        let size = self.expr(expr)?.size();
+        if size > 0 {
+            self.op_i(Opcode::ODrop, size);
+        }
+        Ok(())
+    }
+
+    /// Pushes the value of `expr`. Arrays and strings are pushed as their address.
+    fn expr(&mut self, expr: &Expr) -> Result<Val, Diagnostic> {
+        match &expr.kind {
+            ExprKind::Int(value) => {
+                self.op_i(Opcode::OPushCtI, *value);
+                Ok(Val::Int)
+            }
+            ExprKind::Real(value) => {
+                self.op_d(Opcode::OPushCtD, *value);
+                Ok(Val::Double)
+            }
+            ExprKind::Char(value) => {
+                self.op_i(Opcode::OPushCtC, *value as i64);
+                Ok(Val::Char)
+            }
+            ExprKind::Str(value) => {
+                let mut bytes = value.clone().into_bytes();
+                bytes.push(0);
+                let addr = self.vm.alloc_data(&bytes)
+                    .map_err(|e| Diagnostic::error(e.to_string(), expr.span))?;
+                self.op_a(Opcode::OPushCtA, addr);
+                Ok(Val::Addr)
+            }
+            ExprKind::Var(_) | ExprKind::Index { .. } | ExprKind::Member { .. } => {
+                let (r#type, n) = self.addr(expr)?;
+                if n >= 0 {
+                    return Ok(Val::Addr);
+                }
+                let val = self.val_of(&r#type);
+                self.op_i(Opcode::OLoad, val.size());
+                Ok(val)
+            }
+            ExprKind::Call { name, args } => {
+                let Some(callee) = self.funcs.get(name).cloned() else {
+                    return Err(Diagnostic::error(format!("{} has no code to call", name), expr.span));
+                };
+                for (arg, param) in args.iter().zip(&callee.params) {
+                    let want = self.val_of(param);
+                    let val = self.expr(arg)?;
+                    self.convert(val, want, arg.span)?;
+                }
+                match callee.target {
+                    Some(target) if callee.external => {
+                        self.op_a(Opcode::OCallExt, target);
+                    }
+                    Some(target) => {
+                        self.op_a(Opcode::OCall, target);
+                    }
+                    None => {
+                        let instr = self.op_a(Opcode::OCall, 0);
+                        self.linkage.imports.push(Import {
+                            name: name.clone(),
+                            signature: self.signature(&callee),
+                            instr,
+                            span: expr.span,
+                        });
+                    }
+                }
+                Ok(self.val_of(&callee.ret))
+            }
+            ExprKind::Unary { op: UnaryOp::Neg, operand } => {
+                let want = Self::promote(self.expr_val(operand), Val::Int);
+                let val = self.expr(operand)?;
+                self.convert(val, want, operand.span)?;
+                let opcode = Self::typed(want, [Opcode::ONegI, Opcode::ONegD, Opcode::ONegC]);
+                self.typed_op(opcode, want, "-", expr.span)?;
+                Ok(want)
+            }
+            ExprKind::Unary { op: UnaryOp::Not, operand } => {
+                let val = self.expr(operand)?;
+                let opcode = Self::typed(val, [Opcode::ONotI, Opcode::ONotD, Opcode::ONotC]);
+                self.typed_op(opcode, val, "!", expr.span)?;
+                Ok(Val::Int)
+            }
+            ExprKind::Binary { op: BinaryOp::And, lhs, rhs } => self.logical(false, lhs, rhs),
+            ExprKind::Binary { op: BinaryOp::Or, lhs, rhs } => self.logical(true, lhs, rhs),
+            ExprKind::Binary { op, lhs, rhs } => {
+                // the parser has already converted both operands to the type of the operation
+                let val = self.expr(lhs)?;
+                self.expr(rhs)?;
+                self.arith(*op, val, expr.span)
+            }
+            ExprKind::Assign { target, value } => {
+                let (r#type, n) = self.addr(target)?;
+                if n >= 0 {
+                    return Err(Diagnostic::error("cannot assign to an array", target.span));
+                }
+                let want = self.val_of(&r#type);
+                let val = self.expr(value)?;
+                self.convert(val, want, value.span)?;
+                // keep a copy of the value below the address, as the result of the assignment
+                self.op_ii(Opcode::OInsert, PTR_SIZE + want.size(), want.size());
+                self.op_i(Opcode::OStore, want.size());
+                Ok(want)
+            }
+            ExprKind::Cast { r#type, expr: operand } => {
+                let want = self.val_of(r#type);
+                let val = self.expr(operand)?;
+                self.convert(val, want, operand.span)?;
+                Ok(want)
+            }
+            ExprKind::Convert { conversion, expr: operand } => {
+                let val = self.expr(operand)?;
+                let (opcode, val) = match conversion {
+                    Conversion::None => return Ok(val),
+                    Conversion::CharToInt => (Opcode::OCastCI, Val::Int),
+                    Conversion::CharToDouble => (Opcode::OCastCD, Val::Double),
+                    Conversion::IntToChar => (Opcode::OCastIC, Val::Char),
+                    Conversion::IntToDouble => (Opcode::OCastID, Val::Double),
+                    Conversion::DoubleToInt => (Opcode::OCastDI, Val::Int),
+                    Conversion::DoubleToChar => (Opcode::OCastDC, Val::Char),
+                };
+                self.op(opcode);
+                Ok(val)
+            }
+        }
+    }
+
+    /// Pushes the address of the object designated by `expr` and returns its
+    /// type together with its element count (`-1` if it is not an array).
+    fn addr(&mut self, expr: &Expr) -> Result<(Type, i64), Diagnostic> {
+        match &expr.kind {
+            ExprKind::Var(name) => {
+                let Some(var) = self.lookup(name).cloned() else {
+                    return Err(Diagnostic::error(format!("undefined variable {}", name), expr.span));
+                };
+                match &var.location {
+                    Location::Global(addr) => {
+                        self.op_a(Opcode::OPushCtA, *addr);
+                    }
+                    Location::Frame(offset) => {
+                        self.op_i(Opcode::OPushFpAddr, *offset);
+                    }
+                    Location::Extern(name) => {
+                        let instr = self.op_a(Opcode::OPushCtA, 0);
+                        self.linkage.imports.push(Import {
+                            name: name.clone(),
+                            signature: Signature::Var(self.link_type(&var.r#type)),
+                            instr,
+                            span: expr.span,
+                        });
+                    }
+                }
+                if var.by_ref {
+                    self.op_i(Opcode::OLoad, PTR_SIZE);
+                }
+                Ok((var.r#type, var.n))
+            }
+            ExprKind::Index { array, index } => {
+                let (mut r#type, n) = self.addr(array)?;
+                if n < 0 {
+                    return Err(Diagnostic::error("only an array can be indexed", array.span));
+                }
+                r#type.n_elements = -1;
+                let val = self.expr(index)?;
+                self.convert(val, Val::Int, index.span)?;
+                let size = self.symbols.type_size(&r#type);
+                if size != 1 {
+                    self.op_i(Opcode::OPushCtI, size);
+                    self.op(Opcode::OMulI);
+                }
+                self.op(Opcode::OOffset);
+                Ok((r#type, -1))
+            }
+            ExprKind::Member { base, member } => {
+                let (r#type, _) = self.addr(base)?;
+                let field = r#type.s
+                    .and_then(|s| self.symbols.symbol(s).members.as_ref()?.find_symbol(member))
+                    .and_then(|field| Some((field.offset, field.r#type.clone()?)));
+                let Some((offset, r#type)) = field else {
+                    return Err(Diagnostic::error(format!("no member {}", member), expr.span));
+                };
+                self.op_i(Opcode::OPushCtI, offset);
+                self.op(Opcode::OOffset);
+                let n = r#type.n_elements as i64;
+                Ok((r#type, n))
+            }
+            _ => Err(Diagnostic::error("the expression has no address", expr.span)),
+        }
+    }
+
+    /// Jumps to `label` if the truth value of `expr` is `on_true`.
+    fn cond_jump(&mut self, expr: &Expr, on_true: bool, label: usize) -> Result<(), Diagnostic> {
+        let val = match self.expr(expr)? {
+            Val::Char => {
+                self.op(Opcode::OCastCI);
+                Val::Int
+            }
+            val => val,
+        };
+        let opcode = match (val, on_true) {
+            (Val::Int, false) => Opcode::OJfI,
+            (Val::Int, true) => Opcode::OJtI,
+            (Val::Double, false) => Opcode::OJfD,
+            (Val::Double, true) => Opcode::OJtD,
+            _ => return Err(Diagnostic::error("the expression cannot be used as a condition", expr.span)),
+        };
+        self.jump(opcode, label);
+        Ok(())
+    }
+
+    /// `&&` (`on_true` false) and `||` (`on_true` true), short-circuited.
+    fn logical(&mut self, on_true: bool, lhs: &Expr, rhs: &Expr) -> Result<Val, Diagnostic> {
+        let shortcut_label = self.new_label();
+        let end_label = self.new_label();
+        self.cond_jump(lhs, on_true, shortcut_label)?;
+        self.cond_jump(rhs, on_true, shortcut_label)?;
+        self.op_i(Opcode::OPushCtI, !on_true as i64);
+        self.jump(Opcode::OJmp, end_label);
+        self.bind(shortcut_label);
+        self.op_i(Opcode::OPushCtI, on_true as i64);
+        self.bind(end_label);
+        Ok(Val::Int)
+    }
+
+    /// Emits the instruction of a binary operator whose operands, of type `val`, are on the stack.
+    fn arith(&mut self, op: BinaryOp, val: Val, span: Span) -> Result<Val, Diagnostic> {
+        let (opcodes, symbol) = match op {
+            BinaryOp::Add => ([Opcode::OAddI, Opcode::OAddD, Opcode::OAddC], "+"),
+            BinaryOp::Sub => ([Opcode::OSubI, Opcode::OSubD, Opcode::OSubC], "-"),
+            BinaryOp::Mul => ([Opcode::OMulI, Opcode::OMulD, Opcode::OMulC], "*"),
+            BinaryOp::Div => ([Opcode::ODivI, Opcode::ODivD, Opcode::ODivC], "/"),
+            BinaryOp::Eq => ([Opcode::OEqI, Opcode::OEqD, Opcode::OEqC], "=="),
+            BinaryOp::NotEq => ([Opcode::ONotEqI, Opcode::ONotEqD, Opcode::ONotEqC], "!="),
+            BinaryOp::Less => ([Opcode::OLessI, Opcode::OLessD, Opcode::OLessC], "<"),
+            BinaryOp::LessEq => ([Opcode::OLessEqI, Opcode::OLessEqD, Opcode::OLessEqC], "<="),
+            BinaryOp::Greater => ([Opcode::OGreaterI, Opcode::OGreaterD, Opcode::OGreaterC], ">"),
+            BinaryOp::GreaterEq => ([Opcode::OGreaterEqI, Opcode::OGreaterEqD, Opcode::OGreaterEqC], ">="),
+            BinaryOp::And | BinaryOp::Or => unreachable!("logical operators are generated by logical()"),
+        };
+        self.typed_op(Self::typed(val, opcodes), val, symbol, span)?;
+        match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => Ok(val),
+            _ => Ok(Val::Int),
+        }
+    }
+
+    /// Picks the int, double or char variant of an instruction family.
+    fn typed(val: Val, [int, double, char]: [Opcode; 3]) -> Option<Opcode> {
+        match val {
+            Val::Int => Some(int),
+            Val::Double => Some(double),
+            Val::Char => Some(char),
+            _ => None,
+        }
+    }
+
+    fn typed_op(&mut self, opcode: Option<Opcode>, val: Val, symbol: &str, span: Span) -> Result<(), Diagnostic> {
+        match opcode {
+            Some(opcode) => {
+                self.op(opcode);
+                Ok(())
+            }
+            None => Err(Diagnostic::error(format!("'{}' cannot be applied to {}", symbol, val.describe()), span)),
+        }
+    }
+
+    /// Converts the value on top of the stack from `from` to `to`.
+    fn convert(&mut self, from: Val, to: Val, span: Span) -> Result<(), Diagnostic> {
+        let opcodes: &[Opcode] = match (from, to) {
+            _ if from == to => &[],
+            (Val::Int, Val::Double) => &[Opcode::OCastID],
+            (Val::Int, Val::Char) => &[Opcode::OCastIC],
+            (Val::Double, Val::Int) => &[Opcode::OCastDI],
+            (Val::Double, Val::Char) => &[Opcode::OCastDC],
+            (Val::Char, Val::Int) => &[Opcode::OCastCI],
+            (Val::Char, Val::Double) => &[Opcode::OCastCD],
+            _ => return Err(Diagnostic::error(format!("cannot convert {} to {}", from.describe(), to.describe()), span)),
+        };
+        for &opcode in opcodes {
+            self.op(opcode);
+        }
+        Ok(())
+    }
+
+    /// The type arithmetic on two operands is done in: chars are promoted to int.
+    fn promote(lhs: Val, rhs: Val) -> Val {
+        if lhs == Val::Double || rhs == Val::Double {
+            Val::Double
+        } else {
+            Val::Int
+        }
+    }
+
+    fn expr_val(&self, expr: &Expr) -> Val {
+        match &expr.r#type {
+            Some(r#type) => self.val_of(r#type),
+            None => Val::Int,
+        }
+    }
+
+    fn val_of(&self, r#type: &Type) -> Val {
+        if r#type.n_elements >= 0 {
+            return Val::Addr;
+        }
+        match r#type.type_base {
+            TypeBase::Int => Val::Int,
+            TypeBase::Double => Val::Double,
+            TypeBase::Char => Val::Char,
+            TypeBase::Struct => Val::Struct(self.symbols.type_size(r#type)),
+            TypeBase::Void => Val::Void,
+        }
+    }
+
+    /// How the other units see `r#type`, where its structure is only known by name.
+    fn link_type(&self, r#type: &Type) -> LinkType {
+        LinkType {
+            type_base: r#type.type_base.clone(),
+            array: r#type.n_elements >= 0,
+            struct_name: r#type.s.map(|s| self.symbols.symbol(s).name.clone()),
+        }
+    }
+
+    fn signature(&self, callee: &Callee) -> Signature {
+        Signature::Func {
+            params: callee.params.iter().map(|param| self.link_type(param)).collect(),
+            ret: self.link_type(&callee.ret),
+        }
+    }
+
+    /// The element count of a declared variable, `-1` if it is not an array.
+    /// Only function arguments and `extern` declarations may leave the dimension out.
+    fn elements(&self, decl: &VarDecl, is_arg: bool) -> Result<i64, Diagnostic> {
+        match decl.r#type.n_elements {
+            n if n < 0 => Ok(-1),
+            0 if is_arg => Ok(0),
+            0 => Err(Diagnostic::error(format!("the array {} needs a dimension", decl.name), decl.span)),
+            n => Ok(n as i64),
+        }
+    }
+
+    fn define(&mut self, decl: &VarDecl, n: i64, location: Location, by_ref: bool) {
+        let var = Var { r#type: decl.r#type.clone(), n, location, by_ref };
+        self.scopes.last_mut().unwrap().insert(decl.name.clone(), var);
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Var> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.labels.push(usize::MAX);
+        self.labels.len() - 1
+    }
+
+    /// Makes `label` refer to the next emitted instruction.
+    fn bind(&mut self, label: usize) {
+        self.labels[label] = self.vm.next_index();
+    }
+
+    fn jump(&mut self, opcode: Opcode, label: usize) {
+        let instr = self.op_a(opcode, 0);
+        self.fixups.push((instr, label));
+    }
+
+    /// Points the jumps of the current function at their labels.
+    fn resolve_labels(&mut self) {
+        for (instr, label) in self.fixups.drain(..) {
+            self.vm.set_target(instr, self.labels[label]);
+        }
+        self.labels.clear();
+    }
+
+    fn op(&mut self, opcode: Opcode) -> usize {
+        self.vm.add_instr(opcode)
+    }
+
+    fn op_i(&mut self, opcode: Opcode, val: i64) -> usize {
+        self.vm.add_instr_i(opcode, val)
+    }
+
+    fn op_ii(&mut self, opcode: Opcode, val1: i64, val2: i64) -> usize {
+        self.vm.add_instr_ii(opcode, val1, val2)
+    }
+
+    fn op_d(&mut self, opcode: Opcode, val: f64) -> usize {
+        self.vm.add_instr_d(opcode, val)
+    }
+
+    fn op_a(&mut self, opcode: Opcode, addr: usize) -> usize {
+        self.vm.add_instr_a(opcode, addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lexer::Lexer;
+    use crate::linker::linker::Linker;
+    use crate::parser::parser::Parser;
+    use crate::token::token::Token;
+
+    fn dump(source: &str) -> Result<String, Diagnostic> {
+        let tokens: Vec<Token> = Lexer::new(source.to_string()).collect();
+
+        let mut parser = Parser::new(tokens);
+        let unit = parser.unit().unwrap();
+        let mut vm = Vm::new();
+        let mut linker = Linker::new(&mut vm);
+        linker.add(CodeGen::new(&mut vm, &parser.symbols_table).unit(&unit)?);
+        linker.link(&mut vm).map_err(|mut diagnostics| diagnostics.remove(0))?;
+        let mut out = Vec::new();
+        vm.dump(&mut out).unwrap();
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_codegen() {
+        let source = "
+            int f(int a, char b[]){ return a+b[1]; }
+            void main(){ int i; char s[2]; for(i=0;i<2;i=i+1) f(i, s); }
+        ";
+        let dump = dump(source).unwrap();
+        let code: Vec<&str> = dump.lines().map(|line| line.trim_start()).collect();
+        assert_eq!(code, [
+            "0  CALL\t14",
+            "1  HALT",
+            "2  ENTER\t0",
+            "3  PUSHFPADDR\t-32",
+            "4  LOAD\t8",
+            "5  PUSHFPADDR\t-24",
+            "6  LOAD\t8",
+            "7  PUSHCT_I\t1",
+            "8  OFFSET",
+            "9  LOAD\t1",
+            "10  CAST_C_I",
+            "11  ADD_I",
+            "12  RET\t16,8",
+            "13  NORETURN",
+            "14  ENTER\t10",
+            "15  PUSHFPADDR\t0",
+            "16  PUSHCT_I\t0",
+            "17  INSERT\t16,8",
+            "18  STORE\t8",
+            "19  DROP\t8",
+            "20  PUSHFPADDR\t0",
+            "21  LOAD\t8",
+            "22  PUSHCT_I\t2",
+            "23  LESS_I",
+            "24  JF_I\t39",
+            "25  PUSHFPADDR\t0",
+            "26  LOAD\t8",
+            "27  PUSHFPADDR\t8",
+            "28  CALL\t2",
+            "29  DROP\t8",
+            "30  PUSHFPADDR\t0",
+            "31  PUSHFPADDR\t0",
+            "32  LOAD\t8",
+            "33  PUSHCT_I\t1",
+            "34  ADD_I",
+            "35  INSERT\t16,8",
+            "36  STORE\t8",
+            "37  DROP\t8",
+            "38  JMP\t20",
+            "39  RET\t0,0",
+        ]);
+    }
+
+    #[test]
+    fn test_codegen_ops() {
+        let source = "
+            double d;
+            void main(){ char c; c='a'; if(d) c=-c; while(!c) d=-d; }
+        ";
+        let dump = dump(source).unwrap();
+        let code: Vec<&str> = dump.lines().map(|line| line.trim_start()).collect();
+        assert_eq!(code, [
+            "0  CALL\t2",
+            "1  HALT",
+            "2  ENTER\t1",
+            "3  PUSHFPADDR\t0",
+            "4  PUSHCT_C\t'a'",
+            "5  INSERT\t9,1",
+            "6  STORE\t1",
+            "7  DROP\t1",
+            "8  PUSHCT_A\t0",
+            "9  LOAD\t8",
+            "10  JF_D\t20",
+            "11  PUSHFPADDR\t0",
+            "12  PUSHFPADDR\t0",
+            "13  LOAD\t1",
+            "14  CAST_C_I",
+            "15  NEG_I",
+            "16  CAST_I_C",
+            "17  INSERT\t9,1",
+            "18  STORE\t1",
+            "19  DROP\t1",
+            "20  PUSHFPADDR\t0",
+            "21  LOAD\t1",
+            "22  NOT_C",
+            "23  JF_I\t32",
+            "24  PUSHCT_A\t0",
+            "25  PUSHCT_A\t0",
+            "26  LOAD\t8",
+            "27  NEG_D",
+            "28  INSERT\t16,8",
+            "29  STORE\t8",
+            "30  DROP\t8",
+            "31  JMP\t20",
+            "32  RET\t0,0",
+        ]);
+    }
+
+    #[test]
+    fn test_codegen_messages() {
+        let mut vm = Vm::new();
+        let symbols = SymbolTable::new();
+        let mut codegen = CodeGen::new(&mut vm, &symbols);
+        let error = codegen.typed_op(None, Val::Addr, "!", Span::default()).unwrap_err();
+        assert_eq!(error.message, "'!' cannot be applied to an array");
+        let error = codegen.convert(Val::Void, Val::Int, Span::default()).unwrap_err();
+        assert_eq!(error.message, "cannot convert a void value to an int");
+    }
+
+    #[test]
+    fn test_codegen_folding() {
+        let source = "
+            void main(){ double d; d = 1 + 2 * 3 - 'a' / 2.0; d = (int)2.9 < 3 && !0; put_c((char)(65 + 1)); }
+        ";
+        let dump = dump(source).unwrap();
+        let code: Vec<&str> = dump.lines().map(|line| line.trim_start()).collect();
+        assert_eq!(code, [
+            "0  CALL\t2",
+            "1  HALT",
+            "2  ENTER\t8",
+            "3  PUSHFPADDR\t0",
+            "4  PUSHCT_D\t-41.5",
+            "5  INSERT\t16,8",
+            "6  STORE\t8",
+            "7  DROP\t8",
+            "8  PUSHFPADDR\t0",
+            "9  PUSHCT_D\t1",
+            "10  INSERT\t16,8",
+            "11  STORE\t8",
+            "12  DROP\t8",
+            "13  PUSHCT_C\t'B'",
+            "14  CALLEXT\tput_c",
+            "15  RET\t0,0",
+        ]);
+    }
+}
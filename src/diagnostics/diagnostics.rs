@@ -0,0 +1,316 @@
+use std::fmt;
+
+use crate::token::token::Token;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A range of source text: the bytes `start..end` of the file `file_id` in a [`SourceMap`].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub file_id: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file_id: usize, start: usize, end: usize) -> Span {
+        Span { file_id, start, end }
+    }
+
+    pub fn from_token(token: &Token) -> Span {
+        token.span
+    }
+
+    /// Extends the span up to the end of `other` when both are in the same file.
+    pub fn to(self, other: Span) -> Span {
+        if other.file_id == self.file_id && other.end >= self.start {
+            Span::new(self.file_id, self.start, other.end)
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Where the problem is, if it is about one place of the source.
+    pub span: Option<Span>,
+    pub notes: Vec<Note>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span: Some(span),
+            notes: Vec::new(),
+        }
+    }
+
+    /// An error about the program as a whole, such as a missing `main`.
+    pub fn program_error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: message.into(),
+            span: Some(span),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_note(mut self, message: impl Into<String>, span: Option<Span>) -> Diagnostic {
+        self.notes.push(Note { message: message.into(), span });
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+/// The text of a compiled file, kept next to its tokens so diagnostics can quote it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> SourceFile {
+        let text = text.into();
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(offset, _)| offset + 1))
+            .collect();
+
+        SourceFile {
+            name: name.into(),
+            text,
+            line_starts,
+        }
+    }
+
+    /// Returns the 1-based `line` without its line terminator.
+    pub fn line(&self, line: usize) -> Option<&str> {
+        if line < 1 {
+            return None;
+        }
+        self.text.lines().nth(line - 1)
+    }
+
+    /// Converts a byte offset to a 1-based line and a 1-based column counted in characters.
+    pub fn line_column(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        let line_start = self.line_starts[line - 1];
+        let column = self.text.get(line_start..offset).map_or(0, |text| text.chars().count());
+
+        (line, column + 1)
+    }
+}
+
+/// Every file of a compilation, addressed by the `file_id` of a [`Span`].
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { files: Vec::new() }
+    }
+
+    /// Adds a file and returns its id.
+    pub fn add(&mut self, name: impl Into<String>, text: impl Into<String>) -> usize {
+        self.files.push(SourceFile::new(name, text));
+        self.files.len() - 1
+    }
+
+    pub fn file(&self, file_id: usize) -> Option<&SourceFile> {
+        self.files.get(file_id)
+    }
+
+    /// Returns the 1-based line and column where `span` starts.
+    pub fn line_column(&self, span: Span) -> Option<(usize, usize)> {
+        self.file(span.file_id).map(|file| file.line_column(span.start))
+    }
+}
+
+const RESET: &str = "\x1B[0m";
+const BOLD: &str = "\x1B[1m";
+const BOLD_RED: &str = "\x1B[1;31m";
+const BOLD_YELLOW: &str = "\x1B[1;33m";
+const BOLD_GREEN: &str = "\x1B[1;32m";
+const BOLD_BLUE: &str = "\x1B[1;34m";
+
+/// Renders diagnostics the way rustc does: a header, the location and the
+/// quoted source line with the offending range underlined.
+pub struct Renderer {
+    color: bool,
+}
+
+impl Renderer {
+    pub fn new(color: bool) -> Renderer {
+        Renderer { color }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic, sources: &SourceMap) -> String {
+        let severity_color = match diagnostic.severity {
+            Severity::Error => BOLD_RED,
+            Severity::Warning => BOLD_YELLOW,
+        };
+
+        let max_line = diagnostic.notes.iter()
+            .filter_map(|note| note.span)
+            .chain(diagnostic.span)
+            .filter_map(|span| sources.line_column(span))
+            .map(|(line, _)| line)
+            .max()
+            .unwrap_or(1);
+        let gutter = max_line.max(1).to_string().len();
+
+        let mut out = format!(
+            "{}{}",
+            self.paint(severity_color, &diagnostic.severity.to_string()),
+            self.paint(BOLD, &format!(": {}", diagnostic.message)),
+        );
+        if let Some(span) = diagnostic.span {
+            self.snippet(&mut out, sources, span, '^', severity_color, gutter);
+        }
+
+        for note in &diagnostic.notes {
+            match note.span {
+                Some(span) => {
+                    out.push_str(&format!("\n{}: {}", self.paint(BOLD_GREEN, "note"), note.message));
+                    self.snippet(&mut out, sources, span, '-', BOLD_BLUE, gutter);
+                }
+                None => {
+                    out.push_str(&format!(
+                        "\n{} {} {}: {}",
+                        " ".repeat(gutter),
+                        self.paint(BOLD_BLUE, "="),
+                        self.paint(BOLD, "note"),
+                        note.message,
+                    ));
+                }
+            }
+        }
+
+        out
+    }
+
+    fn snippet(&self, out: &mut String, sources: &SourceMap, span: Span, underline: char, color: &str, gutter: usize) {
+        let pad = " ".repeat(gutter);
+        let bar = self.paint(BOLD_BLUE, "|");
+
+        let Some(source) = sources.file(span.file_id) else {
+            return;
+        };
+        let (line, column) = source.line_column(span.start);
+        out.push_str(&format!("\n{}{} {}:{}:{}", pad, self.paint(BOLD_BLUE, "-->"), source.name, line, column));
+
+        let Some(text) = source.line(line) else {
+            return;
+        };
+
+        // keep the tabs of the quoted line so the underline stays aligned
+        let indent: String = text.chars()
+            .take(column - 1)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        // only the part of the span on its first line is underlined
+        let len = text.chars().skip(column - 1).count().min(
+            source.text.get(span.start..span.end.max(span.start)).map_or(1, |text| text.chars().count()),
+        );
+        let marks = underline.to_string().repeat(len.max(1));
+
+        out.push_str(&format!("\n{} {}", pad, bar));
+        out.push_str(&format!("\n{} {} {}", self.paint(BOLD_BLUE, &format!("{:>width$}", line, width = gutter)), bar, text));
+        out.push_str(&format!("\n{} {} {}{}", pad, bar, indent, self.paint(color, &marks)));
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut sources = SourceMap::new();
+        let file_id = sources.add("a.c", "void main()\n{\n\tint x;\n\tdouble x;\n}\n");
+        let diagnostic = Diagnostic::error("symbol redefinition: x", Span::new(file_id, 30, 31))
+            .with_note("previous definition of x is here", Some(Span::new(file_id, 19, 20)));
+
+        let expected = "\
+error: symbol redefinition: x
+ --> a.c:4:9
+  |
+4 | \tdouble x;
+  | \t       ^
+note: previous definition of x is here
+ --> a.c:3:6
+  |
+3 | \tint x;
+  | \t    -";
+        assert_eq!(Renderer::new(false).render(&diagnostic, &sources), expected);
+
+        let diagnostic = Diagnostic::program_error("the program has no main function");
+        assert_eq!(Renderer::new(false).render(&diagnostic, &sources), "error: the program has no main function");
+    }
+
+    #[test]
+    fn test_source_map() {
+        let mut sources = SourceMap::new();
+        sources.add("a.c", "int x;\n");
+        let file_id = sources.add("b.c", "a\n\u{e9}b\n\nc");
+
+        let file = sources.file(file_id).unwrap();
+        assert_eq!(file.line_column(0), (1, 1));
+        assert_eq!(file.line_column(2), (2, 1));
+        assert_eq!(file.line_column(4), (2, 2));
+        assert_eq!(file.line_column(6), (3, 1));
+        assert_eq!(file.line_column(7), (4, 1));
+        assert_eq!(file.line_column(100), (4, 2));
+        assert_eq!(sources.line_column(Span::new(file_id, 4, 5)), Some((2, 2)));
+        assert_eq!(sources.line_column(Span::new(2, 0, 0)), None);
+        assert_eq!(Span::new(file_id, 2, 3).to(Span::new(file_id, 6, 8)), Span::new(file_id, 2, 8));
+    }
+}
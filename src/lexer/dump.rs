@@ -0,0 +1,187 @@
+//! Stable token dumps, used to diff the lexer output across changes.
+//!
+//! The text format writes one token per line as `line:column<TAB>KIND<TAB>literal`,
+//! where the literal is the source text of the token with `\`, tabs and line breaks
+//! escaped as `\\`, `\t`, `\n` and `\r`. A token read from an included file has the
+//! name of that file in front, as `file:line:column`. The JSON format is an array holding
+//! one `{"file", "kind", "literal", "line", "column"}` object per line. Both formats end
+//! with the `EOF` token, which belongs to the root file, and use 1-based lines and columns
+//! of the file each token was read from.
+
+use std::fmt::Write as _;
+
+use crate::diagnostics::diagnostics::SourceMap;
+use crate::token::token::Token;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    Text,
+    Json,
+}
+
+/// The first line where a dump differs from the expected one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    /// The 1-based line of the dump.
+    pub line: usize,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+/// Writes `tokens`, whose spans point into `sources`, in `format`.
+pub fn dump(tokens: &[Token], sources: &SourceMap, format: DumpFormat) -> String {
+    let mut out = String::new();
+
+    if format == DumpFormat::Json {
+        out.push_str("[\n");
+    }
+    let root = tokens.last().map_or(0, |token| token.span.file_id);
+    for (i, token) in tokens.iter().enumerate() {
+        let (line, column) = sources.line_column(token.span).unwrap_or((0, 0));
+        let file = sources.file(token.span.file_id).map_or("", |file| file.name.as_str());
+        match format {
+            DumpFormat::Text => {
+                if token.span.file_id != root {
+                    write!(out, "{}:", escape(file)).unwrap();
+                }
+                writeln!(out, "{}:{}\t{}\t{}", line, column, token.r#type.name(), escape(&token.literal)).unwrap();
+            }
+            DumpFormat::Json => {
+                let separator = if i + 1 < tokens.len() { "," } else { "" };
+                writeln!(
+                    out,
+                    r#"  {{"file":{},"kind":"{}","literal":{},"line":{},"column":{}}}{}"#,
+                    json_string(file), token.r#type.name(), json_string(&token.literal), line, column, separator,
+                ).unwrap();
+            }
+        }
+    }
+    if format == DumpFormat::Json {
+        out.push_str("]\n");
+    }
+
+    out
+}
+
+/// Compares a fresh dump with a saved one, ignoring `\r` line endings.
+pub fn compare(expected: &str, actual: &str) -> Result<(), Mismatch> {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+
+    for line in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (None, None) => return Ok(()),
+            (expected, actual) if expected != actual => {
+                return Err(Mismatch {
+                    line,
+                    expected: expected.map(str::to_string),
+                    actual: actual.map(str::to_string),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn escape(literal: &str) -> String {
+    let mut out = String::new();
+    for ch in literal.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in text.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => write!(out, "\\u{:04x}", ch as u32).unwrap(),
+            _ => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lexer::Lexer;
+    use crate::preprocessor::preprocessor::Preprocessor;
+    use std::io;
+    use std::path::Path;
+
+    #[test]
+    fn test_dump() {
+        let text = "int x;\n\tput_s(\"a\\tb\");";
+        let mut sources = SourceMap::new();
+        sources.add("a.c", text);
+        let tokens: Vec<Token> = Lexer::new(text.to_string()).collect();
+
+        assert_eq!(dump(&tokens, &sources, DumpFormat::Text), "\
+1:1\tINT\tint
+1:5\tID\tx
+1:6\tSEMICOLON\t;
+2:2\tID\tput_s
+2:7\tLPAR\t(
+2:8\tCT_STRING\t\"a\\\\tb\"
+2:14\tRPAR\t)
+2:15\tSEMICOLON\t;
+2:16\tEOF\tEOF
+");
+
+        let json = dump(&tokens[..2], &sources, DumpFormat::Json);
+        assert_eq!(json, "[\n  {\"file\":\"a.c\",\"kind\":\"INT\",\"literal\":\"int\",\"line\":1,\"column\":1},\n  {\"file\":\"a.c\",\"kind\":\"ID\",\"literal\":\"x\",\"line\":1,\"column\":5}\n]\n");
+    }
+
+    #[test]
+    fn test_included_dump() {
+        let read = |path: &Path| -> io::Result<String> {
+            assert_eq!(path, Path::new("b.h"));
+            Ok(String::from("int y;"))
+        };
+        let mut sources = SourceMap::new();
+        let file_id = sources.add("a.c", "#include \"b.h\"\nint x;");
+        let tokens = Preprocessor::new(&mut sources, &read).run(file_id);
+
+        assert_eq!(dump(&tokens, &sources, DumpFormat::Text), "\
+b.h:1:1\tINT\tint
+b.h:1:5\tID\ty
+b.h:1:6\tSEMICOLON\t;
+2:1\tINT\tint
+2:5\tID\tx
+2:6\tSEMICOLON\t;
+2:7\tEOF\tEOF
+");
+
+        let json = dump(&tokens[2..4], &sources, DumpFormat::Json);
+        assert_eq!(json, "[\n  {\"file\":\"b.h\",\"kind\":\"SEMICOLON\",\"literal\":\";\",\"line\":1,\"column\":6},\n  {\"file\":\"a.c\",\"kind\":\"INT\",\"literal\":\"int\",\"line\":2,\"column\":1}\n]\n");
+    }
+
+    #[test]
+    fn test_saved_dump() {
+        let text = include_str!("../../res/9.c");
+        let mut sources = SourceMap::new();
+        sources.add("res/9.c", text);
+        let tokens: Vec<Token> = Lexer::new(text.to_string()).collect();
+
+        assert_eq!(compare(include_str!("../../res/tokens.txt"), &dump(&tokens, &sources, DumpFormat::Text)), Ok(()));
+        assert_eq!(
+            compare("1:1\tINT\tint\n", "1:1\tINT\tint\n1:4\tEOF\tEOF\n"),
+            Err(Mismatch { line: 2, expected: None, actual: Some(String::from("1:4\tEOF\tEOF")) })
+        );
+    }
+}
@@ -1,395 +1,316 @@
+use std::io::{self, Read};
+
+use crate::diagnostics::diagnostics::{Diagnostic, Span};
 use crate::token::token;
-use crate::token::token::{Token, TokenType};
+use crate::token::token::{IllegalReason, Token, TokenType, TokenValue};
 
+/// Splits a whole source buffer into tokens, ending with a single `EOF` token.
 pub struct Lexer {
-    input: String,
+    input: Vec<char>,
     position: usize,
-    read_position: usize,
     ch: char,
-    line: i32,
-    column: i32,
-    block_comment: bool,
+    file_id: usize,
+    offset: usize,
+    done: bool,
 }
 
 impl Lexer {
     pub fn new(input: String) -> Lexer {
-        let mut lexer = Lexer {
+        let input: Vec<char> = input.chars().collect();
+        let ch = input.first().copied().unwrap_or('\0');
+
+        Lexer {
             input,
             position: 0,
-            read_position: 0,
-            ch: '\0',
-            line: 0,
-            column: 0,
-            block_comment: false,
-        };
-
-        lexer
+            ch,
+            file_id: 0,
+            offset: 0,
+            done: false,
+        }
     }
 
-    pub fn get_line(&mut self) -> i32 {
-        return self.line;
+    /// Sets the file of the [`SourceMap`](crate::diagnostics::diagnostics::SourceMap)
+    /// the spans of the tokens point into.
+    pub fn with_file_id(mut self, file_id: usize) -> Lexer {
+        self.file_id = file_id;
+        self
     }
 
-    pub fn set_input(&mut self, input: String) {
-        self.input = input;
-        self.position = 0;
-        self.read_position = 0;
-        self.ch = '\0';
-        self.line += 1;
-        self.column = 0;
-
-        self.read_char();
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Lexer> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+        Ok(Lexer::new(input))
     }
 
     pub fn next_token(&mut self) -> Token {
-        self.skip_whitespace();
-
-        if self.block_comment {
-            self.skip_comment();
-        }
+        let comment = self.skip_whitespace_and_comments();
 
         let mut tok = Token {
-            r#type: TokenType::ILLEGAL,
+            r#type: TokenType::EOF,
             literal: String::from(""),
-            line: self.line,
-            column: self.column,
+            span: Span::new(self.file_id, self.offset, self.offset),
+            value: TokenValue::None,
         };
 
-        match self.ch {
-            ',' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::COMMA;
-            }
-
-            ';' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::SEMICOLON;
-            }
-
-            '(' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::LPAR;
-            }
-
-            ')' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::RPAR;
-            }
-
-            '[' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::LBRACKET;
-            }
-
-            ']' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::RBRACKET;
-            }
-
-            '{' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::LACC;
-            }
-
-            '}' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::RACC;
-            }
-
-            '+' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::ADD;
-            }
-
-            '-' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::SUB;
-            }
-
-            '*' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::MUL;
-            }
-
-            '/' => {
-                if self.peek_char() == '/' {
-                    self.read_char();
-                    self.skip_line_comment();
-                    return self.next_token();
-                } else if self.peek_char() == '*' {
-                    self.read_char();
-                    self.block_comment = true;
-                    return self.next_token();
-                } else {
-                    tok.literal = self.ch.to_string();
-                    tok.r#type = TokenType::DIV;
-                }
-            }
+        if let Some((position, offset)) = comment {
+            tok.literal = self.slice(position, self.position);
+            tok.r#type = TokenType::ILLEGAL(IllegalReason::UnterminatedComment);
+            tok.span = Span::new(self.file_id, offset, self.offset);
+            return tok;
+        }
 
-            '.' => {
-                tok.literal = self.ch.to_string();
-                tok.r#type = TokenType::DOT;
-            }
+        match self.ch {
+            ',' => self.single(&mut tok, TokenType::COMMA),
+            ';' => self.single(&mut tok, TokenType::SEMICOLON),
+            '(' => self.single(&mut tok, TokenType::LPAR),
+            ')' => self.single(&mut tok, TokenType::RPAR),
+            '[' => self.single(&mut tok, TokenType::LBRACKET),
+            ']' => self.single(&mut tok, TokenType::RBRACKET),
+            '{' => self.single(&mut tok, TokenType::LACC),
+            '}' => self.single(&mut tok, TokenType::RACC),
+            '+' => self.single(&mut tok, TokenType::ADD),
+            '-' => self.single(&mut tok, TokenType::SUB),
+            '*' => self.single(&mut tok, TokenType::MUL),
+            '/' => self.single(&mut tok, TokenType::DIV),
+            '.' => self.single(&mut tok, TokenType::DOT),
 
             '|' => {
                 if self.peek_char() == '|' {
-                    let ch = self.ch;
-                    self.read_char();
-                    tok.literal = format!("{}{}", ch, self.ch);
-                    tok.r#type = TokenType::OR;
+                    self.double(&mut tok, TokenType::OR);
+                } else {
+                    self.single(&mut tok, TokenType::ILLEGAL(IllegalReason::UnexpectedCharacter('|')));
                 }
             }
 
             '&' => {
                 if self.peek_char() == '&' {
-                    let ch = self.ch;
-                    self.read_char();
-                    tok.literal = format!("{}{}", ch, self.ch);
-                    tok.r#type = TokenType::AND;
+                    self.double(&mut tok, TokenType::AND);
+                } else {
+                    self.single(&mut tok, TokenType::ILLEGAL(IllegalReason::UnexpectedCharacter('&')));
                 }
             }
 
             '!' => {
                 if self.peek_char() == '=' {
-                    let ch = self.ch;
-                    self.read_char();
-                    tok.literal = format!("{}{}", ch, self.ch);
-                    tok.r#type = TokenType::NOTEQ;
+                    self.double(&mut tok, TokenType::NOTEQ);
                 } else {
-                    tok.literal = self.ch.to_string();
-                    tok.r#type = TokenType::NOT;
+                    self.single(&mut tok, TokenType::NOT);
                 }
             }
 
             '<' => {
                 if self.peek_char() == '=' {
-                    let ch = self.ch;
-                    self.read_char();
-                    tok.literal = format!("{}{}", ch, self.ch);
-                    tok.r#type = TokenType::LESSEQ;
+                    self.double(&mut tok, TokenType::LESSEQ);
                 } else {
-                    tok.literal = self.ch.to_string();
-                    tok.r#type = TokenType::LESS;
+                    self.single(&mut tok, TokenType::LESS);
                 }
             }
 
             '>' => {
                 if self.peek_char() == '=' {
-                    let ch = self.ch;
-                    self.read_char();
-                    tok.literal = format!("{}{}", ch, self.ch);
-                    tok.r#type = TokenType::GREATEREQ;
+                    self.double(&mut tok, TokenType::GREATEREQ);
                 } else {
-                    tok.literal = self.ch.to_string();
-                    tok.r#type = TokenType::GREATER;
+                    self.single(&mut tok, TokenType::GREATER);
                 }
             }
 
             '=' => {
                 if self.peek_char() == '=' {
-                    let ch = self.ch;
-                    self.read_char();
-                    tok.literal = format!("{}{}", ch, self.ch);
-                    tok.r#type = TokenType::EQUAL;
+                    self.double(&mut tok, TokenType::EQUAL);
                 } else {
-                    tok.literal = self.ch.to_string();
-                    tok.r#type = TokenType::ASSIGN;
+                    self.single(&mut tok, TokenType::ASSIGN);
                 }
             }
 
-            '0' => {
-               if self.peek_char() == 'x' {
-                   tok.literal = self.read_hex();
-                   tok.r#type = TokenType::CT_INT;
-               } else if self.is_octal(self.peek_char()) {
-                   tok.literal = self.read_octal();
-                   tok.r#type = TokenType::CT_INT;
-               } else if self.peek_char() == '.' {
-                    let int_part = self.read_int();
-                    self.read_position += 1;
-                    let real_part = self.read_real();
-
-                    match real_part {
-                        Some(real_number) => {
-                            tok.literal = format!("{int_part}{real_number}");
-                            tok.r#type = TokenType::CT_REAL;
-                        }    
-                        None => {
-                            tok.r#type = TokenType::ILLEGAL;
-                        }
-                    }
-               } else {
-                    tok.literal = self.ch.to_string();
-                    tok.r#type = TokenType::CT_INT;
-               }
-            }
-
-            '1'..='9' => {
-                let int_part = self.read_int().to_string();
-
-                if self.ch == '.' {
-                    self.read_position += 1;
-                    let real_part = self.read_real();
-
-                    match real_part {
-                        Some(real_number) => {
-                            tok.literal = format!("{int_part}{real_number}");
-                            tok.r#type = TokenType::CT_REAL;
-                        }    
-                        None => {
-                            tok.r#type = TokenType::ILLEGAL;
-                        }
-                    }
-                } else if self.ch == 'e' || self.ch == 'E' {
-                    self.read_position += 1;
-                    let real_part = self.read_real();
-
-                    match real_part {
-                        Some(real_number) => {
-                            tok.literal = format!("{int_part}{real_number}");
-                            tok.r#type = TokenType::CT_REAL;
-                        }    
-                        None => {
-                            tok.r#type = TokenType::ILLEGAL;
-                        }
+            '0'..='9' => {
+                let position = self.position;
+                match self.read_number() {
+                    Ok((r#type, value)) => {
+                        tok.r#type = r#type;
+                        tok.value = value;
                     }
-                } else {
-                    tok.literal = int_part;
-                    tok.r#type = TokenType::CT_INT;
+                    Err(reason) => tok.r#type = TokenType::ILLEGAL(reason),
                 }
+                tok.literal = self.slice(position, self.position);
             }
 
             '\'' => {
+                let position = self.position;
                 let char_literal = self.read_char_literal();
 
                 match char_literal {
-                    Some(valid_char_literal) => {
-                       tok.literal = format!("{valid_char_literal}");
-                       tok.r#type = TokenType::CT_CHAR;
+                    Ok(valid_char_literal) => {
+                        tok.r#type = TokenType::CT_CHAR;
+                        tok.value = TokenValue::Char(valid_char_literal);
                     }
 
-                    None => {
-                        tok.r#type = TokenType::ILLEGAL;
+                    Err(reason) => {
+                        tok.r#type = TokenType::ILLEGAL(reason);
                     }
                 }
+                tok.literal = self.slice(position, self.position);
             }
 
             '\"' => {
+                let position = self.position;
                 let string_literal = self.read_string();
 
                 match string_literal {
-                    Some(valid_string_literal) => {
-                        tok.literal = valid_string_literal;
+                    Ok(valid_string_literal) => {
                         tok.r#type = TokenType::CT_STRING;
+                        tok.value = TokenValue::Str(valid_string_literal);
                     }
 
-                    None => {
-                        tok.r#type = TokenType::ILLEGAL;
+                    Err(reason) => {
+                        tok.r#type = TokenType::ILLEGAL(reason);
                     }
                 }
-            } 
+                tok.literal = self.slice(position, self.position);
+            }
 
             'a'..='z' | 'A'..='Z' | '_' => {
-               tok.literal = self.read_identifier(); 
-               tok.r#type = token::lookup_identifier(&tok.literal.clone());
+                tok.literal = self.read_identifier();
+                tok.r#type = token::lookup_identifier(&tok.literal);
             }
 
-            '\0' => {
+            _ if self.at_end() => {
+                tok.literal = String::from("EOF");
                 tok.r#type = TokenType::EOF;
             }
 
-            _ => unreachable!("Not a valid token (for now at least)")
+            ch => self.single(&mut tok, TokenType::ILLEGAL(IllegalReason::UnexpectedCharacter(ch))),
         }
 
+        tok.span.end = self.offset;
+        tok
+    }
+
+    /// Makes `tok` the current character and moves past it.
+    fn single(&mut self, tok: &mut Token, r#type: TokenType) {
+        tok.literal = self.ch.to_string();
+        tok.r#type = r#type;
         self.read_char();
+    }
 
-        tok
+    /// Makes `tok` the current and the next character and moves past them.
+    fn double(&mut self, tok: &mut Token, r#type: TokenType) {
+        tok.literal = format!("{}{}", self.ch, self.peek_char());
+        tok.r#type = r#type;
+        self.read_char();
+        self.read_char();
     }
 
     fn peek_char(&self) -> char {
-        if self.read_position >= self.input.len() {
-            '\0'
-        } else {
-            self.input.chars().nth(self.read_position).unwrap()
-        }
+        self.input.get(self.position + 1).copied().unwrap_or('\0')
     }
 
     fn read_char(&mut self) {
-        if self.read_position >= self.input.len() {
-            self.ch = '\0';
-        } else {
-            self.ch = self.input.chars().nth(self.read_position).unwrap();
+        if self.position >= self.input.len() {
+            return;
         }
 
-        self.position = self.read_position;
-        self.read_position += 1;
-        if self.ch == '\n' {
-            self.line += 1;
-            self.column = 0;
-        } else {
-            self.column += 1;
-        }
+        self.offset += self.ch.len_utf8();
+        self.position += 1;
+        self.ch = self.input.get(self.position).copied().unwrap_or('\0');
     }
 
-    fn read_char_literal(&mut self) -> Option<String> {
-        let position = self.position;
+    fn slice(&self, start: usize, end: usize) -> String {
+        self.input[start..end].iter().collect()
+    }
 
+    /// Reads a quoted character. On error the rest of the literal on the same line is skipped.
+    fn read_char_literal(&mut self) -> Result<char, IllegalReason> {
         // consume ' char
         self.read_char();
+        let mut value = self.ch;
+        let mut error = None;
 
-        if self.ch == '\\' {
-            self.read_char();
-            if self.is_escape(self.ch) {
+        match self.ch {
+            '\'' => error = Some(IllegalReason::InvalidChar),
+            '\n' | '\r' => return Err(IllegalReason::UnterminatedChar),
+            _ if self.at_end() => return Err(IllegalReason::UnterminatedChar),
+            '\\' => {
+                self.read_char();
+                if self.ch == '\n' || self.at_end() {
+                    return Err(IllegalReason::UnterminatedChar);
+                }
+                let escape = self.escape(self.ch);
+                error = escape.is_none().then_some(IllegalReason::BadEscape(self.ch));
+                value = escape.unwrap_or(self.ch);
                 self.read_char();
-            } else {
-                return None;
             }
-        } else if self.ch == '\'' {
-            return None;
-        } else if self.ch.is_ascii() {
-            self.read_char();
+            ch if !ch.is_ascii() => {
+                error = Some(IllegalReason::UnexpectedCharacter(ch));
+                self.read_char();
+            }
+            _ => self.read_char(),
         }
 
-        if self.ch == '\'' {
-            self.read_char();
-        } else {
-            return None;
+        if self.ch != '\'' {
+            error = error.or(Some(IllegalReason::InvalidChar));
+            if !self.skip_to_quote('\'') {
+                return Err(IllegalReason::UnterminatedChar);
+            }
         }
+        self.read_char();
 
-        self.read_position -= 1;
-        Some(self.input[position + 1..self.position - 1].to_string())
+        match error {
+            Some(reason) => Err(reason),
+            None => Ok(value),
+        }
     }
 
-    fn read_string(&mut self) -> Option<String> {
-        let position = self.position;
-
-        //consume " char
+    /// Reads a quoted string. An unterminated string ends at the end of its line.
+    fn read_string(&mut self) -> Result<String, IllegalReason> {
+        // consume " char
         self.read_char();
-
-        while let Some(ch) = self.input.chars().nth(self.position) {
-            if ch == '\\' {
-                self.read_char();
-                if self.is_escape(ch) {
+        let mut value = String::new();
+        let mut error = None;
+
+        loop {
+            match self.ch {
+                '"' => break,
+                '\n' | '\r' => return Err(IllegalReason::UnterminatedString),
+                _ if self.at_end() => return Err(IllegalReason::UnterminatedString),
+                '\\' => {
                     self.read_char();
-                } else {
-                    return None;
+                    if self.ch == '\n' || self.at_end() {
+                        return Err(IllegalReason::UnterminatedString);
+                    }
+                    match self.escape(self.ch) {
+                        Some(ch) => value.push(ch),
+                        None => error = error.or(Some(IllegalReason::BadEscape(self.ch))),
+                    }
                 }
-            } else if ch == '"' {
-                if self.peek_char() == '"' {
-                    return None;
+                ch if !ch.is_ascii() => {
+                    error = error.or(Some(IllegalReason::UnexpectedCharacter(ch)));
                 }
-                self.read_char();
+                ch => value.push(ch),
+            }
+            self.read_char();
+        }
+
+        self.read_char();
 
-                self.read_position -= 1;
-                return Some(self.input[position + 1..self.position - 1].to_string());
-            } else if ch.is_ascii() {
+        match error {
+            Some(reason) => Err(reason),
+            None => Ok(value),
+        }
+    }
+
+    /// Moves to the next `quote` on the current line, returning whether there is one.
+    fn skip_to_quote(&mut self, quote: char) -> bool {
+        while self.ch != quote {
+            if self.ch == '\n' || self.at_end() {
+                return false;
+            }
+            if self.ch == '\\' {
                 self.read_char();
-            } else {
-                return None;
+                continue;
             }
+            self.read_char();
         }
-
-        None
+        true
     }
 
     fn read_identifier(&mut self) -> String {
@@ -397,49 +318,61 @@ impl Lexer {
 
         while self.ch.is_alphanumeric() || self.ch == '_' {
             self.read_char();
-        } 
-
-        self.read_position -= 1;
-        self.input[position..self.position].to_string()
-    }
-    fn read_int(&mut self) -> String {
-        let position = self.position;
-        while self.is_decimal(self.ch) {
-            self.read_char();
         }
 
-        self.read_position -= 1;
-
-        self.input[position..self.position].to_string()
+        self.slice(position, self.position)
     }
 
-    fn read_octal(&mut self) -> String {
+    fn read_number(&mut self) -> Result<(TokenType, TokenValue), IllegalReason> {
         let position = self.position;
-        while self.is_octal(self.ch) {
+
+        if self.ch == '0' && (self.peek_char() == 'x' || self.peek_char() == 'X') {
+            self.read_char();
             self.read_char();
+            let digits = self.position;
+            while self.is_hex(self.ch) {
+                self.read_char();
+            }
+            if self.position == digits {
+                return Err(IllegalReason::MalformedHex);
+            }
+            return self.int_value(digits, 16);
         }
 
-        self.read_position -= 1;
-        self.input[position..self.position].to_string()
-    }
-
-    fn read_hex(&mut self) -> String {
-        let position = self.position;
-
-        self.read_char(); 
-        self.read_char();
-        
-        while self.is_hex(self.ch) {
+        // a leading 0 makes an integer octal, but a real like 09.5 may still follow
+        let octal = self.ch == '0' && self.is_decimal(self.peek_char());
+        while self.is_decimal(self.ch) {
             self.read_char();
         }
 
-        self.read_position -= 1;
-        self.input[position..self.position].to_string()
+        if self.ch != '.' && self.ch != 'e' && self.ch != 'E' {
+            if !octal {
+                return self.int_value(position, 10);
+            }
+            if !self.slice(position, self.position).chars().all(|ch| self.is_octal(ch)) {
+                return Err(IllegalReason::MalformedOctal);
+            }
+            return self.int_value(position, 8);
+        }
+
+        if !self.read_real() {
+            return Err(IllegalReason::MalformedReal);
+        }
+        match self.slice(position, self.position).parse::<f64>() {
+            Ok(number) if number.is_finite() => Ok((TokenType::CT_REAL, TokenValue::Real(number))),
+            _ => Err(IllegalReason::RealOutOfRange),
+        }
     }
 
-    fn read_real(&mut self) -> Option<String> {
-        let position = self.position;
+    fn int_value(&self, digits: usize, radix: u32) -> Result<(TokenType, TokenValue), IllegalReason> {
+        match i64::from_str_radix(&self.slice(digits, self.position), radix) {
+            Ok(number) => Ok((TokenType::CT_INT, TokenValue::Int(number))),
+            Err(_) => Err(IllegalReason::IntegerOutOfRange),
+        }
+    }
 
+    /// Reads the fraction and exponent of a real number, returning whether they are well formed.
+    fn read_real(&mut self) -> bool {
         if self.ch == '.' {
             self.read_char();
 
@@ -451,11 +384,10 @@ impl Lexer {
             }
 
             if !encountered_digits {
-                return None;
+                return false;
             }
         }
 
-
         if self.ch == 'e' || self.ch == 'E' {
             self.read_char();
 
@@ -471,55 +403,260 @@ impl Lexer {
             }
 
             if !encountered_exp_digits {
-                return None;
+                return false;
             }
         }
 
-        self.read_position -= 1;
-        Some(self.input[position..self.position].to_string())
+        true
     }
 
-    fn is_escape(&self, ch: char) -> bool {
-        matches!(ch, 'a' | 'b' | 'f' | 'n' | 'r' | 't' | 'v' | '\'' | '?' | '\"' | '\\' | '0')    
+    /// Returns the character written as `\ch`, if that is a valid escape sequence.
+    fn escape(&self, ch: char) -> Option<char> {
+        match ch {
+            'a' => Some('\x07'),
+            'b' => Some('\x08'),
+            'f' => Some('\x0c'),
+            'n' => Some('\n'),
+            'r' => Some('\r'),
+            't' => Some('\t'),
+            'v' => Some('\x0b'),
+            '0' => Some('\0'),
+            '\'' | '?' | '"' | '\\' => Some(ch),
+            _ => None,
+        }
     }
-    
+
     fn is_decimal(&self, ch: char) -> bool {
-        ch >= '0' && ch <= '9'
+        ch.is_ascii_digit()
     }
 
     fn is_octal(&self, ch: char) -> bool {
-        ch >= '0' && ch <= '7'
+        ('0'..='7').contains(&ch)
     }
-    
+
     fn is_hex(&self, ch: char) -> bool {
-        (ch >= '0' && ch <= '9') || (ch >= 'a' && ch <= 'f') || (ch >= 'A' && ch <= 'F')
+        ch.is_ascii_hexdigit()
     }
 
-    fn skip_whitespace(&mut self) {
-        while self.ch == ' ' || self.ch == '\t' || self.ch == '\n' || self.ch == '\r' {
-            self.read_char();
+    fn at_end(&self) -> bool {
+        self.position >= self.input.len()
+    }
+
+    /// Skips blanks and comments. Returns the start of a block comment which never ends.
+    fn skip_whitespace_and_comments(&mut self) -> Option<(usize, usize)> {
+        loop {
+            match self.ch {
+                ' ' | '\t' | '\n' | '\r' => self.read_char(),
+                '/' if self.peek_char() == '/' => self.skip_line_comment(),
+                '/' if self.peek_char() == '*' => {
+                    let start = (self.position, self.offset);
+                    if !self.skip_comment() {
+                        return Some(start);
+                    }
+                }
+                _ => return None,
+            }
         }
     }
 
     fn skip_line_comment(&mut self) {
-        while self.ch != '\n' && self.ch != '\r' && self.ch != '\0' {
+        while self.ch != '\n' && !self.at_end() {
             self.read_char();
         }
     }
 
-    fn skip_comment(&mut self) {
+    fn skip_comment(&mut self) -> bool {
+        // consume /* chars
+        self.read_char();
+        self.read_char();
+
         while !(self.ch == '*' && self.peek_char() == '/') {
-            if self.ch == '\0' {
-                return;
+            if self.at_end() {
+                return false;
             }
             self.read_char();
         }
 
-        if self.ch == '*' && self.peek_char() == '/' {
-            self.read_char(); 
-            self.read_char(); 
+        self.read_char();
+        self.read_char();
+        true
+    }
+}
+
+/// Turns every `ILLEGAL` token into an error diagnostic.
+pub fn diagnostics(tokens: &[Token]) -> Vec<Diagnostic> {
+    tokens
+        .iter()
+        .filter_map(|token| match &token.r#type {
+            TokenType::ILLEGAL(reason) => Some(Diagnostic::error(reason.to_string(), Span::from_token(token))),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Iterator for Lexer {
+    type Item = Token;
 
-            self.block_comment = false;
+    fn next(&mut self) -> Option<Token> {
+        if self.done {
+            return None;
         }
+
+        let token = self.next_token();
+        self.done = token.r#type == TokenType::EOF;
+        Some(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::diagnostics::SourceFile;
+
+    fn lex(source: &str) -> Vec<(TokenType, String, usize, usize)> {
+        let file = SourceFile::new("a.c", source);
+        Lexer::new(source.to_string())
+            .map(|token| {
+                let (line, column) = file.line_column(token.span.start);
+                (token.r#type, token.literal, line, column)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_positions() {
+        let tokens = lex("int x;\n  /* a\n comment */ x = 1.5e3; // end\n\"s\\n\"");
+        assert_eq!(tokens, vec![
+            (TokenType::INT, String::from("int"), 1, 1),
+            (TokenType::ID, String::from("x"), 1, 5),
+            (TokenType::SEMICOLON, String::from(";"), 1, 6),
+            (TokenType::ID, String::from("x"), 3, 13),
+            (TokenType::ASSIGN, String::from("="), 3, 15),
+            (TokenType::CT_REAL, String::from("1.5e3"), 3, 17),
+            (TokenType::SEMICOLON, String::from(";"), 3, 22),
+            (TokenType::CT_STRING, String::from("\"s\\n\""), 4, 1),
+            (TokenType::EOF, String::from("EOF"), 4, 6),
+        ]);
+    }
+
+    #[test]
+    fn test_literals() {
+        let types: Vec<TokenType> = lex("0 017 0x1F 0.5 2e-3 'a' '\\'' && ||")
+            .into_iter()
+            .map(|(r#type, ..)| r#type)
+            .collect();
+        assert_eq!(types, vec![
+            TokenType::CT_INT,
+            TokenType::CT_INT,
+            TokenType::CT_INT,
+            TokenType::CT_REAL,
+            TokenType::CT_REAL,
+            TokenType::CT_CHAR,
+            TokenType::CT_CHAR,
+            TokenType::AND,
+            TokenType::OR,
+            TokenType::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_real_literals() {
+        // a fraction and an exponent start a real literal the same way
+        let tokens: Vec<(TokenType, String, TokenValue)> = Lexer::new(String::from("1.5 1e3 1E3 25.5e-1 7e+1 3.0E2 1. 1e 2e+"))
+            .map(|token| (token.r#type, token.literal, token.value))
+            .collect();
+        assert_eq!(tokens, vec![
+            (TokenType::CT_REAL, String::from("1.5"), TokenValue::Real(1.5)),
+            (TokenType::CT_REAL, String::from("1e3"), TokenValue::Real(1000.0)),
+            (TokenType::CT_REAL, String::from("1E3"), TokenValue::Real(1000.0)),
+            (TokenType::CT_REAL, String::from("25.5e-1"), TokenValue::Real(2.55)),
+            (TokenType::CT_REAL, String::from("7e+1"), TokenValue::Real(70.0)),
+            (TokenType::CT_REAL, String::from("3.0E2"), TokenValue::Real(300.0)),
+            (TokenType::ILLEGAL(IllegalReason::MalformedReal), String::from("1."), TokenValue::None),
+            (TokenType::ILLEGAL(IllegalReason::MalformedReal), String::from("1e"), TokenValue::None),
+            (TokenType::ILLEGAL(IllegalReason::MalformedReal), String::from("2e+"), TokenValue::None),
+            (TokenType::EOF, String::from("EOF"), TokenValue::None),
+        ]);
+    }
+
+    #[test]
+    fn test_digit_classes() {
+        let tokens: Vec<(TokenType, String, TokenValue)> =
+            Lexer::new(String::from("0.75 0.5e1 9.0e09 0777 078 09.5 0xfF9 0xG 0e1"))
+                .map(|token| (token.r#type, token.literal, token.value))
+                .collect();
+        // an 8 or a 9 makes an octal integer malformed, but not a real with a leading 0
+        assert_eq!(tokens, vec![
+            (TokenType::CT_REAL, String::from("0.75"), TokenValue::Real(0.75)),
+            (TokenType::CT_REAL, String::from("0.5e1"), TokenValue::Real(5.0)),
+            (TokenType::CT_REAL, String::from("9.0e09"), TokenValue::Real(9e9)),
+            (TokenType::CT_INT, String::from("0777"), TokenValue::Int(511)),
+            (TokenType::ILLEGAL(IllegalReason::MalformedOctal), String::from("078"), TokenValue::None),
+            (TokenType::CT_REAL, String::from("09.5"), TokenValue::Real(9.5)),
+            (TokenType::CT_INT, String::from("0xfF9"), TokenValue::Int(4089)),
+            (TokenType::ILLEGAL(IllegalReason::MalformedHex), String::from("0x"), TokenValue::None),
+            (TokenType::ID, String::from("G"), TokenValue::None),
+            (TokenType::CT_REAL, String::from("0e1"), TokenValue::Real(0.0)),
+            (TokenType::EOF, String::from("EOF"), TokenValue::None),
+        ]);
+    }
+
+    #[test]
+    fn test_illegal() {
+        let tokens = lex("x @ y | 1. 0x '' 'ab' '\\q' \"a\\qb\"\n\"open\nz; /* never");
+        assert_eq!(tokens, vec![
+            (TokenType::ID, String::from("x"), 1, 1),
+            (TokenType::ILLEGAL(IllegalReason::UnexpectedCharacter('@')), String::from("@"), 1, 3),
+            (TokenType::ID, String::from("y"), 1, 5),
+            (TokenType::ILLEGAL(IllegalReason::UnexpectedCharacter('|')), String::from("|"), 1, 7),
+            (TokenType::ILLEGAL(IllegalReason::MalformedReal), String::from("1."), 1, 9),
+            (TokenType::ILLEGAL(IllegalReason::MalformedHex), String::from("0x"), 1, 12),
+            (TokenType::ILLEGAL(IllegalReason::InvalidChar), String::from("''"), 1, 15),
+            (TokenType::ILLEGAL(IllegalReason::InvalidChar), String::from("'ab'"), 1, 18),
+            (TokenType::ILLEGAL(IllegalReason::BadEscape('q')), String::from("'\\q'"), 1, 23),
+            (TokenType::ILLEGAL(IllegalReason::BadEscape('q')), String::from("\"a\\qb\""), 1, 28),
+            (TokenType::ILLEGAL(IllegalReason::UnterminatedString), String::from("\"open"), 2, 1),
+            (TokenType::ID, String::from("z"), 3, 1),
+            (TokenType::SEMICOLON, String::from(";"), 3, 2),
+            (TokenType::ILLEGAL(IllegalReason::UnterminatedComment), String::from("/* never"), 3, 4),
+            (TokenType::EOF, String::from("EOF"), 3, 12),
+        ]);
+
+        let tokens: Vec<Token> = Lexer::new(String::from("a # b $")).collect();
+        let messages: Vec<(Option<Span>, String)> = diagnostics(&tokens).into_iter().map(|d| (d.span, d.message)).collect();
+        assert_eq!(messages, vec![
+            (Some(Span::new(0, 2, 3)), String::from("unexpected character '#'")),
+            (Some(Span::new(0, 6, 7)), String::from("unexpected character '$'")),
+        ]);
+    }
+
+    #[test]
+    fn test_values() {
+        let values: Vec<TokenValue> = Lexer::new(String::from("017 0x1F 42 2.5e-1 '\\n' '\\\\' \"a\\tb\\\"\" x"))
+            .map(|token| token.value)
+            .collect();
+        assert_eq!(values, vec![
+            TokenValue::Int(15),
+            TokenValue::Int(31),
+            TokenValue::Int(42),
+            TokenValue::Real(0.25),
+            TokenValue::Char('\n'),
+            TokenValue::Char('\\'),
+            TokenValue::Str(String::from("a\tb\"")),
+            TokenValue::None,
+            TokenValue::None,
+        ]);
+
+        let types: Vec<TokenType> = lex("9223372036854775807 9223372036854775808 0x10000000000000000 1e999")
+            .into_iter()
+            .map(|(r#type, ..)| r#type)
+            .collect();
+        assert_eq!(types, vec![
+            TokenType::CT_INT,
+            TokenType::ILLEGAL(IllegalReason::IntegerOutOfRange),
+            TokenType::ILLEGAL(IllegalReason::IntegerOutOfRange),
+            TokenType::ILLEGAL(IllegalReason::RealOutOfRange),
+            TokenType::EOF,
+        ]);
     }
 }
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use crate::diagnostics::diagnostics::{Diagnostic, Span};
+use crate::symbols::symbols::TypeBase;
+use crate::vm::vm::{Opcode, Vm};
+
+/// A type as seen by the other units, which only know its structure by name since
+/// every unit has its own symbol table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkType {
+    pub type_base: TypeBase,
+    pub array: bool,
+    pub struct_name: Option<String>,
+}
+
+/// The type of a function or of a variable, as seen by the other units.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signature {
+    Func { params: Vec<LinkType>, ret: LinkType },
+    Var(LinkType),
+}
+
+/// A function or global variable defined by a unit: `target` is the `ENTER`
+/// instruction of a function or the address of a variable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub name: String,
+    pub signature: Signature,
+    pub target: usize,
+    pub span: Span,
+}
+
+/// An instruction whose operand is the target of a symbol defined in another unit,
+/// or further down in the same one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub name: String,
+    pub signature: Signature,
+    pub instr: usize,
+    pub span: Span,
+}
+
+/// What a compiled unit defines for, and needs from, the other units.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Linkage {
+    pub exports: Vec<Export>,
+    pub imports: Vec<Import>,
+}
+
+/// Links units compiled separately into the same [`Vm`] into one program.
+pub struct Linker {
+    call_main: usize,
+    exports: HashMap<String, Export>,
+    imports: Vec<Import>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linker {
+    /// Emits the entry of the program into `vm`: a call to `main`, then `HALT`.
+    pub fn new(vm: &mut Vm) -> Linker {
+        let call_main = vm.add_instr_a(Opcode::OCall, 0);
+        vm.add_instr(Opcode::OHalt);
+
+        Linker {
+            call_main,
+            exports: HashMap::new(),
+            imports: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Adds the symbols of one unit, reporting those already defined by another one.
+    pub fn add(&mut self, linkage: Linkage) {
+        for export in linkage.exports {
+            if let Some(previous) = self.exports.get(&export.name) {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("{} is defined more than once", export.name), export.span)
+                        .with_note(format!("{} is first defined here", export.name), Some(previous.span)),
+                );
+                continue;
+            }
+            self.exports.insert(export.name.clone(), export);
+        }
+        self.imports.extend(linkage.imports);
+    }
+
+    /// Points every import, and the entry call, at its definition.
+    pub fn link(mut self, vm: &mut Vm) -> Result<(), Vec<Diagnostic>> {
+        for import in &self.imports {
+            let Some(export) = self.exports.get(&import.name) else {
+                self.diagnostics.push(Diagnostic::error(format!("undefined reference to {}", import.name), import.span));
+                continue;
+            };
+            if export.signature != import.signature {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("conflicting types for {}", import.name), import.span)
+                        .with_note(format!("{} is defined here", import.name), Some(export.span)),
+                );
+                continue;
+            }
+            vm.set_target(import.instr, export.target);
+        }
+
+        match self.exports.get("main") {
+            Some(Export { signature: Signature::Func { .. }, target, .. }) => vm.set_target(self.call_main, *target),
+            _ => self.diagnostics.push(Diagnostic::program_error("the program has no main function")),
+        }
+
+        if self.diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(self.diagnostics)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::codegen::CodeGen;
+    use crate::lexer::lexer::Lexer;
+    use crate::parser::parser::Parser;
+    use crate::token::token::Token;
+
+    fn link(sources: &[&str]) -> Result<Vec<String>, Vec<Diagnostic>> {
+        let mut vm = Vm::new();
+        let mut linker = Linker::new(&mut vm);
+        for (file_id, source) in sources.iter().enumerate() {
+            let tokens: Vec<Token> = Lexer::new(source.to_string()).with_file_id(file_id).collect();
+            let mut parser = Parser::new(tokens);
+            let unit = parser.unit().unwrap();
+            linker.add(CodeGen::new(&mut vm, &parser.symbols_table).unit(&unit).unwrap());
+        }
+        linker.link(&mut vm)?;
+
+        let mut out = Vec::new();
+        vm.dump(&mut out).unwrap();
+        Ok(String::from_utf8(out).unwrap().lines().map(|line| line.trim_start().to_string()).collect())
+    }
+
+    fn messages(diagnostics: Vec<Diagnostic>) -> Vec<String> {
+        diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect()
+    }
+
+    #[test]
+    fn test_link() {
+        let code = link(&[
+            "extern int n; int f(int a); void main(){ n = f(1); }",
+            "int n; int f(int a){ return a; }",
+        ]).unwrap();
+        assert_eq!(code[0], "0  CALL\t2");
+        assert_eq!(code[3], "3  PUSHCT_A\t0");
+        assert_eq!(code[5], "5  CALL\t10");
+        assert_eq!(code[10], "10  ENTER\t0");
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let diagnostics = link(&["int n; void main(){}", "int n;"]).unwrap_err();
+        assert_eq!(messages(diagnostics.clone()), ["n is defined more than once"]);
+        assert_eq!(diagnostics[0].span, Some(Span::new(1, 4, 5)));
+        assert_eq!(diagnostics[0].notes[0].span, Some(Span::new(0, 4, 5)));
+
+        let diagnostics = link(&["int f(int a); void main(){ f(1); }", "int g;"]).unwrap_err();
+        assert_eq!(messages(diagnostics), ["undefined reference to f"]);
+
+        let diagnostics = link(&["extern double n; void main(){ n = 1; }", "int n;"]).unwrap_err();
+        assert_eq!(messages(diagnostics), ["conflicting types for n"]);
+
+        let code = link(&["struct P{ int x; }; extern struct P p; void main(){ p.x = 1; }", "struct P{ int x; }; struct P p;"]);
+        assert!(code.is_ok());
+        let diagnostics = link(&["struct P{ int x; }; extern struct P p; void main(){ p.x = 1; }", "struct Q{ int x; }; struct Q p;"]).unwrap_err();
+        assert_eq!(messages(diagnostics), ["conflicting types for p"]);
+
+        let diagnostics = link(&["int f(){ return 1; }"]).unwrap_err();
+        assert_eq!(diagnostics[0].span, None);
+        assert_eq!(messages(diagnostics), ["the program has no main function"]);
+    }
+}
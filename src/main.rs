@@ -1,67 +1,234 @@
-use std::{fs::{self, File}, io::{self, BufRead, BufReader, Write}};
+#![allow(clippy::module_inception)]
 
+use std::{fs::{self, File}, io::{self, IsTerminal, Write}, path::Path, process::ExitCode};
+
+use cli::cli::{CliError, ColorChoice, Command, Options};
+use ast::ast::Unit;
+use codegen::codegen::CodeGen;
+use diagnostics::diagnostics::{Renderer, SourceMap};
+use linker::linker::Linker;
 use parser::parser::Parser;
+use preprocessor::preprocessor::Preprocessor;
 use token::token::Token;
-use vm::vm::*;
-
-use crate::{lexer::lexer::Lexer, token::token::TokenType};
+use vm::trace::Tracer;
+use vm::vm::Vm;
 
+pub mod ast;
+pub mod cli;
+pub mod codegen;
+pub mod diagnostics;
 pub mod token;
 pub mod lexer;
+pub mod linker;
 pub mod parser;
+pub mod preprocessor;
 pub mod symbols;
 pub mod vm;
 
-fn main() -> io::Result<()>{
-    let mut output_file = fs::File::create("./res/tokens.txt")?;
-
-    if let Ok(file) = File::open("./res/9.c") {
-        let reader = BufReader::new(file);
-        let mut lexer = Lexer::new(String::new());
-        let mut tokens: Vec<Token> = Vec::new();
+fn main() -> ExitCode {
+    let options = match cli::cli::parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(CliError::Help) => {
+            println!("{}", cli::cli::USAGE);
+            return ExitCode::from(cli::cli::EXIT_SUCCESS);
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::cli::USAGE);
+            return ExitCode::from(cli::cli::EXIT_USAGE);
+        }
+    };
 
-        for line in reader.lines() {
-            let line = line.unwrap();
-            lexer.set_input(line);
+    match execute(&options) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(cli::cli::EXIT_IO)
+        }
+    }
+}
 
-            loop {
-                let token = lexer.next_token();
+fn execute(options: &Options) -> io::Result<u8> {
+    let mut sources = SourceMap::new();
+    let read = |path: &Path| fs::read_to_string(path);
+    let mut output = open_output(options)?;
+    let renderer = Renderer::new(use_color(options.color));
 
-                if token.r#type == TokenType::EOF {
-                    break;
-                } else {
-                    tokens.push(token.clone());
-                }
+    let mut streams = Vec::new();
+    let mut failed = false;
+    for input in &options.inputs {
+        let text = fs::read_to_string(input)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot read '{}': {}", input, e)))?;
+        let file_id = sources.add(input.as_str(), text);
+        let mut preprocessor = Preprocessor::new(&mut sources, &read);
+        let tokens = preprocessor.run(file_id);
+        let preprocessor_errors = preprocessor.diagnostics().to_vec();
+        for diagnostic in &preprocessor_errors {
+            eprintln!("{}\n", renderer.render(diagnostic, &sources));
+        }
+        failed |= !preprocessor_errors.is_empty();
+        streams.push((input, tokens));
+    }
+    if failed {
+        return Ok(cli::cli::EXIT_COMPILE_ERROR);
+    }
 
-                writeln!(output_file, "{:?}", token)?;
+    // lex stops at the tokens; every other command parses each file into its own symbol
+    // table, and the linker joins them afterwards
+    match options.command {
+        Command::Lex => lex(options, &streams[0], &sources, &renderer, &mut output),
+        Command::Parse => {
+            let Some(units) = parse_units(streams, &sources, &renderer) else {
+                return Ok(cli::cli::EXIT_COMPILE_ERROR);
+            };
+            writeln!(output, "{:#?}", units[0].1)?;
+            Ok(cli::cli::EXIT_SUCCESS)
+        }
+        Command::Check => {
+            let Some(units) = parse_units(streams, &sources, &renderer) else {
+                return Ok(cli::cli::EXIT_COMPILE_ERROR);
+            };
+            for (input, _, _) in &units {
+                writeln!(output, "{}: ok", input)?;
             }
+            Ok(cli::cli::EXIT_SUCCESS)
+        }
+        Command::Run | Command::DumpBytecode => {
+            let Some(units) = parse_units(streams, &sources, &renderer) else {
+                return Ok(cli::cli::EXIT_COMPILE_ERROR);
+            };
+            build(options, &units, &sources, &renderer, &mut output)
         }
-        tokens.push(Token {r#type: TokenType::EOF, literal: String::from("EOF"), line: lexer.get_line() , column: 1});
+    }
+}
+
+/// Dumps the tokens of the only input, or compares them with the dump given to `--check`.
+fn lex(
+    options: &Options,
+    (input, tokens): &(&String, Vec<Token>),
+    sources: &SourceMap,
+    renderer: &Renderer,
+    output: &mut dyn Write,
+) -> io::Result<u8> {
+    let dump = lexer::dump::dump(tokens, sources, options.format);
+
+    if let Some(path) = &options.check {
+        let expected = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot read '{}': {}", path, e)))?;
+        if let Err(mismatch) = lexer::dump::compare(&expected, &dump) {
+            eprintln!("error: the tokens of '{}' differ from '{}' at line {}", input, path, mismatch.line);
+            eprintln!("  expected: {}", mismatch.expected.as_deref().unwrap_or("<end of dump>"));
+            eprintln!("    actual: {}", mismatch.actual.as_deref().unwrap_or("<end of dump>"));
+            return Ok(cli::cli::EXIT_COMPILE_ERROR);
+        }
+        writeln!(output, "{}: tokens match '{}'", input, path)?;
+        return Ok(cli::cli::EXIT_SUCCESS);
+    }
+    write!(output, "{}", dump)?;
+
+    if report_lex_errors(tokens, sources, renderer) {
+        return Ok(cli::cli::EXIT_COMPILE_ERROR);
+    }
+    Ok(cli::cli::EXIT_SUCCESS)
+}
 
+/// Prints the lexical errors among `tokens`, and tells whether there were any.
+fn report_lex_errors(tokens: &[Token], sources: &SourceMap, renderer: &Renderer) -> bool {
+    let lex_errors = lexer::lexer::diagnostics(tokens);
+    for diagnostic in &lex_errors {
+        eprintln!("{}\n", renderer.render(diagnostic, sources));
+    }
+    !lex_errors.is_empty()
+}
+
+/// Parses every input, printing the lexical and syntax errors. Returns `None` if there were any.
+fn parse_units<'a>(
+    streams: Vec<(&'a String, Vec<Token>)>,
+    sources: &SourceMap,
+    renderer: &Renderer,
+) -> Option<Vec<(&'a String, Unit, Parser)>> {
+    let mut failed = false;
+    for (_, tokens) in &streams {
+        failed |= report_lex_errors(tokens, sources, renderer);
+    }
+    if failed {
+        return None;
+    }
+
+    let mut units = Vec::new();
+    for (input, tokens) in streams {
         let mut parser = Parser::new(tokens);
-        if parser.unit() {
-            print!("\x1B[2J\x1B[1;1H");
-            println!("Success!");
-            for element in &parser.symbols_table.table {
-                println!("{:?}", element);
+        let result = parser.unit();
+        for diagnostic in parser.diagnostics() {
+            eprintln!("{}\n", renderer.render(diagnostic, sources));
+        }
+        match result {
+            Ok(unit) => units.push((input, unit, parser)),
+            Err(_) => failed = true,
+        }
+    }
+    (!failed).then_some(units)
+}
+
+/// Generates the code of every unit, links it and then runs it or dumps it.
+fn build(
+    options: &Options,
+    units: &[(&String, Unit, Parser)],
+    sources: &SourceMap,
+    renderer: &Renderer,
+    output: &mut dyn Write,
+) -> io::Result<u8> {
+    let mut vm = Vm::new();
+    let mut linker = Linker::new(&mut vm);
+    let mut failed = false;
+    for (_, unit, parser) in units {
+        match CodeGen::new(&mut vm, &parser.symbols_table).unit(unit) {
+            Ok(linkage) => linker.add(linkage),
+            Err(diagnostic) => {
+                eprintln!("{}\n", renderer.render(&diagnostic, sources));
+                failed = true;
             }
-        } else {
-            println!("Didn't work :(");
-            println!("{:?}", parser.symbols_table);
         }
-    } else {
-        eprintln!("Failed to open the file");
     }
+    if failed {
+        return Ok(cli::cli::EXIT_COMPILE_ERROR);
+    }
+    if let Err(diagnostics) = linker.link(&mut vm) {
+        for diagnostic in &diagnostics {
+            eprintln!("{}\n", renderer.render(diagnostic, sources));
+        }
+        return Ok(cli::cli::EXIT_COMPILE_ERROR);
+    }
+    if options.command == Command::DumpBytecode {
+        vm.dump(output)?;
+        return Ok(cli::cli::EXIT_SUCCESS);
+    }
+    if let Some(path) = &options.trace {
+        let file = File::create(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot create '{}': {}", path, e)))?;
+        vm.set_tracer(Some(Tracer::new(options.trace_format, Box::new(io::BufWriter::new(file)))));
+    }
+    if let Err(e) = vm.run() {
+        eprintln!("error: {}", e);
+        return Ok(cli::cli::EXIT_RUNTIME_ERROR);
+    }
+    Ok(cli::cli::EXIT_SUCCESS)
+}
 
-    unsafe {
-        // let a = 10;
-        // let b = 10;
-        add_instr_i(Opcode::OPushCtI, 10);
-        add_instr_i(Opcode::OPushCtI, 5);
-        add_instr(Opcode::OAddI);
-        add_instr(Opcode::OHalt);
+fn use_color(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+    }
+}
 
-        run(INSTRUCTIONS);
+fn open_output(options: &Options) -> io::Result<Box<dyn Write>> {
+    match &options.output {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot create '{}': {}", path, e)))?;
+            Ok(Box::new(io::BufWriter::new(file)))
+        }
+        None => Ok(Box::new(io::stdout().lock())),
     }
-    Ok(())
 }
@@ -1,62 +1,192 @@
-use std::borrow::BorrowMut;
-use std::char;
-use std::ops::Deref;
-use std::sync::Mutex;
-
-use once_cell::sync::Lazy;
-
-use crate::token::token::{Token, TokenType};
-use crate::symbols::symbols::{self, Class, CtVal, Memory, RetVal, Symbol, SymbolTable, Type, TypeBase};
+use crate::ast::ast::{BinaryOp, Expr, ExprKind, FuncDecl, Item, Stmt, StmtKind, StructDecl, UnaryOp, Unit, VarDecl};
+use crate::diagnostics::diagnostics::{Diagnostic, Span};
+use crate::token::token::{Token, TokenType, TokenValue};
+use crate::symbols::symbols::{self, Class, Conversion, CtVal, Memory, RetVal, Symbol, SymbolTable, Type, TypeBase, TypeError, PTR_SIZE};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Parser {
     tokens: Vec<Token>,
     current_token_index: usize,
     consumed_token: Option<Token>,
-    crt_depth: i32,
     crt_struct: Option<Symbol>,
     pub crt_func: Option<Symbol>,
+    crt_extern: bool,
+    /// The bytes used so far by the members of the current struct, the locals of the
+    /// current function and the globals of the unit.
+    struct_size: i64,
+    frame_size: i64,
+    globals_size: i64,
     current_type: Type,
     pub symbols_table: SymbolTable,
-    rv: RetVal,
+    diagnostics: Vec<Diagnostic>,
+    furthest_failure: Option<(usize, String)>,
 }
 
 impl Parser {
     /// Creates a new [`Parser`].
     pub fn new(tokens: Vec<Token>) -> Parser {
-        let mut parser = Parser {
+        Parser {
             tokens,
             current_token_index: 0,
             consumed_token: None,
-            crt_depth: 0,
             crt_struct: None,
             crt_func: None,
-            current_type: Type {
-                type_base: TypeBase::Int,
-                s: None,
-                n_elements: -1,
-            },
-            symbols_table: symbols::SymbolTable { table: Vec::new() },
-            rv: RetVal {
-               r#type: None,
-               is_lval: false,
-               is_ctval:false,
-               ct_val: None,
+            crt_extern: false,
+            struct_size: 0,
+            frame_size: 0,
+            globals_size: 0,
+            current_type: Type::new(TypeBase::Int, -1),
+            symbols_table: SymbolTable::new(),
+            diagnostics: Vec::new(),
+            furthest_failure: None,
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    fn token_span(&self, index: usize) -> Span {
+        match self.tokens.get(index).or(self.tokens.last()) {
+            Some(token) => Span::from_token(token),
+            None => Span::default(),
+        }
+    }
+
+    fn consumed_span(&self) -> Span {
+        match &self.consumed_token {
+            Some(token) => Span::from_token(token),
+            None => self.token_span(self.current_token_index),
+        }
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic) {
+        // backtracking can run the same check twice
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn error(&mut self, message: impl Into<String>, span: Span) {
+        self.report(Diagnostic::error(message, span));
+    }
+
+    fn conflicting_types(&mut self, name: &str, span: Span, previous: Option<Span>) {
+        let mut diagnostic = Diagnostic::error(format!("conflicting types for {}", name), span);
+        if previous.is_some() {
+            diagnostic = diagnostic.with_note(format!("previous declaration of {} is here", name), previous);
+        }
+        self.report(diagnostic);
+    }
+
+    fn redefinition(&mut self, name: &str, span: Span, previous: Option<Span>) {
+        let mut diagnostic = Diagnostic::error(format!("symbol redefinition: {}", name), span);
+        if previous.is_some() {
+            diagnostic = diagnostic.with_note(format!("previous definition of {} is here", name), previous);
+        }
+        self.report(diagnostic);
+    }
+
+    /// Remembers a syntax error at the current token. Since alternatives are tried by
+    /// backtracking, only the failure that got furthest into the declaration or statement
+    /// being parsed is reported, by [`Parser::recover`].
+    fn expected(&mut self, message: &str) {
+        let index = self.current_token_index;
+
+        if self.furthest_failure.as_ref().is_none_or(|(furthest, _)| index > *furthest) {
+            self.furthest_failure = Some((index, message.to_string()));
+        }
+    }
+
+    /// Remembers that the binary operator at `op_index` is missing its right operand.
+    fn expected_operand(&mut self, op_index: usize) {
+        let op = self.tokens.get(op_index).map_or(String::new(), |token| token.literal.clone());
+        self.expected(&format!("expected an expression after '{}'", op));
+    }
+
+    /// Reports why the declaration or statement starting at `start` could not be parsed,
+    /// unless it already reported an error of its own, and moves back to `start` so the
+    /// caller can skip it.
+    fn recover(&mut self, start: usize, reported: usize, fallback: &str) {
+        let failure = self.furthest_failure.take();
+
+        if self.diagnostics.len() == reported {
+            let (index, message) = failure.unwrap_or((start, fallback.to_string()));
+            self.error(message, self.token_span(index));
+        }
+        self.current_token_index = start;
+    }
+
+    /// Skips a statement that failed to parse: up to and including the next `;` or block,
+    /// or up to the `}` closing the enclosing block. The `;` of a `for` header do not count.
+    fn synchronize_statement(&mut self) {
+        let mut depth = 0;
+        let mut parens = 0usize;
+
+        loop {
+            match self.get_token_type() {
+                TokenType::EOF => return,
+                TokenType::SEMICOLON if depth == 0 && parens == 0 => {
+                    self.consume();
+                    return;
+                }
+                TokenType::RACC if depth == 0 => return,
+                TokenType::RACC => {
+                    self.consume();
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                    continue;
+                }
+                TokenType::LACC => depth += 1,
+                TokenType::LPAR => parens += 1,
+                TokenType::RPAR => parens = parens.saturating_sub(1),
+                _ => {}
             }
-        };
+            self.consume();
+        }
+    }
 
-        parser
+    /// Skips a declaration that failed to parse, up to the next one starting at the top
+    /// level, and forgets whatever scope it left open.
+    fn synchronize_declaration(&mut self) {
+        let start = self.current_token_index;
+        let mut depth = 0usize;
+
+        loop {
+            match self.get_token_type() {
+                TokenType::EOF => break,
+                TokenType::INT | TokenType::DOUBLE | TokenType::CHAR | TokenType::STRUCT | TokenType::VOID | TokenType::EXTERN
+                    if depth == 0 && self.current_token_index > start => break,
+                TokenType::SEMICOLON if depth == 0 => {
+                    self.consume();
+                    break;
+                }
+                TokenType::LPAR | TokenType::LACC => depth += 1,
+                TokenType::RPAR | TokenType::RACC => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            self.consume();
+        }
+
+        self.crt_func = None;
+        self.crt_struct = None;
+        self.crt_extern = false;
+        while self.symbols_table.depth() > 0 {
+            self.symbols_table.exit_scope();
+        }
     }
 
-    fn current_token(&mut self) -> Option<&Token> {
+    fn current_token(&self) -> Option<&Token> {
         self.tokens.get(self.current_token_index)
     }
 
-    fn get_token_type(&mut self) -> TokenType {
+    fn get_token_type(&self) -> TokenType {
         if let Some(token) = self.current_token() {
             token.r#type.clone()
         } else {
-            TokenType::ILLEGAL
+            TokenType::EOF
         }
     }
 
@@ -67,84 +197,176 @@ impl Parser {
         self.current_token_index += 1;
     }
 
-    fn add_var(&mut self, token: Token) {
-        if let Some(crt_struct) = &mut self.crt_struct {
-            let crt_struct_name = crt_struct.name.clone();
-            if let Some(ref mut s_struct) = self.symbols_table.find_symbol_mut(&crt_struct_name) {
-               let struct_members = s_struct.members.as_mut().unwrap();
+    /// Declares the variable named by `token` in the current struct, function or unit,
+    /// and returns the offset it was placed at.
+    fn add_var(&mut self, token: &Token) -> i64 {
+        if let Some(crt_struct) = &self.crt_struct {
+            let depth = self.symbols_table.depth();
+            let Some(s_struct) = self.symbols_table.lookup(&crt_struct.name) else {
+                return 0;
+            };
 
-               if struct_members.find_symbol(&token.literal).is_some() {
-                   println!("ERROR1: Symbol redefinition: {}", token.literal);
-                   return;
-               }
-               let s = struct_members.add_symbol(
-                   Symbol::new(token.literal.clone(), Class::Var, None, Some(self.current_type.clone()), self.crt_depth, None, None)
-                   );
+            let struct_members = self.symbols_table.symbol(s_struct).members.as_ref().unwrap();
+            if let Some(existing_symbol) = struct_members.find_symbol(&token.literal) {
+                let previous = existing_symbol.span;
+                self.redefinition(&token.literal, Span::from_token(token), previous);
+                return 0;
+            }
+            let offset = self.symbols_table.place(&mut self.struct_size, &self.current_type);
+            let struct_members = self.symbols_table.symbol_mut(s_struct).members.as_mut().unwrap();
+            let s = struct_members.add_symbol(
+                Symbol::new(token.literal.clone(), Class::Var, None, Some(self.current_type.clone()), depth, None, None)
+                );
+            s.span = Some(Span::from_token(token));
+            s.offset = offset;
+            offset
+        } else if self.crt_func.is_some() {
+            // a local may shadow a symbol of an outer scope, but not one of its own
+            if let Some(existing_symbol) = self.symbols_table.find_in_scope(&token.literal) {
+                let previous = existing_symbol.span;
+                self.redefinition(&token.literal, Span::from_token(token), previous);
+                return 0;
             }
-        }else if let Some(crt_func) = &mut self.crt_func {
-            if let Some(existing_symbol) = self.symbols_table.find_symbol(&token.literal) {
-                if existing_symbol.depth == self.crt_depth {
-                    println!("ERROR2: Symbol redefinition: {}", token.literal);
-                    return;
-                }
-            } 
 
+            let offset = self.symbols_table.place(&mut self.frame_size, &self.current_type);
             let s = self.symbols_table.add_symbol(
-                Symbol::new(token.literal.clone(), Class::Var, Some(Memory::Local), Some(self.current_type.clone()), self.crt_depth, None, None)
+                Symbol::new(token.literal.clone(), Class::Var, Some(Memory::Local), Some(self.current_type.clone()), self.symbols_table.depth(), None, None)
                 );
+            s.span = Some(Span::from_token(token));
+            s.offset = offset;
+            offset
         } else {
-            if self.symbols_table.find_symbol(&token.literal).is_some() {
-                println!("ERROR3: Symbol redefinition: {}", token.literal);
-                return;
+            let crt_extern = self.crt_extern;
+            let current_type = self.current_type.clone();
+            if let Some(id) = self.symbols_table.lookup_in_scope(&token.literal) {
+                let existing_symbol = self.symbols_table.symbol(id);
+                let previous = existing_symbol.span;
+
+                // an extern declaration may be repeated, and followed by the definition
+                if existing_symbol.class == Class::Var && (existing_symbol.external || crt_extern) {
+                    if !existing_symbol.r#type.as_ref().is_some_and(|t| t.matches(&current_type)) {
+                        self.conflicting_types(&token.literal, Span::from_token(token), previous);
+                    } else if !crt_extern {
+                        let offset = self.symbols_table.place(&mut self.globals_size, &current_type);
+                        let existing_symbol = self.symbols_table.symbol_mut(id);
+                        existing_symbol.external = false;
+                        existing_symbol.r#type = Some(current_type);
+                        existing_symbol.span = Some(Span::from_token(token));
+                        existing_symbol.offset = offset;
+                    }
+                    return self.symbols_table.symbol(id).offset;
+                }
+                self.redefinition(&token.literal, Span::from_token(token), previous);
+                return 0;
             }
 
+            // an extern variable takes no room in this unit
+            let offset = if crt_extern { 0 } else { self.symbols_table.place(&mut self.globals_size, &current_type) };
             let s = self.symbols_table.add_symbol(
-                Symbol::new(token.literal.clone(), Class::Var, Some(Memory::Global), Some(self.current_type.clone()), self.crt_depth, None, None)
+                Symbol::new(token.literal.clone(), Class::Var, Some(Memory::Global), Some(current_type), self.symbols_table.depth(), None, None)
                 );
+            s.span = Some(Span::from_token(token));
+            s.external = crt_extern;
+            s.offset = offset;
+            offset
         }
     }
 
-    fn cast_var(dst: &Type, src: &Type) {
+    /// Checks that a value of type `src` may be used where `dst` is expected, and finds
+    /// the implicit conversion that makes it so.
+    fn cast_var(dst: &Type, src: &Type) -> Result<Conversion, TypeError> {
         if src.n_elements > -1 {
-            if dst.n_elements > -1 {
-                if src.type_base != dst.type_base {
-                    println!("ERROR: An array cannot be converted to an array of another type");
-                    return;
-                }
-            } else {
-                println!("ERROR: An array cannot be converted to a non-array");
-                return;
+            if dst.n_elements < 0 {
+                return Err(TypeError::ArrayToNonArray);
             }
-        } else {
-            if dst.n_elements > -1 {
-                println!("ERROR: A non-array cannot be converted to an array");
-                return;
+            if src.type_base != dst.type_base {
+                return Err(TypeError::ArrayElements);
             }
+        } else if dst.n_elements > -1 {
+            return Err(TypeError::NonArrayToArray);
         }
 
-        match src.type_base {
-            TypeBase::Char | TypeBase::Int | TypeBase::Double => {
-                match dst.type_base {
-                    TypeBase::Char | TypeBase::Int | TypeBase::Double => return,
-                    _ => {
-                        println!("ERROR: Incompatible types");
-                        return;
-                    }
+        match (&src.type_base, &dst.type_base) {
+            (TypeBase::Struct, TypeBase::Struct) if src.s != dst.s => Err(TypeError::OtherStruct),
+            _ if src.n_elements > -1 || src.type_base == dst.type_base => Ok(Conversion::None),
+            (TypeBase::Char, TypeBase::Int) => Ok(Conversion::CharToInt),
+            (TypeBase::Char, TypeBase::Double) => Ok(Conversion::CharToDouble),
+            (TypeBase::Int, TypeBase::Char) => Ok(Conversion::IntToChar),
+            (TypeBase::Int, TypeBase::Double) => Ok(Conversion::IntToDouble),
+            (TypeBase::Double, TypeBase::Int) => Ok(Conversion::DoubleToInt),
+            (TypeBase::Double, TypeBase::Char) => Ok(Conversion::DoubleToChar),
+            _ => Err(TypeError::Incompatible),
+        }
+    }
+
+    /// Checks that a value of type `r#type` can be tested for truth, which only scalars can.
+    fn check_condition(&mut self, r#type: Option<&Type>, span: Span) -> Option<()> {
+        let message = match r#type {
+            Some(t) if t.n_elements > -1 => "an array cannot be logically tested",
+            Some(t) if t.type_base == TypeBase::Struct => "a structure cannot be logically tested",
+            Some(t) if t.type_base == TypeBase::Void => "a void value cannot be logically tested",
+            _ => return Some(()),
+        };
+        self.error(message, span);
+        None
+    }
+
+    /// Checks that the unary operator `op` can be applied to a value of type `r#type`,
+    /// which only scalars can.
+    fn check_operand(&mut self, op: &str, r#type: Option<&Type>, span: Span) -> Option<()> {
+        let operand = match r#type {
+            Some(t) if t.n_elements > -1 => "an array",
+            Some(t) if t.type_base == TypeBase::Struct => "a struct",
+            Some(t) if t.type_base == TypeBase::Void => "a void value",
+            _ => return Some(()),
+        };
+        self.error(format!("{} cannot be applied to {}", op, operand), span);
+        None
+    }
+
+    /// The type both operands of an arithmetic or relational operator are converted to:
+    /// a double operand makes it double, otherwise chars are promoted to int as in C.
+    /// Arrays, structures and void values have no such type.
+    fn arith_type_to(a: &Type, b: &Type) -> Option<Type> {
+        if a.n_elements > -1 || b.n_elements > -1 {
+            return None;
+        }
+        match (&a.type_base, &b.type_base) {
+            (TypeBase::Double, TypeBase::Int | TypeBase::Double | TypeBase::Char)
+            | (TypeBase::Int | TypeBase::Char, TypeBase::Double) => Some(Type::new(TypeBase::Double, -1)),
+            (TypeBase::Int | TypeBase::Char, TypeBase::Int | TypeBase::Char) => Some(Type::new(TypeBase::Int, -1)),
+            _ => None,
+        }
+    }
+
+    /// Converts both operands of a binary operator to the type given by `arith_type_to`,
+    /// which is returned along with them.
+    fn arith_operands(&mut self, lhs: Expr, rhs: Expr, lhs_type: &Type, rhs_type: &Type, span: Span) -> Option<(Expr, Expr, Type)> {
+        let Some(r#type) = Parser::arith_type_to(lhs_type, rhs_type) else {
+            self.error(TypeError::Incompatible.to_string(), span);
+            return None;
+        };
+        let lhs = self.convert(lhs, &r#type, lhs_type, span)?;
+        let rhs = self.convert(rhs, &r#type, rhs_type, span)?;
+        Some((lhs, rhs, r#type))
+    }
+
+    /// Converts `expr`, of type `src`, to `dst`, or reports at `span` why it cannot be.
+    fn convert(&mut self, expr: Expr, dst: &Type, src: &Type, span: Span) -> Option<Expr> {
+        match Parser::cast_var(dst, src) {
+            Ok(Conversion::None) => Some(expr),
+            Ok(conversion) => {
+                let span = expr.span;
+                let folded = Parser::literal_val(&expr).and_then(|value| value.cast(&dst.type_base));
+                if let Some(expr) = folded.and_then(|value| Parser::literal_expr(&value, dst.clone(), span)) {
+                    return Some(expr);
                 }
+                Some(Expr::new(ExprKind::Convert { conversion, expr: Box::new(expr) }, Some(dst.clone()), span))
             }
-            TypeBase::Struct => {
-                if let TypeBase::Struct = dst.type_base {
-                    if src.s != dst.s {
-                        println!("ERROR: A structure cannot be converted to another one");
-                        return;
-                    }
-                    return;
-                } else {
-                    println!("ERROR: Incompatible types");
-                    return;
-                }
+            Err(e) => {
+                self.error(e.to_string(), span);
+                None
             }
-            TypeBase::Void => (),
         }
     }
 
@@ -155,15 +377,16 @@ impl Parser {
                 Class::ExtFunc,
                 None,
                 Some(func_type),
-                self.crt_depth,
-                Some(symbols::SymbolTable {table: Vec::new()}),
+                self.symbols_table.depth(),
+                Some(symbols::SymbolTable::new()),
                 None,
                 )
             )
     }
 
-    fn add_func_arg(&mut self, symbol:&mut Symbol, name: &str, arg_type: Type) {
-        if let Some(ref mut func) = self.symbols_table.find_symbol_mut(&symbol.name) {
+    fn add_func_arg(&mut self, symbol: &Symbol, name: &str, arg_type: Type) {
+        let depth = self.symbols_table.depth();
+        if let Some(func) = self.symbols_table.find_symbol_mut(&symbol.name) {
             let func_args = func.args.as_mut().unwrap();
             func_args.add_symbol(
                 Symbol::new(
@@ -171,7 +394,7 @@ impl Parser {
                     Class::Var,
                     None,
                     Some(arg_type),
-                    self.crt_depth,
+                    depth,
                     None,
                     None,
                     )
@@ -180,49 +403,59 @@ impl Parser {
     }
 
     fn add_ext_functions(&mut self) {
-        let mut s = self.add_ext_func("put_s", Type::new(TypeBase::Void, -1)).to_owned();
-        self.add_func_arg(&mut s, "s", Type::new(TypeBase::Char, 0));
+        let s = self.add_ext_func("put_s", Type::new(TypeBase::Void, -1)).to_owned();
+        self.add_func_arg(&s, "s", Type::new(TypeBase::Char, 0));
 
-        let mut s = self.add_ext_func("get_s", Type::new(TypeBase::Void, -1)).to_owned();
-        self.add_func_arg(&mut s, "s", Type::new(TypeBase::Char, 0));
+        let s = self.add_ext_func("get_s", Type::new(TypeBase::Void, -1)).to_owned();
+        self.add_func_arg(&s, "s", Type::new(TypeBase::Char, 0));
 
-        let mut s = self.add_ext_func("put_i", Type::new(TypeBase::Void, -1)).to_owned();
-        self.add_func_arg(&mut s, "i", Type::new(TypeBase::Int, 0));
+        let s = self.add_ext_func("put_i", Type::new(TypeBase::Void, -1)).to_owned();
+        self.add_func_arg(&s, "i", Type::new(TypeBase::Int, -1));
 
-        let mut s = self.add_ext_func("get_i", Type::new(TypeBase::Int, -1)).to_owned();
+        self.add_ext_func("get_i", Type::new(TypeBase::Int, -1));
 
-        let mut s = self.add_ext_func("put_d", Type::new(TypeBase::Void, -1)).to_owned();
-        self.add_func_arg(&mut s, "d", Type::new(TypeBase::Double, 0));
+        let s = self.add_ext_func("put_d", Type::new(TypeBase::Void, -1)).to_owned();
+        self.add_func_arg(&s, "d", Type::new(TypeBase::Double, -1));
 
-        let mut s = self.add_ext_func("get_d", Type::new(TypeBase::Double, -1)).to_owned();
-
-        let mut s = self.add_ext_func("put_c", Type::new(TypeBase::Void, -1)).to_owned();
-        self.add_func_arg(&mut s, "c", Type::new(TypeBase::Char, 0));
-
-        let mut s = self.add_ext_func("get_c", Type::new(TypeBase::Char, -1)).to_owned();
+        self.add_ext_func("get_d", Type::new(TypeBase::Double, -1));
 
+        let s = self.add_ext_func("put_c", Type::new(TypeBase::Void, -1)).to_owned();
+        self.add_func_arg(&s, "c", Type::new(TypeBase::Char, -1));
 
+        self.add_ext_func("get_c", Type::new(TypeBase::Char, -1));
     }
 
-    pub fn unit(&mut self) -> bool {
+    /// Parses the whole token stream into a [`Unit`], returning every diagnostic
+    /// found if the program is not valid. A declaration that fails to parse is
+    /// reported and skipped, so that the following ones are still checked.
+    pub fn unit(&mut self) -> Result<Unit, Vec<Diagnostic>> {
         self.add_ext_functions();
-        loop {
-            if self.decl_struct() {
-                continue;
-            } else if self.decl_func() {
-                continue;
-            } else if self.decl_var() {
-                continue;
+        let mut items = Vec::new();
+
+        while self.get_token_type() != TokenType::EOF {
+            let start = self.current_token_index;
+            let reported = self.diagnostics.len();
+            self.furthest_failure = None;
+
+            if let Some(decl) = self.decl_struct() {
+                items.push(Item::Struct(decl));
+            } else if let Some(decl) = self.decl_func() {
+                items.push(Item::Func(decl));
+            } else if let Some(decls) = self.decl_var() {
+                items.extend(decls.into_iter().map(Item::Var));
+            } else if let Some(decls) = self.decl_extern() {
+                items.extend(decls.into_iter().map(Item::Extern));
             } else {
-                break;
+                self.recover(start, reported, "expected a declaration");
+                self.synchronize_declaration();
             }
         }
+        self.consume();
 
-        if self.get_token_type() == TokenType::EOF {
-            self.consume();
-            return true;
+        if self.diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+            Err(self.diagnostics.clone())
         } else {
-            return false;
+            Ok(Unit { items, globals_size: self.globals_size })
         }
     }
 
@@ -231,19 +464,19 @@ impl Parser {
 
         if self.get_token_type() == TokenType::INT {
             self.consume();
-            self.current_type.type_base = TypeBase::Int;
+            self.current_type = Type::new(TypeBase::Int, -1);
             return true;
         }
 
         if self.get_token_type() == TokenType::DOUBLE {
             self.consume();
-            self.current_type.type_base = TypeBase::Double;
+            self.current_type = Type::new(TypeBase::Double, -1);
             return true;
         }
 
         if self.get_token_type() == TokenType::CHAR {
             self.consume();
-            self.current_type.type_base = TypeBase::Char;
+            self.current_type = Type::new(TypeBase::Char, -1);
             return true;
         }
 
@@ -254,29 +487,27 @@ impl Parser {
                 self.consume();
                 let token_name = self.consumed_token.clone().unwrap().literal;
 
-                if let Some(s) = self.symbols_table.find_symbol(&token_name) {
-                    if s.class != Class::Struct {
-                        println!("ERROR: {} is not a struct", token_name);
+                if let Some(id) = self.symbols_table.lookup(&token_name) {
+                    if self.symbols_table.symbol(id).class != Class::Struct {
+                        self.error(format!("{} is not a struct", token_name), self.consumed_span());
                         return false;
                     }
-                    self.current_type.type_base = TypeBase::Struct;
-                    self.current_type.s = Some(Box::new(s.clone()));
+                    self.current_type = Type::new(TypeBase::Struct, -1);
+                    self.current_type.s = Some(id);
                     return true;
                 } else {
-                    println!("Undefined symbol: {}", token_name);
+                    self.error(format!("undefined symbol: {}", token_name), self.consumed_span());
                 }
             } else {
+                self.expected("expected an identifier after 'struct'");
                 self.current_token_index = start_token;
-                println!("Missing identifier!");
             }
         }
-        
-        println!("Should be INT, DOUBLE, CHAR or STRUCT");
-        return false;
+
+        false
     }
 
-    pub fn decl_struct(&mut self) -> bool {
-        println!("IN DECL_STRUCT");
+    pub fn decl_struct(&mut self) -> Option<StructDecl> {
         let start_token = self.current_token_index;
 
         if self.get_token_type() == TokenType::STRUCT {
@@ -285,36 +516,37 @@ impl Parser {
             if self.get_token_type() == TokenType::ID {
                 self.consume();
                 let token_name = self.consumed_token.clone().unwrap().literal;
-                
 
                 if self.get_token_type() == TokenType::LACC {
                     self.consume();
 
-                    if self.symbols_table.find_symbol(&token_name).is_some() {
-                        println!("ERROR: symbol redefinition: {}", token_name);
-                        return false;
+                    let name_span = self.token_span(start_token + 1);
+                    if let Some(existing_symbol) = self.symbols_table.find_symbol(&token_name) {
+                        let previous = existing_symbol.span;
+                        self.redefinition(&token_name, name_span, previous);
+                        self.current_token_index = start_token;
+                        return None;
                     }
 
                     let crt_struct = self.symbols_table.add_symbol(
                             Symbol::new(
-                                token_name, 
-                                Class::Struct, 
-                                None, 
+                                token_name.clone(),
+                                Class::Struct,
+                                None,
                                 None,
-                                self.crt_depth,
+                                self.symbols_table.depth(),
                                 None,
                                 None
                                 )
                             );
-                    crt_struct.members = Some(symbols::SymbolTable { table: Vec::new()});
+                    crt_struct.members = Some(symbols::SymbolTable::new());
+                    crt_struct.span = Some(name_span);
                     self.crt_struct = Some(crt_struct.clone());
+                    self.struct_size = 0;
 
-                    loop {
-                        if !self.decl_var() {
-                            break;
-                        } else {
-                            continue;
-                        }
+                    let mut members = Vec::new();
+                    while let Some(decls) = self.decl_var() {
+                        members.extend(decls);
                     }
 
                     if self.get_token_type() == TokenType::RACC {
@@ -323,38 +555,54 @@ impl Parser {
                         if self.get_token_type() == TokenType::SEMICOLON {
                             self.consume();
                             self.crt_struct = None;
-                            return true;
+                            return Some(StructDecl {
+                                name: token_name,
+                                members,
+                                span: name_span,
+                            });
                         } else {
-                            println!("Missing ';'!");
+                            self.expected("expected ';' after the structure declaration");
                         }
                     } else {
-                        println!("Missing ')'!");
+                        self.expected("expected '}' to close the structure declaration");
                     }
-                } else {
-                    println!("Missing '('!");
+                    self.crt_struct = None;
                 }
-            } else{
-                println!("Missing identifier!");
+            } else {
+                self.expected("expected an identifier after 'struct'");
             }
-        } else {
-            println!("Missing 'struct' keyword!");
         }
 
         self.current_token_index = start_token;
-        return false;
+        None
+    }
+
+    fn decl_extern(&mut self) -> Option<Vec<VarDecl>> {
+        if self.get_token_type() != TokenType::EXTERN {
+            return None;
+        }
+        let start_token = self.current_token_index;
+        self.consume();
+
+        self.crt_extern = true;
+        let decls = self.decl_var();
+        self.crt_extern = false;
+
+        if decls.is_none() {
+            self.expected("expected a variable declaration after 'extern'");
+            self.current_token_index = start_token;
+        }
+        decls
     }
 
-    fn decl_var(&mut self) -> bool {
+    fn decl_var(&mut self) -> Option<Vec<VarDecl>> {
         let start_token = self.current_token_index;
-        println!("IN DECL_VAR");
 
         if self.type_base() {
             if self.get_token_type() == TokenType::ID {
                 self.consume();
-                let token_name = self.consumed_token.clone();
-                println!("Consumed token : {:?}", token_name);
-                self.array_decl();
-                self.add_var(token_name.expect("Add variable into symbol table"));
+                let token = self.consumed_token.clone().expect("Add variable into symbol table");
+                let mut decls = vec![self.var_def(&token)];
 
                 loop {
                     if self.get_token_type() == TokenType::COMMA {
@@ -362,14 +610,12 @@ impl Parser {
 
                         if self.get_token_type() == TokenType::ID {
                             self.consume();
-                            let token_name = self.consumed_token.clone();
-                            println!("Consumed token : {:?}", token_name);
-                            self.array_decl();
-                            self.add_var(token_name.expect("Add variable into symbol table"));
+                            let token = self.consumed_token.clone().expect("Add variable into symbol table");
+                            decls.push(self.var_def(&token));
                         } else {
-                            println!("Comma should be followed by an argument!");
+                            self.expected("expected a variable name after ','");
                             self.current_token_index = start_token;
-                            return false;
+                            return None;
                         }
                     } else {
                         break;
@@ -378,58 +624,87 @@ impl Parser {
 
                 if self.get_token_type() == TokenType::SEMICOLON {
                     self.consume();
-                    return true;
+                    return Some(decls);
                 } else {
-                    println!("Missing ';'");
+                    self.expected("expected ';' after the variable declaration");
                 }
             } else {
-                println!("Missing identifier!");
+                self.expected("expected a variable name");
             }
-        } else {
-            println!("Cannot define variable without a type!");
         }
 
         self.current_token_index = start_token;
-        return false;
+        None
+    }
+
+    /// Declares the variable named by `token`, including an optional array dimension.
+    fn var_def(&mut self, token: &Token) -> VarDecl {
+        let mut dim = None;
+
+        self.current_type.n_elements = -1;
+        let reported = self.diagnostics.len();
+        self.array_decl(&mut dim);
+        // a dimension that failed to parse has already been reported
+        if self.current_type.n_elements == 0 && dim.is_none() && !self.crt_extern && self.diagnostics.len() == reported {
+            self.error(format!("the array {} needs a dimension", token.literal), Span::from_token(token));
+        }
+        let offset = self.add_var(token);
+
+        VarDecl {
+            name: token.literal.clone(),
+            r#type: self.current_type.clone(),
+            dim,
+            offset,
+            span: Span::from_token(token),
+        }
     }
 
-    fn array_decl(&mut self) -> bool {
+    fn array_decl(&mut self, dim: &mut Option<Expr>) -> bool {
         let start_token = self.current_token_index;
-        let mut rv_copy = self.rv.clone();
 
         if self.get_token_type() == TokenType::LBRACKET {
             self.consume();
-            self.expr(&mut rv_copy);
-            self.rv = rv_copy;
-            self.current_type.n_elements = 0;
+            let mut rv = RetVal::new();
+            let size = self.expr(&mut rv);
 
             if self.get_token_type() == TokenType::RBRACKET {
                 self.consume();
-                return true;
+                // an array without a dimension, such as an argument, keeps 0 elements
+                self.current_type.n_elements = match &size {
+                    Some(size) => match rv.const_int() {
+                        Some(n) if n > 0 && n <= i32::MAX as i64 => n as i32,
+                        _ => {
+                            self.error("the array dimension must be a positive integer constant", size.span);
+                            0
+                        }
+                    },
+                    None => 0,
+                };
+                *dim = size;
+                true
             } else {
+                self.expected("expected ']' after the array dimension");
                 self.current_token_index = start_token;
-                println!("Missing ']'!");
-                return false;
+                false
             }
         } else {
-            return false;
+            false
         }
     }
 
     fn type_name(&mut self) -> bool {
         if self.type_base() {
-            self.array_decl(); 
-            return true;
+            let mut dim = None;
+            self.array_decl(&mut dim);
+            true
         } else {
-            println!("Missing type base!");
-            return false;
+            false
         }
     }
 
-    fn decl_func(&mut self) -> bool {
+    fn decl_func(&mut self) -> Option<FuncDecl> {
         let start_token = self.current_token_index;
 
-        println!("IN DECL_FUNC");
         if self.type_base() {
             if self.get_token_type() == TokenType::MUL {
                 self.consume();
@@ -437,12 +712,11 @@ impl Parser {
             }
         } else if self.get_token_type() == TokenType::VOID {
             self.consume();
-            println!("Void token: {:?}", self.consumed_token);
-            self.current_type.type_base = TypeBase::Void;
+            self.current_type = Type::new(TypeBase::Void, -1);
         } else {
-            println!("Cannot declare a funcion without a type!");
-            return false;
+            return None;
         }
+        let ret_type = self.current_type.clone();
 
         if self.get_token_type() == TokenType::ID {
             self.consume();
@@ -451,35 +725,52 @@ impl Parser {
             if self.get_token_type() == TokenType::LPAR {
                 self.consume();
 
-                if self.symbols_table.find_symbol(&token_name).is_some() {
-                    println!("ERROR: symbol redefinition: {}", token_name);
-                    return false;
+                let name_span = self.token_span(self.current_token_index - 2);
+                let mut prototype = None;
+                if let Some(existing_symbol) = self.symbols_table.find_symbol(&token_name) {
+                    if existing_symbol.class != Class::Func || !existing_symbol.external {
+                        let previous = existing_symbol.span;
+                        self.redefinition(&token_name, name_span, previous);
+                        self.current_token_index = start_token;
+                        return None;
+                    }
+                    // the prototype is replaced by this declaration once both are checked to agree
+                    prototype = Some(existing_symbol.clone());
+                    self.symbols_table.remove_symbol(&token_name);
                 }
 
+                self.frame_size = 0;
                 self.crt_func = Some(Symbol::new(
-                            token_name, 
-                            Class::Func, 
-                            None, 
-                            Some(self.current_type.clone()),
-                            self.crt_depth,
-                            Some(symbols::SymbolTable { table: Vec::new()}),
+                            token_name.clone(),
+                            Class::Func,
+                            None,
+                            Some(ret_type.clone()),
+                            self.symbols_table.depth(),
+                            Some(symbols::SymbolTable::new()),
                             None
                             )
                     );
+                if let Some(crt_func) = self.crt_func.as_mut() {
+                    crt_func.span = Some(name_span);
+                }
                 self.symbols_table.add_symbol(self.crt_func.clone().expect("Adding function symbol into the table"));
-                self.crt_depth += 1;
+                // the arguments share their scope with the outermost block of the body
+                self.symbols_table.enter_scope();
+
+                let mut params = Vec::new();
+                if let Some(param) = self.func_arg() {
+                    params.push(param);
 
-                if self.func_arg() {
                     loop {
                         if self.get_token_type() == TokenType::COMMA {
                             self.consume();
 
-                            if !self.func_arg() {
-                                println!("Comma should be followed by an argument!");
-                                self.current_token_index = start_token;
-                                return false;
+                            if let Some(param) = self.func_arg() {
+                                params.push(param);
                             } else {
-                                continue;
+                                self.expected("expected a function argument after ','");
+                                self.current_token_index = start_token;
+                                return None;
                             }
                         } else {
                             break;
@@ -489,135 +780,177 @@ impl Parser {
 
                 if self.get_token_type() == TokenType::RPAR {
                     self.consume();
-                    self.crt_depth -= 1;
-                    if !self.stm_compound() {
-                        return false;
-                    } else {
-                        if let Some(crt_func) = &self.crt_func {
-                            self.symbols_table.delete_symbol_after(crt_func);
+
+                    // the caller pushes the arguments, then CALL the return address and ENTER the old frame pointer
+                    let args_size: i64 = params.iter().map(|param| self.symbols_table.arg_size(&param.r#type)).sum();
+                    let mut offset = -2 * PTR_SIZE - args_size;
+                    for param in &mut params {
+                        param.offset = offset;
+                        offset += self.symbols_table.arg_size(&param.r#type);
+                        if let Some(arg) = self.symbols_table.find_in_scope_mut(&param.name) {
+                            arg.offset = param.offset;
                         }
-                        self.crt_func = None;
-                        return true;
                     }
-                    
 
+                    if let Some(prototype) = prototype {
+                        let prototype_args = prototype.args.iter().flat_map(|args| args.iter());
+                        let same_args = params.len() == prototype_args.clone().count()
+                            && params.iter().zip(prototype_args)
+                                .all(|(param, arg)| arg.r#type.as_ref().is_some_and(|t| t.matches(&param.r#type)));
+                        if !same_args || !prototype.r#type.as_ref().is_some_and(|t| t.matches(&ret_type)) {
+                            self.conflicting_types(&token_name, name_span, prototype.span);
+                        }
+                    }
+
+                    let body = if self.get_token_type() == TokenType::SEMICOLON {
+                        self.consume();
+                        if let Some(func) = self.symbols_table.find_symbol_mut(&token_name) {
+                            func.external = true;
+                        }
+                        None
+                    } else {
+                        Some(Box::new(self.stm_compound(false)?))
+                    };
+                    self.symbols_table.exit_scope();
+                    self.crt_func = None;
+
+                    return Some(FuncDecl {
+                        name: token_name,
+                        ret_type,
+                        params,
+                        body,
+                        span: name_span,
+                    });
                 } else {
-                    println!("Expecting ')' after function arguments!");
+                    self.expected("expected ')' after the function arguments");
                 }
             } else {
-                println!("Function needs an opening paranthesis for arguments!");
+                self.expected("expected '(' after the function name");
             }
         } else {
-            println!("Function needs an identifier!");
+            self.expected("expected a function name");
         }
 
         self.current_token_index = start_token;
-        return false;
+        None
     }
 
-    fn func_arg(&mut self) -> bool {
+    fn func_arg(&mut self) -> Option<VarDecl> {
         let start_token = self.current_token_index;
 
         if self.type_base() {
             if self.get_token_type() == TokenType::ID {
                 self.consume();
                 let token_name = self.consumed_token.clone().unwrap().literal;
+                let name_span = self.consumed_span();
+                let mut dim = None;
+                self.array_decl(&mut dim);
+                if let Some(existing_symbol) = self.symbols_table.find_in_scope(&token_name) {
+                    let previous = existing_symbol.span;
+                    self.redefinition(&token_name, name_span, previous);
+                }
                 let s = self.symbols_table.add_symbol(
                         Symbol::new(
-                            token_name.clone(), 
-                            Class::Var, 
-                            Some(Memory::Arg), 
+                            token_name.clone(),
+                            Class::Var,
+                            Some(Memory::Arg),
                             Some(self.current_type.clone()),
-                            self.crt_depth,
+                            self.symbols_table.depth(),
                             None,
                             None
                             )
                         );
+                s.span = Some(name_span);
 
                 if let Some(crt_func) = &self.crt_func {
                     let crt_func_name = crt_func.name.clone();
-                    if let Some(ref mut func) = self.symbols_table.find_symbol_mut(&crt_func_name) {
+                    let depth = self.symbols_table.depth();
+                    if let Some(func) = self.symbols_table.find_symbol_mut(&crt_func_name) {
                         let func_args = func.args.as_mut().unwrap();
                         func_args.add_symbol(Symbol::new(
                                 token_name.clone(),
-                                Class::Var, 
-                                Some(Memory::Arg), 
-                                Some(self.current_type.clone()), 
-                                self.crt_depth, 
-                                None, 
+                                Class::Var,
+                                Some(Memory::Arg),
+                                Some(self.current_type.clone()),
+                                depth,
+                                None,
                                 None
                                 )
                             );
                     }
                 }
-                self.array_decl();
 
-                return true;
+                Some(VarDecl {
+                    name: token_name,
+                    r#type: self.current_type.clone(),
+                    dim,
+                    offset: 0,
+                    span: name_span,
+                })
             } else {
-                println!("Missing identifier!");
+                self.expected("expected an argument name");
                 self.current_token_index = start_token;
-                return false;
+                None
             }
         } else {
-            return false;
+            None
         }
     }
 
-    fn stm(&mut self) -> bool {
+    fn stm(&mut self) -> Option<Stmt> {
         let start_token = self.current_token_index;
-        let mut rv_copy = self.rv.clone();
+        let stm_span = self.token_span(start_token);
 
         match self.get_token_type() {
-            TokenType::LACC => {
-                if self.stm_compound() {
-                    return true;
-                } else {
-                    return false;
-                }
-            }
+            TokenType::LACC => self.stm_compound(true),
 
             TokenType::IF => {
                 self.consume();
-                
+
                 if self.get_token_type() == TokenType::LPAR {
                     self.consume();
 
-                    if self.expr(&mut rv_copy) {
-                        if rv_copy.r#type.as_ref().map_or(false, |t| t.type_base == TypeBase::Struct) {
-                            println!("A structure cannot be logically tested");
-                            return false;
-                        }
-                        self.rv = rv_copy;
+                    let mut rv = RetVal::new();
+                    if let Some(cond) = self.expr(&mut rv) {
+                        self.check_condition(rv.r#type.as_ref(), stm_span)?;
 
                         if self.get_token_type() == TokenType::RPAR {
                             self.consume();
-                            
-                            if self.stm() {
-                                if self.get_token_type() == TokenType:: ELSE {
+
+                            if let Some(then_branch) = self.stm() {
+                                let mut else_branch = None;
+
+                                if self.get_token_type() == TokenType::ELSE {
                                     self.consume();
-                                    if !self.stm() {
-                                        println!("Else branch left empty, should be followed by another statement!");
+
+                                    if let Some(stm) = self.stm() {
+                                        else_branch = Some(Box::new(stm));
+                                    } else {
+                                        self.expected("expected a statement after 'else'");
                                         self.current_token_index = start_token;
-                                        return false;
+                                        return None;
                                     }
                                 }
-                                
-                                return true;
+
+                                return Some(Stmt::new(
+                                    StmtKind::If { cond, then_branch: Box::new(then_branch), else_branch },
+                                    stm_span,
+                                ));
                             } else {
-                                println!("Missing statement!");
+                                self.expected("expected a statement");
                             }
                         } else {
-                            println!("Expected ')' to close the if statement!");
+                            self.expected("expected ')' to close the if condition");
                         }
                     } else {
-                        println!("Expected expression!");
+                        self.expected("expected an expression");
                     }
                 } else {
-                    println!("Expected '(' to open the if statement!");
+                    self.expected("expected '(' after 'if'");
                 }
 
                 self.current_token_index = start_token;
-                return false;
+                None
             }
 
             TokenType::WHILE => {
@@ -626,33 +959,30 @@ impl Parser {
                 if self.get_token_type() == TokenType::LPAR {
                     self.consume();
 
-                    if self.expr(&mut rv_copy) {
-                        if rv_copy.r#type.as_ref().map_or(false, |t| t.type_base == TypeBase::Struct) {
-                            println!("A structure cannot be logically tested");
-                            return false;
-                        }
-                        self.rv = rv_copy;
+                    let mut rv = RetVal::new();
+                    if let Some(cond) = self.expr(&mut rv) {
+                        self.check_condition(rv.r#type.as_ref(), stm_span)?;
 
                         if self.get_token_type() == TokenType::RPAR {
                             self.consume();
 
-                            if self.stm() {
-                                return true;
+                            if let Some(body) = self.stm() {
+                                return Some(Stmt::new(StmtKind::While { cond, body: Box::new(body) }, stm_span));
                             } else {
-                                println!("Missing statement!");
+                                self.expected("expected a statement");
                             }
                         } else {
-                            println!("Expected ')' to close the while statement!");
+                            self.expected("expected ')' to close the while condition");
                         }
                     } else {
-                        println!("Missing expression!");
+                        self.expected("expected an expression");
                     }
                 } else {
-                    println!("Expected '(' to open the while statement!");
+                    self.expected("expected '(' after 'while'");
                 }
 
                 self.current_token_index = start_token;
-                return false;
+                None
             }
 
             TokenType::FOR => {
@@ -661,68 +991,50 @@ impl Parser {
                 if self.get_token_type() == TokenType::LPAR {
                     self.consume();
 
-                    let mut rv1 = RetVal {
-                        r#type: None,
-                        is_ctval: false,
-                        is_lval: false,
-                        ct_val: None,
-                    };
-
-                    self.expr(&mut rv1);
+                    let mut rv1 = RetVal::new();
+                    let init = self.expr(&mut rv1);
 
                     if self.get_token_type() == TokenType::SEMICOLON {
                         self.consume();
 
-                        let mut rv2 = RetVal {
-                            r#type: None,
-                            is_ctval: false,
-                            is_lval: false,
-                            ct_val: None,
-                        };
-                        self.expr(&mut rv2);
+                        let mut rv2 = RetVal::new();
+                        let cond = self.expr(&mut rv2);
 
-                        if let Some(rv_type) = &rv2.r#type {
-                            if rv_type.type_base == TypeBase::Struct {
-                                println!("A structure cannot be logically tested");
-                                return false;
-                            }
-                        }
+                        self.check_condition(rv2.r#type.as_ref(), stm_span)?;
 
                         if self.get_token_type() == TokenType::SEMICOLON {
                             self.consume();
 
-                            let mut rv3 = RetVal {
-                                r#type: None,
-                                is_ctval: false,
-                                is_lval: false,
-                                ct_val: None,
-                            };
-                            self.expr(&mut rv3);
+                            let mut rv3 = RetVal::new();
+                            let step = self.expr(&mut rv3);
 
                             if self.get_token_type() == TokenType::RPAR {
                                 self.consume();
 
-                                if self.stm() {
-                                    return true;
+                                if let Some(body) = self.stm() {
+                                    return Some(Stmt::new(
+                                        StmtKind::For { init, cond, step, body: Box::new(body) },
+                                        stm_span,
+                                    ));
                                 } else {
-                                    println!("Missing statement!");
-                                    return false;
+                                    self.expected("expected a statement");
+                                    return None;
                                 }
                             } else {
-                                println!("Expected ')' to close the for statement!");
+                                self.expected("expected ')' to close the for header");
                             }
                         } else {
-                            println!("Expected ';' in 'for' statement specifier!");
+                            self.expected("expected ';' in the for header");
                         }
                     } else {
-                        println!("Expected ';' in 'for' statement specifier!");
+                        self.expected("expected ';' in the for header");
                     }
                 } else {
-                    println!("Expected '(' to open the for statement");
+                    self.expected("expected '(' after 'for'");
                 }
 
                 self.current_token_index = start_token;
-                return false;
+                None
             }
 
             TokenType::BREAK => {
@@ -730,544 +1042,642 @@ impl Parser {
 
                 if self.get_token_type() == TokenType::SEMICOLON {
                     self.consume();
-                    return true;
+                    Some(Stmt::new(StmtKind::Break, stm_span))
                 } else {
-                    println!("Expected ';' after expression!");
+                    self.expected("expected ';' after the statement");
                     self.current_token_index = start_token;
-                    return false;
+                    None
                 }
             }
 
             TokenType::RETURN => {
                 self.consume();
-                if self.expr(&mut rv_copy) {
-                    self.rv = rv_copy.clone();
 
+                let value_start = self.current_token_index;
+                let mut rv = RetVal::new();
+                let mut value = self.expr(&mut rv);
+                if value.is_none()
+                    && self.current_token_index == value_start
+                    && self.get_token_type() == TokenType::SEMICOLON
+                    && self.crt_func.as_ref().is_some_and(|func| {
+                        func.r#type.as_ref().is_some_and(|t| t.type_base != TypeBase::Void)
+                    })
+                {
+                    self.error("a non-void function must return a value", stm_span);
+                    return None;
+                }
+                if let Some(expr) = value.take() {
                     if let Some(crt_func) = self.crt_func.clone() {
-                        if crt_func.r#type.as_ref().map_or(false, |t| t.type_base == TypeBase::Void) && rv_copy.r#type.is_some() {
-                            println!("A void function cannot return a value");
-                            return false;
-                        }
-                        if let Some(ref rv_type) = rv_copy.r#type {
-                            Parser::cast_var(&crt_func.r#type.unwrap(), rv_type);
+                        if crt_func.r#type.as_ref().is_some_and(|t| t.type_base == TypeBase::Void) && rv.r#type.is_some() {
+                            self.error("a void function cannot return a value", stm_span);
+                            return None;
                         }
+                        value = match (&crt_func.r#type, &rv.r#type) {
+                            (Some(ret_type), Some(rv_type)) => Some(self.convert(expr, ret_type, rv_type, stm_span)?),
+                            _ => Some(expr),
+                        };
+                    } else {
+                        value = Some(expr);
                     }
                 }
 
                 if self.get_token_type() == TokenType::SEMICOLON {
                     self.consume();
-                    return true;
+                    Some(Stmt::new(StmtKind::Return(value), stm_span))
                 } else {
-                    println!("Expected ';' after expression!");
+                    self.expected("expected ';' after the statement");
                     self.current_token_index = start_token;
-                    return false;
+                    None
                 }
             }
-            
+
             _ => {
-                self.expr(&mut rv_copy);
-                self.rv = rv_copy;
+                let mut rv = RetVal::new();
+                let value = self.expr(&mut rv);
+                // only an empty statement has no expression; anything else failed to parse
+                if value.is_none() && self.current_token_index != start_token {
+                    return None;
+                }
 
                 if self.get_token_type() == TokenType::SEMICOLON {
                     self.consume();
-                    return true;
+                    Some(Stmt::new(StmtKind::Expr(value), stm_span))
                 } else {
-                    println!("Expected ';' after expression!");
-                    return false;
+                    self.expected("expected ';' after the expression");
+                    None
                 }
             }
         }
     }
 
-    fn stm_compound(&mut self) -> bool {
+    /// Parses a block. The body of a function is parsed with `new_scope` unset, since
+    /// its locals live in the scope of the arguments.
+    fn stm_compound(&mut self, new_scope: bool) -> Option<Stmt> {
         let start_token = self.current_token_index;
 
         if self.get_token_type() == TokenType::LACC {
             self.consume();
+            let span = self.consumed_span();
 
-            let start = self.symbols_table.table.last().cloned();
-            self.crt_depth += 1;
-            loop {
-                if self.decl_var() {
-                    continue;
-                } else if self.stm() {
-                    continue;
+            if new_scope {
+                self.symbols_table.enter_scope();
+            }
+
+            let mut stms = Vec::new();
+            while !matches!(self.get_token_type(), TokenType::RACC | TokenType::EOF) {
+                let start = self.current_token_index;
+                let reported = self.diagnostics.len();
+                self.furthest_failure = None;
+
+                if let Some(decls) = self.decl_var() {
+                    stms.extend(decls.into_iter().map(|decl| {
+                        let span = decl.span;
+                        Stmt::new(StmtKind::Var(decl), span)
+                    }));
+                } else if let Some(stm) = self.stm() {
+                    stms.push(stm);
                 } else {
-                    break
+                    self.recover(start, reported, "expected a statement");
+                    self.synchronize_statement();
                 }
             }
 
             if self.get_token_type() == TokenType::RACC {
                 self.consume();
-                self.crt_depth -= 1;
-
-                if let Some(start) = start {
-                    self.symbols_table.delete_symbol_after(&start);
+                if new_scope {
+                    self.symbols_table.exit_scope();
                 }
-                return true;
+                Some(Stmt::new(StmtKind::Compound(stms), span))
             } else {
-                println!("Expected '}}' to close the compound statement!");
+                self.error("expected '}' to close the compound statement", self.token_span(self.current_token_index));
+                if new_scope {
+                    self.symbols_table.exit_scope();
+                }
                 self.current_token_index = start_token;
-                return false;
+                None
             }
         } else {
-            println!("Expected '{{' to open the compound statement!");
-            return false;
+            self.expected("expected '{' to open the compound statement");
+            None
         }
     }
 
-    fn expr(&mut self, rv: &mut RetVal) -> bool {
-        return self.expr_assign(rv);
+    /// Evaluates a binary operator on two constants. Arithmetic and relational operands
+    /// have already been converted to the type the operation is done in.
+    fn fold(op: BinaryOp, lhs: &CtVal, rhs: &CtVal) -> Result<Option<CtVal>, &'static str> {
+        if let BinaryOp::And | BinaryOp::Or = op {
+            let (Some(lhs), Some(rhs)) = (lhs.is_true(), rhs.is_true()) else { return Ok(None) };
+            let value = if op == BinaryOp::And { lhs && rhs } else { lhs || rhs };
+            return Ok(Some(CtVal::Int(value as i64)));
+        }
+
+        let value = match (lhs, rhs) {
+            (CtVal::Int(_), CtVal::Int(0)) | (CtVal::Double(_), CtVal::Double(0.0)) if op == BinaryOp::Div => {
+                return Err("division by zero in a constant expression");
+            }
+            (CtVal::Int(lhs), CtVal::Int(rhs)) => {
+                let value = match op {
+                    BinaryOp::Add => lhs.checked_add(*rhs),
+                    BinaryOp::Sub => lhs.checked_sub(*rhs),
+                    BinaryOp::Mul => lhs.checked_mul(*rhs),
+                    BinaryOp::Div => lhs.checked_div(*rhs),
+                    _ => Some(Parser::compare(op, lhs, rhs) as i64),
+                };
+                CtVal::Int(value.ok_or("integer overflow in a constant expression")?)
+            }
+            (CtVal::Double(lhs), CtVal::Double(rhs)) => match op {
+                BinaryOp::Add => CtVal::Double(lhs + rhs),
+                BinaryOp::Sub => CtVal::Double(lhs - rhs),
+                BinaryOp::Mul => CtVal::Double(lhs * rhs),
+                BinaryOp::Div => CtVal::Double(lhs / rhs),
+                _ => CtVal::Int(Parser::compare(op, lhs, rhs) as i64),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
     }
 
-    fn expr_assign(&mut self, rv: &mut RetVal) -> bool {
+    fn compare<T: PartialOrd>(op: BinaryOp, lhs: T, rhs: T) -> bool {
+        match op {
+            BinaryOp::Eq => lhs == rhs,
+            BinaryOp::NotEq => lhs != rhs,
+            BinaryOp::Less => lhs < rhs,
+            BinaryOp::LessEq => lhs <= rhs,
+            BinaryOp::Greater => lhs > rhs,
+            _ => lhs >= rhs,
+        }
+    }
+
+    /// Folds `op` when both operands are constants, first converting them to `r#type`, the
+    /// type of the operation, if it has one. A constant division by zero or an overflow is
+    /// reported at `span`.
+    fn fold_binary(&mut self, op: BinaryOp, rv: &mut RetVal, rve: &RetVal, r#type: Option<&Type>, span: Span) -> Option<()> {
+        let operands = match (rv.const_val(), rve.const_val(), r#type) {
+            (Some(lhs), Some(rhs), Some(r#type)) => lhs.cast(&r#type.type_base).zip(rhs.cast(&r#type.type_base)),
+            (Some(lhs), Some(rhs), None) => Some((lhs.clone(), rhs.clone())),
+            _ => None,
+        };
+        rv.ct_val = match operands.map(|(lhs, rhs)| Parser::fold(op, &lhs, &rhs)).transpose() {
+            Ok(value) => value.flatten(),
+            Err(message) => {
+                self.error(message, span);
+                return None;
+            }
+        };
+        rv.is_ctval = rv.ct_val.is_some();
+        Some(())
+    }
+
+    /// The value of a literal expression, left behind by the parser when folding constants.
+    fn literal_val(expr: &Expr) -> Option<CtVal> {
+        match expr.kind {
+            ExprKind::Int(value) => Some(CtVal::Int(value)),
+            ExprKind::Real(value) => Some(CtVal::Double(value)),
+            ExprKind::Char(value) => Some(CtVal::Char(value)),
+            _ => None,
+        }
+    }
+
+    /// The literal expression standing for a folded constant of type `r#type`.
+    fn literal_expr(value: &CtVal, r#type: Type, span: Span) -> Option<Expr> {
+        let kind = match value {
+            CtVal::Int(value) => ExprKind::Int(*value),
+            CtVal::Double(value) => ExprKind::Real(*value),
+            CtVal::Char(value) => ExprKind::Char(*value),
+            CtVal::Str(_) => return None,
+        };
+        Some(Expr::new(kind, Some(r#type), span))
+    }
+
+    /// Types an arithmetic or relational operator: both operands are converted to their
+    /// common type and the operation is folded when they are constants. Returns the node
+    /// of the operator, or the literal it folds to.
+    fn binary_operands(&mut self, op: BinaryOp, rv: &mut RetVal, rve: &RetVal, mut lhs: Expr, mut rhs: Expr, span: Span) -> Option<Expr> {
+        if let (Some(lhs_type), Some(rhs_type)) = (rv.r#type.clone(), rve.r#type.clone()) {
+            let action = match op {
+                BinaryOp::Add | BinaryOp::Sub => "added or subtracted",
+                BinaryOp::Mul | BinaryOp::Div => "multiplied or divided",
+                _ => "compared",
+            };
+            if lhs_type.n_elements > -1 || rhs_type.n_elements > -1 {
+                self.error(format!("an array cannot be {}", action), span);
+                return None;
+            }
+            if lhs_type.type_base == TypeBase::Struct || rhs_type.type_base == TypeBase::Struct {
+                self.error(format!("a structure cannot be {}", action), span);
+                return None;
+            }
+
+            let r#type;
+            (lhs, rhs, r#type) = self.arith_operands(lhs, rhs, &lhs_type, &rhs_type, span)?;
+            self.fold_binary(op, rv, rve, Some(&r#type), span)?;
+            rv.r#type = match op {
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => Some(r#type),
+                _ => Some(Type::new(TypeBase::Int, -1)),
+            };
+            rv.is_lval = false;
+        }
+        Some(Parser::binary_expr(op, lhs, rhs, rv))
+    }
+
+    /// The node of a binary operator, or the literal it folds to.
+    fn binary_expr(op: BinaryOp, lhs: Expr, rhs: Expr, rv: &RetVal) -> Expr {
+        let span = lhs.span.to(rhs.span);
+        if let (Some(value), Some(r#type)) = (rv.const_val(), &rv.r#type) {
+            if let Some(expr) = Parser::literal_expr(value, r#type.clone(), span) {
+                return expr;
+            }
+        }
+        Expr::new(ExprKind::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs) }, rv.r#type.clone(), span)
+    }
+
+    fn expr(&mut self, rv: &mut RetVal) -> Option<Expr> {
+        self.expr_assign(rv)
+    }
+
+    fn expr_assign(&mut self, rv: &mut RetVal) -> Option<Expr> {
         let start_token = self.current_token_index;
 
-        if self.expr_unary(rv) {
+        if let Some(target) = self.expr_unary(rv) {
             if self.get_token_type() == TokenType::ASSIGN {
+                let op_index = self.current_token_index;
                 self.consume();
+                let op_span = self.consumed_span();
 
-                let token_type = self.consumed_token.clone().unwrap().r#type;
-
-                let mut rve = RetVal {
-                    r#type: None,
-                    is_ctval: false,
-                    is_lval: false,
-                    ct_val: None,
+                let mut rve = RetVal::new();
+                let Some(value) = self.expr_assign(&mut rve) else {
+                    self.expected_operand(op_index);
+                    self.current_token_index = start_token;
+                    return None;
                 };
 
-                if self.expr_assign(&mut rve) {
-                    if !rv.is_lval {
-                        println!("Cannot assign to a non-lval");
-                        return false;
-                    }
+                if !rv.is_lval {
+                    self.error("cannot assign to a non-lval", op_span);
+                    return None;
+                }
 
-                    if rv.r#type.as_ref().map_or(false, |t| t.n_elements > -1) 
-                        || rve.r#type.as_ref().map_or(false, |t| t.n_elements > -1) 
-                    {
-                        println!("Arrays cannot be assigned");
-                        return false;
-                    }
+                if rv.r#type.as_ref().is_some_and(|t| t.n_elements > -1)
+                    || rve.r#type.as_ref().is_some_and(|t| t.n_elements > -1)
+                {
+                    self.error("arrays cannot be assigned", op_span);
+                    return None;
+                }
 
-                    Parser::cast_var(rv.r#type.as_mut().unwrap(), rve.r#type.as_ref().unwrap());
-                    rv.is_ctval = false;
-                    rv.is_lval = false;
+                let value = self.convert(value, rv.r#type.as_ref().unwrap(), rve.r#type.as_ref().unwrap(), op_span)?;
+                rv.is_ctval = false;
+                rv.is_lval = false;
 
-                    return true;
-                } else {
-                    return false
-                }
-            } else {
-                self.current_token_index = start_token;
-                return self.expr_or(rv);
+                let span = target.span.to(value.span);
+                return Some(Expr::new(
+                    ExprKind::Assign { target: Box::new(target), value: Box::new(value) },
+                    rv.r#type.clone(),
+                    span,
+                ));
             }
         }
 
-        return false;
+        self.current_token_index = start_token;
+        self.expr_or(rv)
     }
 
-    fn expr_or(&mut self, rv: &mut RetVal) -> bool {
-        if self.expr_and(rv) {
-            return self.expr_or_tail(rv);
-        } else {
-            return false;
-        }
+    fn expr_or(&mut self, rv: &mut RetVal) -> Option<Expr> {
+        let lhs = self.expr_and(rv)?;
+        self.expr_or_tail(rv, lhs)
     }
 
-    fn expr_or_tail(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_or_tail(&mut self, rv: &mut RetVal, lhs: Expr) -> Option<Expr> {
         let start_token = self.current_token_index;
 
         if self.get_token_type() == TokenType::OR {
             self.consume();
+            let op_span = self.consumed_span();
 
-            let token_type = self.consumed_token.clone().unwrap().r#type;
-
-            let mut rve = RetVal {
-                r#type: None,
-                is_ctval: false,
-                is_lval: false,
-                ct_val: None,
-            };
+            let mut rve = RetVal::new();
 
-            if self.expr_and(&mut rve) {
-                if let Some(rv_type) = &rv.r#type {
-                    if rv_type.type_base == TypeBase::Struct || rve.r#type.as_ref().map_or(false, |t| t.type_base == TypeBase::Struct) {
-                        println!("A structure cannot be logically tested");
-                        return false;
-                    }
+            if let Some(rhs) = self.expr_and(&mut rve) {
+                if rv.r#type.is_some() {
+                    self.check_condition(rv.r#type.as_ref(), op_span)?;
+                    self.check_condition(rve.r#type.as_ref(), op_span)?;
 
                     rv.r#type = Some(Type::new(TypeBase::Int, -1));
-                    rv.is_ctval = false;
+                    self.fold_binary(BinaryOp::Or, rv, &rve, None, op_span)?;
                     rv.is_lval = false;
                 }
 
-                return self.expr_or_tail(rv); 
+                let expr = Parser::binary_expr(BinaryOp::Or, lhs, rhs, rv);
+                self.expr_or_tail(rv, expr)
             } else {
+                self.expected_operand(start_token);
                 self.current_token_index = start_token;
-                return false;
+                None
             }
         } else {
-            return true;
+            Some(lhs)
         }
     }
 
-    fn expr_and(&mut self, rv: &mut RetVal) -> bool {
-        if self.expr_eq(rv) {
-            return self.expr_and_tail(rv);
-        } else {
-            return false;
-        }
+    fn expr_and(&mut self, rv: &mut RetVal) -> Option<Expr> {
+        let lhs = self.expr_eq(rv)?;
+        self.expr_and_tail(rv, lhs)
     }
 
-    fn expr_and_tail(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_and_tail(&mut self, rv: &mut RetVal, lhs: Expr) -> Option<Expr> {
         let start_token = self.current_token_index;
 
         if self.get_token_type() == TokenType::AND {
             self.consume();
+            let op_span = self.consumed_span();
 
-            let token_type = self.consumed_token.clone().unwrap().r#type;
+            let mut rve = RetVal::new();
 
-            let mut rve = RetVal {
-                r#type: None,
-                is_ctval: false,
-                is_lval: false,
-                ct_val: None,
-            };
-
-            if self.expr_eq(&mut rve) {
-                if let Some(rv_type) = &rv.r#type {
-                    if rv_type.type_base == TypeBase::Struct || rve.r#type.as_ref().map_or(false, |t| t.type_base == TypeBase::Struct) {
-                        println!("A structure cannot be logically tested");
-                        return false;
-                    }
+            if let Some(rhs) = self.expr_eq(&mut rve) {
+                if rv.r#type.is_some() {
+                    self.check_condition(rv.r#type.as_ref(), op_span)?;
+                    self.check_condition(rve.r#type.as_ref(), op_span)?;
 
                     rv.r#type = Some(Type::new(TypeBase::Int, -1));
-                    rv.is_ctval = false;
+                    self.fold_binary(BinaryOp::And, rv, &rve, None, op_span)?;
                     rv.is_lval = false;
                 }
 
-                return self.expr_and_tail(rv);
+                let expr = Parser::binary_expr(BinaryOp::And, lhs, rhs, rv);
+                self.expr_and_tail(rv, expr)
             } else {
+                self.expected_operand(start_token);
                 self.current_token_index = start_token;
-                return false;
+                None
             }
         } else {
-            return true;
+            Some(lhs)
         }
     }
 
-    fn expr_eq(&mut self, rv: &mut RetVal) -> bool {
-        if self.expr_rel(rv) {
-            return self.expr_eq_tail(rv);
-        } else {
-            return false;
-        }
+    fn expr_eq(&mut self, rv: &mut RetVal) -> Option<Expr> {
+        let lhs = self.expr_rel(rv)?;
+        self.expr_eq_tail(rv, lhs)
     }
 
-    fn expr_eq_tail(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_eq_tail(&mut self, rv: &mut RetVal, lhs: Expr) -> Option<Expr> {
         let start_token = self.current_token_index;
 
-        if self.get_token_type() == TokenType::EQUAL 
+        if self.get_token_type() == TokenType::EQUAL
             || self.get_token_type() == TokenType::NOTEQ
         {
             self.consume();
-
-            let token_type = self.consumed_token.clone().unwrap().r#type;
-
-            let mut rve = RetVal {
-                r#type: None,
-                is_ctval: false,
-                is_lval: false,
-                ct_val: None,
+            let op_span = self.consumed_span();
+            let op = if self.consumed_token.as_ref().unwrap().r#type == TokenType::EQUAL {
+                BinaryOp::Eq
+            } else {
+                BinaryOp::NotEq
             };
 
-            if self.expr_rel(&mut rve) {
-                if let Some(rv_type) = &rv.r#type {
-                    if rv_type.type_base == TypeBase::Struct || rve.r#type.as_ref().map_or(false, |t| t.type_base == TypeBase::Struct) {
-                        println!("A structure cannot be compared");
-                        return false;
-                    }
-
-                    rv.r#type = Some(Type::new(TypeBase::Int, -1));
-                    rv.is_ctval = false;
-                    rv.is_lval = false;
-                }
+            let mut rve = RetVal::new();
 
-                return self.expr_eq_tail(rv);
+            if let Some(rhs) = self.expr_rel(&mut rve) {
+                let expr = self.binary_operands(op, rv, &rve, lhs, rhs, op_span)?;
+                self.expr_eq_tail(rv, expr)
             } else {
+                self.expected_operand(start_token);
                 self.current_token_index = start_token;
-                return false;
+                None
             }
         } else {
-            return true;
+            Some(lhs)
         }
     }
 
-    fn expr_rel(&mut self, rv: &mut RetVal) -> bool {
-        if self.expr_add(rv) {
-            return self.expr_rel_tail(rv);
-        } else {
-            return false;
-        }
+    fn expr_rel(&mut self, rv: &mut RetVal) -> Option<Expr> {
+        let lhs = self.expr_add(rv)?;
+        self.expr_rel_tail(rv, lhs)
     }
 
-    fn expr_rel_tail(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_rel_tail(&mut self, rv: &mut RetVal, lhs: Expr) -> Option<Expr> {
         let start_token = self.current_token_index;
 
-        if self.get_token_type() == TokenType::LESS 
-            || self.get_token_type() == TokenType::LESSEQ 
-            || self.get_token_type() == TokenType::GREATER 
+        if self.get_token_type() == TokenType::LESS
+            || self.get_token_type() == TokenType::LESSEQ
+            || self.get_token_type() == TokenType::GREATER
             || self.get_token_type() == TokenType::GREATEREQ
         {
             self.consume();
-
-            let token_type = self.consumed_token.clone().unwrap().r#type;
-
-            let mut rve = RetVal {
-                r#type: None,
-                is_ctval: false,
-                is_lval: false,
-                ct_val: None,
+            let op_span = self.consumed_span();
+            let op = match self.consumed_token.as_ref().unwrap().r#type {
+                TokenType::LESS => BinaryOp::Less,
+                TokenType::LESSEQ => BinaryOp::LessEq,
+                TokenType::GREATER => BinaryOp::Greater,
+                _ => BinaryOp::GreaterEq,
             };
 
-            if self.expr_add(&mut rve) {
-                if let Some(rv_type) = &rv.r#type {
-                    if rv_type.n_elements > -1 || rve.r#type.as_ref().map_or(false, |t| t.n_elements > -1) {
-                        println!("An array cannot be compared");
-                        return false;
-                    }
-                    if rv_type.type_base == TypeBase::Struct || rve.r#type.as_ref().map_or(false, |t| t.type_base == TypeBase::Struct) {
-                        println!("A structure cannot be compared");
-                        return false;
-                    }
-
-                    rv.r#type = Some(Type::new(TypeBase::Int, -1));
-                    rv.is_ctval = false;
-                    rv.is_lval = false;
-                }
+            let mut rve = RetVal::new();
 
-                return self.expr_rel_tail(rv);
+            if let Some(rhs) = self.expr_add(&mut rve) {
+                let expr = self.binary_operands(op, rv, &rve, lhs, rhs, op_span)?;
+                self.expr_rel_tail(rv, expr)
             } else {
+                self.expected_operand(start_token);
                 self.current_token_index = start_token;
-                return false;
+                None
             }
         } else {
-            return true;
+            Some(lhs)
         }
     }
 
-    fn expr_add(&mut self, rv: &mut RetVal) -> bool {
-        if self.expr_mul(rv) {
-            return self.expr_add_tail(rv);
-        } else {
-            return false;
-        }
+    fn expr_add(&mut self, rv: &mut RetVal) -> Option<Expr> {
+        let lhs = self.expr_mul(rv)?;
+        self.expr_add_tail(rv, lhs)
     }
 
-    fn expr_add_tail(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_add_tail(&mut self, rv: &mut RetVal, lhs: Expr) -> Option<Expr> {
         let start_token = self.current_token_index;
 
-        if self.get_token_type() == TokenType::ADD 
-            || self.get_token_type() == TokenType::SUB 
+        if self.get_token_type() == TokenType::ADD
+            || self.get_token_type() == TokenType::SUB
         {
             self.consume();
-
-            let token_type = self.consumed_token.clone().unwrap().r#type;
-
-            let mut rve = RetVal {
-                r#type: None,
-                is_ctval: false,
-                is_lval: false,
-                ct_val: None,
+            let op_span = self.consumed_span();
+            let op = if self.consumed_token.as_ref().unwrap().r#type == TokenType::ADD {
+                BinaryOp::Add
+            } else {
+                BinaryOp::Sub
             };
 
-            if self.expr_mul(&mut rve) {
-                if let Some(rv_type) = &rv.r#type {
-                    if rv_type.n_elements > -1 || rve.r#type.as_ref().map_or(false, |t| t.n_elements > -1) {
-                        println!("An array cannot be added or substracted");
-                        return false;
-                    }
-                    if rv_type.type_base == TypeBase::Struct || rve.r#type.as_ref().map_or(false, |t| t.type_base == TypeBase::Struct) {
-                        println!("An structure cannot be added or substracted");
-                        return false;
-                    }
+            let mut rve = RetVal::new();
 
-                    rv.is_ctval = false;
-                    rv.is_lval = false;
-                }
-                return self.expr_add_tail(rv);
+            if let Some(rhs) = self.expr_mul(&mut rve) {
+                let expr = self.binary_operands(op, rv, &rve, lhs, rhs, op_span)?;
+                self.expr_add_tail(rv, expr)
             } else {
+                self.expected_operand(start_token);
                 self.current_token_index = start_token;
-                return false;
+                None
             }
         } else {
-            return true;
+            Some(lhs)
         }
     }
 
-    fn expr_mul(&mut self, rv: &mut RetVal) -> bool {
-        if self.expr_cast(rv) {
-            return self.expr_mul_tail(rv);
-        } else {
-            return false;
-        }
+    fn expr_mul(&mut self, rv: &mut RetVal) -> Option<Expr> {
+        let lhs = self.expr_cast(rv)?;
+        self.expr_mul_tail(rv, lhs)
     }
 
-    fn expr_mul_tail(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_mul_tail(&mut self, rv: &mut RetVal, lhs: Expr) -> Option<Expr> {
         let start_token = self.current_token_index;
 
-        if self.get_token_type() == TokenType::MUL 
-            || self.get_token_type() == TokenType::DIV 
+        if self.get_token_type() == TokenType::MUL
+            || self.get_token_type() == TokenType::DIV
         {
             self.consume();
-            let token_type = self.consumed_token.clone().unwrap().r#type;
-
-            let mut rve = RetVal {
-                r#type: None,
-                is_ctval: false,
-                is_lval: false,
-                ct_val: None,
+            let op_span = self.consumed_span();
+            let op = if self.consumed_token.as_ref().unwrap().r#type == TokenType::MUL {
+                BinaryOp::Mul
+            } else {
+                BinaryOp::Div
             };
 
-            if self.expr_cast(&mut rve) {
-                if let Some(rv_type) = &rv.r#type {
-                    if rv_type.n_elements > -1 || rve.r#type.as_ref().map_or(false, |t| t.n_elements > -1) {
-                        println!("An array cannot be multiplied or divided");
-                        return false;
-                    }
-                    if rv_type.type_base == TypeBase::Struct || rve.r#type.as_ref().map_or(false, |t| t.type_base == TypeBase::Struct) {
-                        println!("A structure cannot be multiplied or divided");
-                        return false;
-                    }
+            let mut rve = RetVal::new();
 
-                    rv.is_ctval = false;
-                    rv.is_lval = false;
-                }
-                return self.expr_mul_tail(rv);
+            if let Some(rhs) = self.expr_cast(&mut rve) {
+                let expr = self.binary_operands(op, rv, &rve, lhs, rhs, op_span)?;
+                self.expr_mul_tail(rv, expr)
             } else {
+                self.expected_operand(start_token);
                 self.current_token_index = start_token;
-                return false
+                None
             }
         } else {
-            return true;
+            Some(lhs)
         }
     }
 
-    fn expr_cast(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_cast(&mut self, rv: &mut RetVal) -> Option<Expr> {
         let start_token = self.current_token_index;
 
         if self.get_token_type() == TokenType::LPAR {
             self.consume();
+            let start_span = self.consumed_span();
 
             if self.type_name() {
                 if self.get_token_type() == TokenType::RPAR {
                     self.consume();
+                    let cast_type = self.current_type.clone();
 
-                    let mut rve = RetVal {
-                        r#type: None,
-                        is_lval: false,
-                        is_ctval: false,
-                        ct_val: None,
-                    };
+                    let mut rve = RetVal::new();
+                    let expr = self.expr_cast(&mut rve)?;
 
-                    if self.expr_cast(&mut rve) {
-                        let mut current_type = self.current_type.clone();
-                        Parser::cast_var(&mut current_type, rve.r#type.as_ref().unwrap());
-                        rv.r#type = Some(current_type.clone());
-                        rv.is_ctval = false;
-                        rv.is_lval = false;
+                    if let Err(e) = Parser::cast_var(&cast_type, rve.r#type.as_ref().unwrap()) {
+                        self.error(e.to_string(), start_span);
+                        return None;
+                    }
+                    rv.r#type = Some(cast_type.clone());
+                    rv.ct_val = rve.const_val().and_then(|value| value.cast(&cast_type.type_base));
+                    rv.is_ctval = rv.ct_val.is_some() && cast_type.n_elements < 0;
+                    rv.is_lval = false;
 
-                        return true;
-                    } else {
-                        return false;
+                    let span = start_span.to(expr.span);
+                    if let Some(expr) = rv.const_val().and_then(|value| Parser::literal_expr(value, cast_type.clone(), span)) {
+                        return Some(expr);
                     }
+                    Some(Expr::new(
+                        ExprKind::Cast { r#type: cast_type.clone(), expr: Box::new(expr) },
+                        Some(cast_type),
+                        span,
+                    ))
                 } else {
-                    println!("Expected ')' to close the expression!");
+                    self.expected("expected ')' after the cast type");
                     self.current_token_index = start_token;
-                    return false;
+                    None
                 }
             } else {
-                println!("Expected expression!");
                 self.current_token_index = start_token;
-                return false;
+                self.expr_unary(rv)
             }
         } else {
-            return self.expr_unary(rv);
+            self.expr_unary(rv)
         }
     }
 
-    fn expr_unary(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_unary(&mut self, rv: &mut RetVal) -> Option<Expr> {
         let start_token = self.current_token_index;
 
         if self.get_token_type() == TokenType::SUB || self.get_token_type() == TokenType::NOT {
             self.consume();
             let token_type = self.consumed_token.clone().unwrap().r#type;
+            let op_span = self.consumed_span();
 
-            if self.expr_unary(rv) {
-                if token_type == TokenType::SUB {
-                    if let Some(t) = &rv.r#type {
-                        if t.n_elements >= 0 {
-                            println!("unary '-' cannot be applied to an array");
-                            return false;
-                        }
-                        if t.type_base == TypeBase::Struct {
-                            println!("unary '-' cannot be applied to a struct");
-                            return false;
-                        }
-                    }
-                } else if token_type == TokenType::NOT {
-                    if let Some(t) = &rv.r#type {
-                        if t.type_base == TypeBase::Struct {
-                            println!("'!' cannot be applied to a struct");
-                            return false;
-                        }
+            if let Some(operand) = self.expr_unary(rv) {
+                let op = if token_type == TokenType::SUB {
+                    self.check_operand("unary '-'", rv.r#type.as_ref(), op_span)?;
+                    if rv.r#type.as_ref().is_some_and(|t| t.type_base == TypeBase::Char) {
+                        rv.r#type = Some(Type::new(TypeBase::Int, -1));
                     }
+                    rv.ct_val = match rv.const_val() {
+                        Some(CtVal::Double(value)) => Some(CtVal::Double(-value)),
+                        Some(value) => match value.as_int().map(i64::checked_neg) {
+                            Some(None) => {
+                                self.error("integer overflow in a constant expression", op_span);
+                                return None;
+                            }
+                            value => value.flatten().map(CtVal::Int),
+                        },
+                        None => None,
+                    };
+                    UnaryOp::Neg
+                } else {
+                    self.check_operand("'!'", rv.r#type.as_ref(), op_span)?;
                     rv.r#type = Some(Type::new(TypeBase::Int, -1));
-                }
-                rv.is_ctval = false;
+                    rv.ct_val = rv.const_val().and_then(CtVal::is_true).map(|value| CtVal::Int(!value as i64));
+                    UnaryOp::Not
+                };
+                rv.is_ctval = rv.ct_val.is_some();
                 rv.is_lval = false;
 
-                return true;
+                let span = op_span.to(operand.span);
+                if let (Some(value), Some(r#type)) = (rv.const_val(), &rv.r#type) {
+                    if let Some(expr) = Parser::literal_expr(value, r#type.clone(), span) {
+                        return Some(expr);
+                    }
+                }
+                Some(Expr::new(ExprKind::Unary { op, operand: Box::new(operand) }, rv.r#type.clone(), span))
             } else {
-                println!("Invalid expression after uanry operator");
+                self.expected("expected an expression after the unary operator");
                 self.current_token_index = start_token;
-                return false;
+                None
             }
         } else {
-            return self.expr_postfix(rv);
+            self.expr_postfix(rv)
         }
     }
 
-    fn expr_postfix(&mut self, rv: &mut RetVal) -> bool {
-        if self.expr_primary(rv) {
-            return self.expr_postfix_tail(rv);
-        } else {
-            return false;
-        }
+    fn expr_postfix(&mut self, rv: &mut RetVal) -> Option<Expr> {
+        let expr = self.expr_primary(rv)?;
+        self.expr_postfix_tail(rv, expr)
     }
 
-    fn expr_postfix_tail(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_postfix_tail(&mut self, rv: &mut RetVal, base: Expr) -> Option<Expr> {
         let start_token = self.current_token_index;
 
         match self.get_token_type() {
             TokenType::LBRACKET => {
                 self.consume();
+                let op_span = self.consumed_span();
 
-                let mut rve = RetVal {
-                    r#type: None,
-                    is_lval: false,
-                    is_ctval: false,
-                    ct_val: None,
-                };
+                let mut rve = RetVal::new();
 
-                if self.expr(&mut rve) {
-                    if rv.r#type.as_ref().map_or(true, |t| t.n_elements < 0) {
-                        println!("Only an array can be indexed");
-                        return false;
+                if let Some(index) = self.expr(&mut rve) {
+                    if rv.r#type.as_ref().is_none_or(|t| t.n_elements < 0) {
+                        self.error("only an array can be indexed", op_span);
+                        return None;
+                    }
+
+                    let type_int = Type::new(TypeBase::Int, -1);
+                    let index = self.convert(index, &type_int, rve.r#type.as_ref().unwrap(), op_span)?;
+
+                    let n_elements = rv.r#type.as_ref().map_or(0, |t| t.n_elements as i64);
+                    if let Some(i) = rve.const_int().filter(|i| n_elements > 0 && (*i < 0 || *i >= n_elements)) {
+                        self.error(format!("the index {} is out of the bounds of an array of {} elements", i, n_elements), index.span);
                     }
-                    
-                    let mut type_int = Type::new(TypeBase::Int, -1);
-                    Parser::cast_var(&mut type_int, rve.r#type.as_ref().unwrap());
 
                     rv.r#type.as_mut().unwrap().n_elements = -1;
                     rv.is_lval = true;
@@ -1275,15 +1685,21 @@ impl Parser {
 
                     if self.get_token_type() == TokenType::RBRACKET {
                         self.consume();
-                        return self.expr_postfix_tail(rv);
+                        let span = base.span.to(self.consumed_span());
+                        let expr = Expr::new(
+                            ExprKind::Index { array: Box::new(base), index: Box::new(index) },
+                            rv.r#type.clone(),
+                            span,
+                        );
+                        self.expr_postfix_tail(rv, expr)
                     } else {
-                        println!("Missing ']' after array index");
+                        self.expected("expected ']' after the array index");
                         self.current_token_index = start_token;
-                        return false;
+                        None
                     }
                 } else {
-                    println!("Invalid expression inside square brackets");
-                    return false;
+                    self.expected("expected an expression inside square brackets");
+                    None
                 }
             }
 
@@ -1294,467 +1710,636 @@ impl Parser {
                     self.consume();
                     let token_name = self.consumed_token.clone().unwrap().literal;
                     let symbol_struct = match &rv.r#type {
-                        Some(t) => t.s.clone(),
-                        None => None,
+                        Some(t) if t.n_elements < 0 => t.s,
+                        _ => None,
                     };
 
                     if let Some(s_struct) = symbol_struct {
-                        if let Some(s_members) = s_struct.members.expect("Couldn't find symbol!").find_symbol(&token_name) {
+                        let s_struct = self.symbols_table.symbol(s_struct);
+                        if let Some(s_members) = s_struct.members.as_ref().expect("Couldn't find symbol!").find_symbol(&token_name) {
                             rv.r#type = Some(s_members.r#type.clone().expect("Symbol doesn't have a type!"));
                             rv.is_lval = true;
                             rv.is_ctval = false;
                         } else {
-                            println!("Struct does not have a member {}", token_name);
-                            return false;
+                            self.error(format!("struct does not have a member {}", token_name), self.consumed_span());
+                            return None;
                         }
+                    } else {
+                        self.error(format!("only a structure has members, cannot select {}", token_name), self.consumed_span());
+                        return None;
                     }
-                    return self.expr_postfix_tail(rv);
+
+                    let span = base.span.to(self.consumed_span());
+                    let expr = Expr::new(
+                        ExprKind::Member { base: Box::new(base), member: token_name },
+                        rv.r#type.clone(),
+                        span,
+                    );
+                    self.expr_postfix_tail(rv, expr)
                 } else {
-                    println!("Missing identifier after '.' operator");
+                    self.expected("expected a member name after '.'");
                     self.current_token_index = start_token;
-                    return false;
+                    None
                 }
             }
 
-            _ => true
+            _ => Some(base),
         }
     }
 
-    fn expr_primary(&mut self, rv: &mut RetVal) -> bool {
+    fn expr_primary(&mut self, rv: &mut RetVal) -> Option<Expr> {
         let start_token = self.current_token_index;
-        let mut arg = self.rv.clone();
 
         match self.get_token_type() {
             TokenType::ID => {
                 self.consume();
                 let token_name = self.consumed_token.clone().unwrap().literal;
-                if let Some(mut symbol) = self.symbols_table.find_symbol(&token_name).cloned() {
-                    // let symbol = symbol.unwrap();
-                    rv.r#type = Some(symbol.r#type.clone().expect("symbol.r#type is None"));
-                    rv.is_ctval = false;
-                    rv.is_lval = true;
-                    
-                    if self.get_token_type() == TokenType::LPAR {
-                        self.consume();
-                        if symbol.class != Class::Func && symbol.class != Class::ExtFunc {
-                            println!("Call to a non-function {}", token_name);
-                            return false;
-                        }
+                let name_span = self.consumed_span();
 
+                let Some(symbol) = self.symbols_table.find_symbol(&token_name).cloned() else {
+                    self.error(format!("undefined symbol: {}", token_name), name_span);
+                    return None;
+                };
+                let Some(symbol_type) = symbol.r#type.clone() else {
+                    self.error(format!("{} is not a value", token_name), name_span);
+                    return None;
+                };
 
-                        let mut crt_def_args = symbol.args.as_mut().unwrap().table.iter_mut();
-                        if self.expr(&mut arg) {
-                            if let Some(crt_arg) = crt_def_args.next() {
-                                if let (Some(crt_arg_type), Some(arg_type)) = (&crt_arg.r#type, &arg.r#type) {
-                                    Parser::cast_var(crt_arg_type, arg_type);
-                                }
-                            } else {
-                                println!("Too many arguments in call");
-                                return false;
+                rv.r#type = Some(symbol_type);
+                rv.is_ctval = false;
+                rv.is_lval = true;
+
+                if self.get_token_type() == TokenType::LPAR {
+                    self.consume();
+                    if symbol.class != Class::Func && symbol.class != Class::ExtFunc {
+                        self.error(format!("call to a non-function {}", token_name), name_span);
+                        return None;
+                    }
+
+                    let mut crt_def_args = symbol.args.as_ref().unwrap().iter();
+                    let mut args = Vec::new();
+                    let arg_span = self.token_span(self.current_token_index);
+                    let mut arg_rv = RetVal::new();
+                    let empty = self.get_token_type() == TokenType::RPAR;
+
+                    let first = self.expr(&mut arg_rv);
+                    // a first argument that failed to parse is not an empty argument list
+                    if first.is_none() && !empty {
+                        self.expected("expected ')' after the call arguments");
+                        self.current_token_index = start_token;
+                        return None;
+                    }
+                    if let Some(mut arg) = first {
+                        if let Some(crt_arg) = crt_def_args.next() {
+                            if let (Some(crt_arg_type), Some(arg_type)) = (&crt_arg.r#type, &arg_rv.r#type) {
+                                arg = self.convert(arg, crt_arg_type, arg_type, arg_span)?;
                             }
+                        } else {
+                            self.error("too many arguments in call", arg_span);
+                            return None;
+                        }
+                        args.push(arg);
 
-                            loop {
-                                if self.get_token_type() == TokenType::COMMA {
-                                    self.consume();
+                        loop {
+                            if self.get_token_type() == TokenType::COMMA {
+                                self.consume();
 
-                                    if !self.expr(&mut arg) {
-                                        println!("Comma should be followed by another expression!");
-                                        self.current_token_index = start_token;
-                                        return false;
-                                    } else if let Some(crt_arg) = crt_def_args.next() {
-                                        if let (Some(crt_arg_type), Some(arg_type)) = (&crt_arg.r#type, &arg.r#type) {
-                                            Parser::cast_var(crt_arg_type, arg_type);
-                                        }
-                                        continue;
-                                    } else {
-                                        println!("Too many arguments in call");
-                                        return false;
+                                let arg_span = self.token_span(self.current_token_index);
+                                let mut arg_rv = RetVal::new();
+
+                                let Some(mut arg) = self.expr(&mut arg_rv) else {
+                                    self.expected("expected an expression after ','");
+                                    self.current_token_index = start_token;
+                                    return None;
+                                };
+
+                                if let Some(crt_arg) = crt_def_args.next() {
+                                    if let (Some(crt_arg_type), Some(arg_type)) = (&crt_arg.r#type, &arg_rv.r#type) {
+                                        arg = self.convert(arg, crt_arg_type, arg_type, arg_span)?;
                                     }
                                 } else {
-                                    break;
+                                    self.error("too many arguments in call", arg_span);
+                                    return None;
                                 }
+                                args.push(arg);
+                            } else {
+                                break;
                             }
                         }
+                    }
 
-                        if self.get_token_type() == TokenType::RPAR {
-                            self.consume();
-                            if crt_def_args.next().is_some() {
-                                println!("Too few arguments in call!");
-                                return false;
-                            }
-
-                            rv.r#type = Some(symbol.r#type.clone().expect("symbol.r#type is None"));
-                            rv.is_ctval = false;
-                            rv.is_lval = false;
-                        } else {
-                            println!("Expected ')' to close the expression!");
-                            self.current_token_index = start_token;
-                            return false;
+                    if self.get_token_type() == TokenType::RPAR {
+                        self.consume();
+                        if crt_def_args.next().is_some() {
+                            self.error("too few arguments in call", self.consumed_span());
+                            return None;
                         }
-                    } else if symbol.class == Class::Func || symbol.class == Class::ExtFunc {
-                        println!("Missing call for function {}", token_name);
-                        return false;
+
+                        rv.r#type = symbol.r#type.clone();
+                        rv.is_ctval = false;
+                        rv.is_lval = false;
+
+                        let span = name_span.to(self.consumed_span());
+                        Some(Expr::new(ExprKind::Call { name: token_name, args }, rv.r#type.clone(), span))
+                    } else {
+                        self.expected("expected ')' after the call arguments");
+                        self.current_token_index = start_token;
+                        None
                     }
+                } else if symbol.class == Class::Func || symbol.class == Class::ExtFunc {
+                    self.error(format!("missing call for function {}", token_name), name_span);
+                    None
                 } else {
-                    println!("Undefined symbol {}", token_name);
-                    return false;
+                    Some(Expr::new(ExprKind::Var(token_name), rv.r#type.clone(), name_span))
                 }
-
-                return true;
             }
 
             TokenType::CT_INT => {
                 self.consume();
+                let token = self.consumed_token.clone().unwrap();
+
+                let value = match token.value {
+                    TokenValue::Int(number) => number,
+                    _ => 0,
+                };
                 rv.r#type = Some(Type::new(TypeBase::Int, -1));
-                if let Some(token) = self.consumed_token.clone() {
-                    match token.literal.parse::<i64>() {
-                        Ok(number) => {
-                            rv.ct_val = Some(CtVal::Int(number));
-                        },
-                        Err(e) => {
-                            println!("Failed to parse number: {}", e);
-                            rv.ct_val = None;
-                        }
-                    }
-                } else {
-                    println!("No token consumed");
-                    rv.r#type = None;
-                }
+                rv.ct_val = Some(CtVal::Int(value));
                 rv.is_ctval = true;
                 rv.is_lval = false;
-
-                return true;
+                Some(Expr::new(ExprKind::Int(value), rv.r#type.clone(), Span::from_token(&token)))
             }
 
             TokenType::CT_REAL => {
                 self.consume();
+                let token = self.consumed_token.clone().unwrap();
 
+                let value = match token.value {
+                    TokenValue::Real(number) => number,
+                    _ => 0.0,
+                };
                 rv.r#type = Some(Type::new(TypeBase::Double, -1));
-                if let Some(token) = self.consumed_token.clone() {
-                    match token.literal.parse::<f64>() {
-                        Ok(number) => {
-                            rv.ct_val = Some(CtVal::Double(number));
-                        },
-                        Err(e) => {
-                            println!("Failed to parse number: {}", e);
-                            rv.ct_val = None;
-                        }
-                    }
-                } else {
-                    println!("No token consumed");
-                    rv.r#type = None;
-                }
+                rv.ct_val = Some(CtVal::Double(value));
                 rv.is_ctval = true;
                 rv.is_lval = false;
-
-                return true;
+                Some(Expr::new(ExprKind::Real(value), rv.r#type.clone(), Span::from_token(&token)))
             }
 
             TokenType::CT_CHAR => {
                 self.consume();
+                let token = self.consumed_token.clone().unwrap();
 
+                let value = match token.value {
+                    TokenValue::Char(char_literal) => char_literal,
+                    _ => '\0',
+                };
                 rv.r#type = Some(Type::new(TypeBase::Char, -1));
-                if let Some(token) = self.consumed_token.clone() {
-                    match token.literal.parse::<char>() {
-                        Ok(char_literal) => {
-                            rv.ct_val = Some(CtVal::Char(char_literal));
-                        },
-                        Err(e) => {
-                            println!("Failed to parse number: {}", e);
-                            rv.ct_val = None;
-                        }
-                    }
-                } else {
-                    println!("No token consumed");
-                    rv.r#type = None;
-                }
+                rv.ct_val = Some(CtVal::Char(value));
                 rv.is_ctval = true;
                 rv.is_lval = false;
-
-                return true;
+                Some(Expr::new(ExprKind::Char(value), rv.r#type.clone(), Span::from_token(&token)))
             }
+
             TokenType::CT_STRING => {
                 self.consume();
-                
+                let token = self.consumed_token.clone().unwrap();
+
+                let value = match &token.value {
+                    TokenValue::Str(string_literal) => string_literal.clone(),
+                    _ => String::new(),
+                };
                 rv.r#type = Some(Type::new(TypeBase::Char, 0));
-                rv.ct_val = Some(CtVal::Str(self.consumed_token.clone().unwrap().literal));
+                rv.ct_val = Some(CtVal::Str(value.clone()));
                 rv.is_ctval = true;
                 rv.is_lval = false;
-                return true;
+                Some(Expr::new(ExprKind::Str(value), rv.r#type.clone(), Span::from_token(&token)))
             }
 
             TokenType::LPAR => {
                 self.consume();
+                let start_span = self.consumed_span();
 
-                if self.expr(rv) {
+                if let Some(expr) = self.expr(rv) {
                     if self.get_token_type() == TokenType::RPAR {
                         self.consume();
-                        return true;
+                        let span = start_span.to(self.consumed_span());
+                        return Some(Expr { span, ..expr });
                     } else {
+                        self.expected("expected ')' to close the expression");
                         self.current_token_index = start_token;
-                        println!("Expected ')' to close the expression!");
                     }
                 }
 
-                return false;
+                None
             }
 
-            _ => false
+            _ => None
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::{self, File}, io::{self, BufRead, BufReader, Write}};
-
-    use crate::{lexer::lexer::Lexer, token::token::TokenType};
+    use crate::lexer::lexer::Lexer;
 
     use super::*;
 
-    #[test]
-    fn test_rules() {
-        let t_lacc = Token {
-            r#type: TokenType::LACC,
-            literal: String::from(""),
-            line: 1,
-            column: 1,
-
-        };
+    fn parse(source: &str) -> Result<Unit, Vec<Diagnostic>> {
+        let tokens: Vec<Token> = Lexer::new(source.to_string()).collect();
 
-        let t_racc = Token {
-            r#type: TokenType::RACC,
-            literal: String::from(""),
-            line: 1,
-            column: 1,
-
-        };
-
-
-        let t_int = Token {
-            r#type: TokenType::INT,
-            literal: String::from("int"),
-            line: 1,
-            column: 1,
-        };
+        Parser::new(tokens).unit()
+    }
 
+    #[test]
+    fn test_rules() {
         let t_id = Token {
             r#type: TokenType::ID,
             literal: String::from("x"),
-            line: 1,
-            column: 1,
+            span: Span::default(),
+            value: TokenValue::None,
         };
 
         let t_id1 = Token {
             r#type: TokenType::ID,
             literal: String::from("y"),
-            line: 1,
-            column: 1,
+            span: Span::default(),
+            value: TokenValue::None,
         };
 
         let t_semicolon = Token {
             r#type: TokenType::SEMICOLON,
             literal: String::from(""),
-            line: 1,
-            column: 1,
-        };
-
-        let t_comma = Token {
-            r#type: TokenType::COMMA,
-            literal: String::from(","),
-            line: 1,
-            column: 1,
-        };
-
-        let t_lbrack = Token {
-            r#type: TokenType::LBRACKET,
-            literal: String::from("["),
-            line: 1,
-            column: 1,
-        };
-
-        let t_rbrack= Token {
-            r#type: TokenType::RBRACKET,
-            literal: String::from("]"),
-            line: 1,
-            column: 1,
+            span: Span::default(),
+            value: TokenValue::None,
         };
 
         let t_struct = Token {
             r#type: TokenType::STRUCT,
             literal: String::from("struct"),
-            line: 1,
-            column: 1,
-        };
-
-        let t_char = Token {
-            r#type: TokenType::CHAR,
-            literal: String::from("char"),
-            line: 1,
-            column: 1,
-        };
-
-        let t_double = Token {
-            r#type: TokenType::DOUBLE,
-            literal: String::from("double"),
-            line: 1,
-            column: 1,
+            span: Span::default(),
+            value: TokenValue::None,
         };
 
         let t_lacc = Token {
             r#type: TokenType::LACC,
             literal: String::from("{"),
-            line: 1,
-            column: 1,
+            span: Span::default(),
+            value: TokenValue::None,
         };
 
         let t_racc = Token {
             r#type: TokenType::RACC,
             literal: String::from("}"),
-            line: 1,
-            column: 1,
+            span: Span::default(),
+            value: TokenValue::None,
         };
 
-        let t_mul = Token {
-            r#type: TokenType::MUL,
-            literal: String::from("*"),
-            line: 1,
-            column: 1,
+        let t_eof = Token {
+            r#type: TokenType::EOF,
+            literal: String::from("eof"),
+            span: Span::default(),
+            value: TokenValue::None,
         };
 
-        let t_lpar = Token {
-            r#type: TokenType::LPAR,
-            literal: String::from("("),
-            line: 1,
-            column: 1,
-        };
+        let tokens = vec![
+            t_struct.clone(),
+            t_id.clone(),
+            t_lacc.clone(),
+            t_racc.clone(),
+            t_semicolon.clone(),
+
+            t_struct.clone(),
+            t_id1.clone(),
+            t_lacc.clone(),
+            t_racc.clone(),
+            t_semicolon.clone(),
+            t_eof.clone(),
+        ];
+        let mut parser = Parser::new(tokens);
+        assert!(parser.unit().is_ok(), "{:?}", parser.current_token());
+    }
 
-        let t_rpar = Token {
-            r#type: TokenType::RPAR,
-            literal: String::from(")"),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_ast() {
+        let unit = parse("\
+struct Pt { int x, y; };
+int v[10];
+int f(int a)
+{
+    if (a < 0) return -a;
+    return a * 2;
+}
+void main()
+{
+    v[0] = f(3) + 1;
+}").unwrap();
+
+        assert_eq!(unit.items.len(), 4);
+        let Item::Struct(pt) = &unit.items[0] else { panic!("expected a struct") };
+        assert_eq!(pt.members.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), ["x", "y"]);
+
+        let Item::Var(v) = &unit.items[1] else { panic!("expected a variable") };
+        assert_eq!(v.r#type.n_elements, 10);
+        assert!(matches!(v.dim, Some(Expr { kind: ExprKind::Int(10), .. })));
+
+        let Item::Func(f) = &unit.items[2] else { panic!("expected a function") };
+        assert_eq!(f.params.len(), 1);
+        let StmtKind::Compound(body) = &f.body.as_ref().unwrap().kind else { panic!("expected a compound statement") };
+        assert!(matches!(body[0].kind, StmtKind::If { else_branch: None, .. }));
+
+        let Item::Func(main) = &unit.items[3] else { panic!("expected a function") };
+        let StmtKind::Compound(body) = &main.body.as_ref().unwrap().kind else { panic!("expected a compound statement") };
+        let StmtKind::Expr(Some(assign)) = &body[0].kind else { panic!("expected an expression") };
+        let ExprKind::Assign { target, value } = &assign.kind else { panic!("expected an assignment") };
+        assert!(matches!(target.kind, ExprKind::Index { .. }));
+        let ExprKind::Binary { op: BinaryOp::Add, lhs, .. } = &value.kind else { panic!("expected an addition") };
+        assert!(matches!(&lhs.kind, ExprKind::Call { name, args } if name == "f" && args.len() == 1));
+        assert_eq!(assign.r#type.as_ref().map(|t| t.type_base.clone()), Some(TypeBase::Int));
+    }
 
-        let t_while = Token {
-            r#type: TokenType::WHILE,
-            literal: String::from("while"),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_errors() {
+        let diagnostics = parse("void main()\n{\n\tint x;\n\tdouble x;\n\ty = 1;\n}").unwrap_err();
+        assert_eq!(diagnostics[0].message, "symbol redefinition: x");
+        assert_eq!(diagnostics[0].span, Some(Span::new(0, 30, 31)));
+        assert_eq!(diagnostics[0].notes[0].span, Some(Span::new(0, 19, 20)));
+    }
 
-        let t_if = Token {
-            r#type: TokenType::IF,
-            literal: String::from("if"),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_dimensions() {
+        let unit = parse("int v[100]; struct Pt{ int x; }; struct Pt points[20/4+5]; char s[-(2-5)*2]; int w['a'-'Z']; void f(int a[]){ v[99] = a[7]; }").unwrap();
+        let sizes: Vec<i32> = unit.items.iter()
+            .filter_map(|item| if let Item::Var(decl) = item { Some(decl.r#type.n_elements) } else { None })
+            .collect();
+        assert_eq!(sizes, [100, 10, 6, 7]);
+        let Item::Func(f) = &unit.items[5] else { panic!("expected a function") };
+        assert_eq!(f.params[0].r#type.n_elements, 0);
+
+        let diagnostics = parse("int n; int a[0]; int b[2-3]; int c[n]; double d[1.5]; int e[5/0];").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, [
+            "the array dimension must be a positive integer constant",
+            "the array dimension must be a positive integer constant",
+            "the array dimension must be a positive integer constant",
+            "the array dimension must be a positive integer constant",
+            "division by zero in a constant expression",
+        ]);
+        assert_eq!(diagnostics[1].span, Some(Span::new(0, 23, 26)));
+
+        let diagnostics = parse("int v[4]; void main(){ v[3] = v[4]; }").unwrap_err();
+        assert_eq!(diagnostics[0].message, "the index 4 is out of the bounds of an array of 4 elements");
+    }
 
-        let t_return = Token {
-            r#type: TokenType::RETURN,
-            literal: String::from("return"),
-            line: 1,
-            column: 1,
+    #[test]
+    fn test_conversions() {
+        let unit = parse("int v[3]; double f(int n, char c){ char k; k = n; v[c] = f(c, 'x'); return n; }").unwrap();
+        let Item::Func(f) = &unit.items[1] else { panic!("expected a function") };
+        let Some(body) = &f.body else { panic!("expected a body") };
+        let StmtKind::Compound(stmts) = &body.kind else { panic!("expected a block") };
+        let conversion = |expr: &Expr| match &expr.kind {
+            ExprKind::Convert { conversion, .. } => *conversion,
+            _ => Conversion::None,
         };
 
-        let t_else = Token {
-            r#type: TokenType::ELSE,
-            literal: String::from("else"),
-            line: 1,
-            column: 1,
-        };
+        let StmtKind::Expr(Some(assign)) = &stmts[1].kind else { panic!("expected an expression") };
+        let ExprKind::Assign { value, .. } = &assign.kind else { panic!("expected an assignment") };
+        assert_eq!(conversion(value), Conversion::IntToChar);
+        assert_eq!(value.r#type.as_ref().map(|t| t.type_base.clone()), Some(TypeBase::Char));
+
+        let StmtKind::Expr(Some(assign)) = &stmts[2].kind else { panic!("expected an expression") };
+        let ExprKind::Assign { target, value } = &assign.kind else { panic!("expected an assignment") };
+        assert_eq!(conversion(value), Conversion::DoubleToInt);
+        let ExprKind::Convert { expr: call, .. } = &value.kind else { panic!("expected a conversion") };
+        let ExprKind::Call { args, .. } = &call.kind else { panic!("expected a call") };
+        assert_eq!(args.iter().map(conversion).collect::<Vec<_>>(), [Conversion::CharToInt, Conversion::None]);
+        let ExprKind::Index { index, .. } = &target.kind else { panic!("expected an index") };
+        assert_eq!(conversion(index), Conversion::CharToInt);
+
+        let StmtKind::Return(Some(value)) = &stmts[3].kind else { panic!("expected a return") };
+        assert_eq!(conversion(value), Conversion::IntToDouble);
+
+        let diagnostics = parse("struct A{ int x; }; struct B{ int x; }; int v[2]; void g(int n){} \
+            void main(){ struct A a; struct B b; int i; a = b; g(v); i = (int)a; }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, [
+            "a structure cannot be converted to another one",
+            "an array cannot be converted to a non-array",
+            "incompatible types",
+        ]);
+
+        let diagnostics = parse("int f(int x){ if (x) return; return 1; } void g(){ return; } void main(){ return 1; }")
+            .unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, ["a non-void function must return a value", "a void function cannot return a value"]);
+        assert_eq!(diagnostics[0].span, Some(Span::new(0, 21, 27)));
+    }
 
-        let t_break = Token {
-            r#type: TokenType::BREAK,
-            literal: String::from("break"),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_arith_types() {
+        let unit = parse("int i; double d; char c; void main(){ i + d; c * c; c < d; i == c; i && d; }").unwrap();
+        let Item::Func(f) = &unit.items[3] else { panic!("expected a function") };
+        let Some(body) = &f.body else { panic!("expected a body") };
+        let StmtKind::Compound(stmts) = &body.kind else { panic!("expected a block") };
+        let typed: Vec<(TypeBase, Conversion, Conversion)> = stmts.iter()
+            .map(|stmt| {
+                let StmtKind::Expr(Some(expr)) = &stmt.kind else { panic!("expected an expression") };
+                let ExprKind::Binary { lhs, rhs, .. } = &expr.kind else { panic!("expected a binary expression") };
+                let conversion = |expr: &Expr| match &expr.kind {
+                    ExprKind::Convert { conversion, .. } => *conversion,
+                    _ => Conversion::None,
+                };
+                (expr.r#type.as_ref().unwrap().type_base.clone(), conversion(lhs), conversion(rhs))
+            })
+            .collect();
+        assert_eq!(typed, [
+            (TypeBase::Double, Conversion::IntToDouble, Conversion::None),
+            (TypeBase::Int, Conversion::CharToInt, Conversion::CharToInt),
+            (TypeBase::Int, Conversion::CharToDouble, Conversion::None),
+            (TypeBase::Int, Conversion::None, Conversion::CharToInt),
+            (TypeBase::Int, Conversion::None, Conversion::None),
+        ]);
+
+        let diagnostics = parse("int v[2]; void g(){} void main(){ g() + 1; v == v; 2 < g(); }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, ["incompatible types", "an array cannot be compared", "incompatible types"]);
+    }
 
-        let t_for = Token {
-            r#type: TokenType::FOR,
-            literal: String::from("for"),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_folding() {
+        let unit = parse("int v[(int)2.5 * 2 + ('b' > 'a')]; char c[-'a' + 100]; int w[!(1 && 0.0) + (3 == 3.0)];").unwrap();
+        let sizes: Vec<i32> = unit.items.iter()
+            .filter_map(|item| if let Item::Var(decl) = item { Some(decl.r#type.n_elements) } else { None })
+            .collect();
+        assert_eq!(sizes, [5, 3, 2]);
+
+        let diagnostics = parse("void main(){ int i; double d; i = 9223372036854775807 + 1; i = 4 * 0 / (2 - 2); \
+            d = 1.5 / 0.0; i = (1 - 9223372036854775807 - 2) / -1; i = -(-9223372036854775807 - 1); i = i / 0; }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, [
+            "integer overflow in a constant expression",
+            "division by zero in a constant expression",
+            "division by zero in a constant expression",
+            "integer overflow in a constant expression",
+            "integer overflow in a constant expression",
+        ]);
+    }
 
-        let t_dot = Token {
-            r#type: TokenType::DOT,
-            literal: String::from("."),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_conditions() {
+        parse("int i; double d; char c; void main(){ if (i) ; while (d) ; for (; c; ) ; }").unwrap();
+
+        let diagnostics = parse("struct S{ int x; }; struct S s; int v[3]; void f(){} \
+            void main(){ if (f()) put_i(1); while (v) ; for (; s; ) ; }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, [
+            "a void value cannot be logically tested",
+            "an array cannot be logically tested",
+            "a structure cannot be logically tested",
+        ]);
+    }
 
-        let t_eof = Token {
-            r#type: TokenType::EOF,
-            literal: String::from("eof"),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_logical_operands() {
+        parse("int i; double d; char c; void main(){ i = i && d || c; }").unwrap();
+
+        let diagnostics = parse("struct S{ int x; }; struct S s; int v[3]; void f(){} \
+            void main(){ int i; i = f() || 1; i = v && 1; i = 1 || s; i = 0 && f(); }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, [
+            "a void value cannot be logically tested",
+            "an array cannot be logically tested",
+            "a structure cannot be logically tested",
+            "a void value cannot be logically tested",
+        ]);
+    }
 
-        let t_ctint = Token {
-            r#type: TokenType::CT_INT,
-            literal: String::from("5"),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_unary_operands() {
+        let diagnostics = parse("int v[3]; void f(){} \
+            void main(){ put_i(!v); put_i(!f()); put_i(-v); put_i(-f()); }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, [
+            "'!' cannot be applied to an array",
+            "'!' cannot be applied to a void value",
+            "unary '-' cannot be applied to an array",
+            "unary '-' cannot be applied to a void value",
+        ]);
+    }
 
-        let t_assign = Token {
-            r#type: TokenType::ASSIGN,
-            literal: String::from("="),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_layout() {
+        let source = "struct S{ char c; int i; char d[3]; double x; };\nstruct S g; char k; extern int e; struct S arr[2];\n\
+            int f(char a, struct S s, int v[]){ char loc; struct S t; { int inner; } return 0; }";
+        let mut parser = Parser::new(Lexer::new(source.to_string()).collect());
+        let unit = parser.unit().unwrap();
+        let symbols = &parser.symbols_table;
+
+        let Item::Struct(decl) = &unit.items[0] else { panic!("expected a struct") };
+        assert_eq!(decl.members.iter().map(|m| m.offset).collect::<Vec<_>>(), [0, 8, 16, 24]);
+        let Item::Var(g) = &unit.items[1] else { panic!("expected a variable") };
+        assert_eq!(symbols.type_size(&g.r#type), 32);
+        assert_eq!(symbols.type_align(&g.r#type), 8);
+
+        let globals: Vec<i64> = unit.items.iter()
+            .filter_map(|item| if let Item::Var(decl) = item { Some(decl.offset) } else { None })
+            .collect();
+        assert_eq!(globals, [0, 32, 40]);
+        assert_eq!(unit.globals_size, 104);
+
+        let Item::Func(f) = &unit.items[5] else { panic!("expected a function") };
+        assert_eq!(f.params.iter().map(|p| p.offset).collect::<Vec<_>>(), [-57, -56, -24]);
+        let layout: Vec<(&str, Option<Memory>, i64)> = symbols.iter()
+            .filter(|symbol| ["k", "a", "v", "loc", "t", "inner"].contains(&symbol.name.as_str()))
+            .map(|symbol| (symbol.name.as_str(), symbol.memory.clone(), symbol.offset))
+            .collect();
+        assert_eq!(layout, [
+            ("k", Some(Memory::Global), 32),
+            ("a", Some(Memory::Arg), -57),
+            ("v", Some(Memory::Arg), -24),
+            ("loc", Some(Memory::Local), 0),
+            ("t", Some(Memory::Local), 8),
+            ("inner", Some(Memory::Local), 40),
+        ]);
+
+        let diagnostics = parse("struct T{ int a[]; }; void main(){ char s[]; }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, ["the array a needs a dimension", "the array s needs a dimension"]);
+    }
 
-        let t_less = Token {
-            r#type: TokenType::LESS,
-            literal: String::from("<"),
-            line: 1,
-            column: 1,
-        };
+    #[test]
+    fn test_struct_types() {
+        let source = "struct A{ int x; }; struct B{ int x; }; struct A a; void main(){ struct A c; c = a; }";
+        let mut parser = Parser::new(Lexer::new(source.to_string()).collect());
+        let unit = parser.unit().unwrap();
+        let Item::Var(a) = &unit.items[2] else { panic!("expected a variable") };
+        assert_eq!(a.r#type.s, parser.symbols_table.lookup("A"));
+        assert_ne!(a.r#type.s, parser.symbols_table.lookup("B"));
+
+        let diagnostics = parse("struct A{ int x; }; struct B{ int x; }; void main(){ struct A a; struct B b; a = b; }").unwrap_err();
+        assert_eq!(diagnostics[0].message, "a structure cannot be converted to another one");
+    }
 
-        let mut tokens: Vec<Token> = Vec::new();
-        tokens.push(t_struct.clone());
-        tokens.push(t_id.clone());
-        tokens.push(t_lacc.clone());
-        tokens.push(t_racc.clone());
-        tokens.push(t_semicolon.clone());
-
-        tokens.push(t_struct.clone());
-        tokens.push(t_id1.clone());
-        tokens.push(t_lacc.clone());
-        tokens.push(t_racc.clone());
-        tokens.push(t_semicolon.clone());
-        tokens.push(t_eof.clone());
-        let mut parser = Parser::new(tokens);
-        assert_eq!(parser.unit(), true, "{:?}", parser.current_token());
-
-        // if let Ok(file) = File::open("./res/9.c") {
-        //     let reader = BufReader::new(file);
-        //     let mut lexer = Lexer::new(String::new());
-        //
-        //     for line in reader.lines() {
-        //         let line = line.unwrap();
-        //         lexer.set_input(line);
-        //
-        //         loop {
-        //             let token = lexer.next_token();
-        //
-        //             if token.r#type == TokenType::EOF {
-        //                 break;
-        //             } else {
-        //                 tokens.push(token.clone());
-        //             }
-        //         }
-        //     }
-        //
-        //     tokens.push(Token {r#type: TokenType::EOF, literal: String::from("EOF"), line: 1, column: 1});
-        //     // println!("{:?}", tokens);
-        //     let mut parser = Parser::new(tokens);
-        //     assert_eq!(parser.unit(), true);
-        //
-        // } else {
-        //     eprintln!("Failed to open the file");
-        // }
+    #[test]
+    fn test_scopes() {
+        let source = "int x; int f(int x){ { double x[2]; { char x; x = 'a'; } x[0] = 1.5; } return x; }\nvoid main(){ x = f(1); }";
+        assert!(parse(source).is_ok());
+
+        let diagnostics = parse("int f(int a, int a){ return a; }").unwrap_err();
+        assert_eq!(diagnostics[0].message, "symbol redefinition: a");
+        assert_eq!(diagnostics[0].notes[0].span, Some(Span::new(0, 10, 11)));
+
+        let diagnostics = parse("int f(int a){ int a; return a; }").unwrap_err();
+        assert_eq!(diagnostics[0].message, "symbol redefinition: a");
+
+        let diagnostics = parse("void f(){ int y; { int y; } { int z; int z; } }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, ["symbol redefinition: z"]);
+
+        let diagnostics = parse("void f(){ { int y; } y = 1; }").unwrap_err();
+        assert_eq!(diagnostics[0].message, "undefined symbol: y");
+    }
+
+    #[test]
+    fn test_recovery() {
+        let source = "int a b;\nint f(int n){ n = n +; if (n) { n = 1 } return n; }\nint g;\nvoid main(){ f(1) g = 2; h = g; }";
+        let diagnostics = parse(source).unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, [
+            "expected '(' after the function name",
+            "expected an expression after '+'",
+            "expected ';' after the expression",
+            "expected ';' after the expression",
+            "undefined symbol: h",
+        ]);
+        assert_eq!(diagnostics[1].span, Some(Span::new(0, 30, 31)));
+        assert_eq!(diagnostics[4].span, Some(Span::new(0, 93, 94)));
+
+        let diagnostics = parse("int f(int a,){ return a; }\nvoid main(){ {").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, ["expected a function argument after ','", "expected '}' to close the compound statement"]);
+
+
+        let diagnostics = parse("void main(){ int i; for (x = 0; i < 3; i = i + 1) i = 2; i = 3; }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, ["undefined symbol: x"]);
+
+        let diagnostics = parse("void main(){ int x; int y; y = ; if (x) { y = ; } put_i(y); }").unwrap_err();
+        let messages: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, ["expected an expression after '='", "expected an expression after '='"]);
+        assert_eq!(diagnostics[0].span, Some(Span::new(0, 31, 32)));
+    }
+
+    #[test]
+    fn test_prototypes() {
+        let unit = parse("extern int n; extern int v[]; int f(int a); int n; void main(){ n = f(v[0]); } int f(int a){ return a; }").unwrap();
+        assert!(matches!(&unit.items[0], Item::Extern(decl) if decl.name == "n"));
+        assert!(matches!(&unit.items[2], Item::Func(decl) if decl.name == "f" && decl.body.is_none()));
+        assert!(matches!(&unit.items[5], Item::Func(decl) if decl.name == "f" && decl.body.is_some()));
+
+        let diagnostics = parse("int f(int a);\nchar f(int a){ return a; }").unwrap_err();
+        assert_eq!(diagnostics[0].message, "conflicting types for f");
+        assert_eq!(diagnostics[0].notes[0].span, Some(Span::new(0, 4, 5)));
+
+        let diagnostics = parse("extern int n;\ndouble n;").unwrap_err();
+        assert_eq!(diagnostics[0].message, "conflicting types for n");
     }
 }
@@ -0,0 +1,378 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::diagnostics::diagnostics::{Diagnostic, SourceFile, SourceMap, Span};
+use crate::lexer::lexer::Lexer;
+use crate::token::token::{Token, TokenType, TokenValue};
+
+/// How deep `#include` may nest before it is treated as a cycle.
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+/// One open `#ifdef`/`#ifndef` block.
+struct Conditional {
+    /// Whether the lines around the block are compiled.
+    parent_active: bool,
+    /// Whether the current branch of the block is compiled.
+    active: bool,
+    seen_else: bool,
+    span: Span,
+}
+
+/// Runs the supported subset of the C preprocessor ahead of the [`Lexer`]:
+/// `#include "file"`, object-like `#define NAME value` and `#ifdef`/`#ifndef`/`#else`/`#endif`.
+///
+/// Every file is lexed with its own id in the [`SourceMap`], so the spans of the
+/// tokens, including those of included files and macro values, point into the file
+/// they were written in.
+pub struct Preprocessor<'a> {
+    sources: &'a mut SourceMap,
+    read: &'a dyn Fn(&Path) -> io::Result<String>,
+    macros: HashMap<String, Vec<Token>>,
+    include_stack: Vec<PathBuf>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Preprocessor<'a> {
+    /// Creates a preprocessor which loads included files with `read`.
+    pub fn new(sources: &'a mut SourceMap, read: &'a dyn Fn(&Path) -> io::Result<String>) -> Preprocessor<'a> {
+        Preprocessor {
+            sources,
+            read,
+            macros: HashMap::new(),
+            include_stack: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Returns the tokens of the file `file_id` with every directive applied, ending with `EOF`.
+    pub fn run(&mut self, file_id: usize) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let path = PathBuf::from(&self.file(file_id).name);
+
+        self.include_stack.push(path);
+        self.file_tokens(file_id, &mut tokens);
+        self.include_stack.pop();
+
+        let end = self.file(file_id).text.len();
+        tokens.push(Token {
+            r#type: TokenType::EOF,
+            literal: String::from("EOF"),
+            span: Span::new(file_id, end, end),
+            value: TokenValue::None,
+        });
+        tokens
+    }
+
+    fn file(&self, file_id: usize) -> &SourceFile {
+        self.sources.file(file_id).expect("the file was added to the source map")
+    }
+
+    fn error(&mut self, message: impl Into<String>, span: Span) {
+        self.diagnostics.push(Diagnostic::error(message, span));
+    }
+
+    /// Appends the tokens of one file, without its `EOF`.
+    fn file_tokens(&mut self, file_id: usize, tokens: &mut Vec<Token>) {
+        let text = strip_comments(&self.file(file_id).text);
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut chunk_start = 0;
+        let mut line_start = 0;
+
+        while line_start < text.len() {
+            let line_end = text[line_start..].find('\n').map_or(text.len(), |i| line_start + i + 1);
+            let line = &text[line_start..line_end];
+            let active = conditionals.last().is_none_or(|c| c.active);
+            let trimmed = line.trim_start();
+
+            if trimmed.starts_with('#') || !active {
+                self.lex_chunk(file_id, &text[chunk_start..line_start], chunk_start, tokens);
+                chunk_start = line_end;
+            }
+
+            if let Some(directive) = trimmed.strip_prefix('#') {
+                let offset = line_start + (line.len() - trimmed.len());
+                let span = Span::new(file_id, offset, offset + trimmed.trim_end().len());
+                self.directive(directive, offset + 1, span, active, &mut conditionals, tokens);
+            }
+
+            line_start = line_end;
+        }
+        self.lex_chunk(file_id, &text[chunk_start..], chunk_start, tokens);
+
+        for conditional in conditionals {
+            self.error("unterminated conditional directive", conditional.span);
+        }
+    }
+
+    /// Handles the directive `text`, which starts at byte `offset` of its file.
+    fn directive(
+        &mut self,
+        text: &str,
+        offset: usize,
+        span: Span,
+        active: bool,
+        conditionals: &mut Vec<Conditional>,
+        tokens: &mut Vec<Token>,
+    ) {
+        let text = text.trim_end();
+        let name_start = text.len() - text.trim_start().len();
+        let name_end = text[name_start..]
+            .find(|ch: char| !ch.is_ascii_alphanumeric() && ch != '_')
+            .map_or(text.len(), |i| name_start + i);
+        let name = &text[name_start..name_end];
+        let rest = text[name_end..].trim_start();
+        let rest_offset = offset + text.len() - rest.len();
+
+        match name {
+            "ifdef" | "ifndef" => {
+                let defined = self.macro_name(rest, span).is_some_and(|name| self.macros.contains_key(name));
+                conditionals.push(Conditional {
+                    parent_active: active,
+                    active: active && defined == (name == "ifdef"),
+                    seen_else: false,
+                    span,
+                });
+            }
+            "else" => match conditionals.last_mut() {
+                Some(conditional) if !conditional.seen_else => {
+                    conditional.seen_else = true;
+                    conditional.active = conditional.parent_active && !conditional.active;
+                }
+                Some(_) => self.error("#else after #else", span),
+                None => self.error("#else without #ifdef or #ifndef", span),
+            },
+            "endif" => {
+                if conditionals.pop().is_none() {
+                    self.error("#endif without #ifdef or #ifndef", span);
+                }
+            }
+            _ if !active => {}
+            "define" => {
+                let Some(macro_name) = self.macro_name_prefix(rest, span) else {
+                    return;
+                };
+                let value = &rest[macro_name.len()..];
+                let value_offset = rest_offset + macro_name.len();
+                let mut value_tokens = Vec::new();
+                lex(span.file_id, value, value_offset, &mut value_tokens);
+                self.macros.insert(macro_name.to_string(), value_tokens);
+            }
+            "include" => self.include(rest, span, tokens),
+            "" => self.error("expected a directive name after '#'", span),
+            _ => self.error(format!("unknown directive #{}", name), span),
+        }
+    }
+
+    /// Returns `text` if it is a single identifier, reporting an error otherwise.
+    fn macro_name<'t>(&mut self, text: &'t str, span: Span) -> Option<&'t str> {
+        let name = self.macro_name_prefix(text, span)?;
+        if name.len() != text.len() {
+            self.error(format!("unexpected text after the macro name {}", name), span);
+        }
+        Some(name)
+    }
+
+    /// Returns the identifier `text` starts with, reporting an error if there is none.
+    fn macro_name_prefix<'t>(&mut self, text: &'t str, span: Span) -> Option<&'t str> {
+        let end = text
+            .find(|ch: char| !ch.is_ascii_alphanumeric() && ch != '_')
+            .unwrap_or(text.len());
+        let name = &text[..end];
+
+        if name.is_empty() || name.starts_with(|ch: char| ch.is_ascii_digit()) {
+            self.error("expected a macro name", span);
+            return None;
+        }
+        Some(name)
+    }
+
+    fn include(&mut self, text: &str, span: Span, tokens: &mut Vec<Token>) {
+        let Some(name) = text.strip_prefix('"').and_then(|text| text.strip_suffix('"')) else {
+            self.error("expected #include \"file\"", span);
+            return;
+        };
+
+        // included files are looked up next to the file which includes them
+        let parent = self.include_stack.last().and_then(|path| path.parent()).unwrap_or(Path::new(""));
+        let path = parent.join(name);
+
+        if self.include_stack.len() >= MAX_INCLUDE_DEPTH || self.include_stack.contains(&path) {
+            self.error(format!("#include of '{}' nests too deeply", name), span);
+            return;
+        }
+
+        let text = match (self.read)(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                self.error(format!("cannot read '{}': {}", path.display(), e), span);
+                return;
+            }
+        };
+
+        let file_id = self.sources.add(path.to_string_lossy(), text);
+        self.include_stack.push(path);
+        self.file_tokens(file_id, tokens);
+        self.include_stack.pop();
+    }
+
+    /// Lexes one run of compiled lines and expands the macros used in it.
+    fn lex_chunk(&mut self, file_id: usize, text: &str, offset: usize, tokens: &mut Vec<Token>) {
+        if text.trim().is_empty() {
+            return;
+        }
+
+        let mut chunk = Vec::new();
+        lex(file_id, text, offset, &mut chunk);
+        for token in chunk {
+            self.expand(token, &mut HashSet::new(), tokens);
+        }
+    }
+
+    fn expand(&self, token: Token, expanding: &mut HashSet<String>, tokens: &mut Vec<Token>) {
+        let value = match self.macros.get(&token.literal) {
+            Some(value) if token.r#type == TokenType::ID && !expanding.contains(&token.literal) => value,
+            _ => {
+                tokens.push(token);
+                return;
+            }
+        };
+
+        expanding.insert(token.literal.clone());
+        for value_token in value {
+            self.expand(value_token.clone(), expanding, tokens);
+        }
+        expanding.remove(&token.literal);
+    }
+}
+
+/// Appends the tokens of `text`, which starts at byte `offset` of the file `file_id`.
+fn lex(file_id: usize, text: &str, offset: usize, tokens: &mut Vec<Token>) {
+    for mut token in Lexer::new(text.to_string()).with_file_id(file_id) {
+        if token.r#type == TokenType::EOF {
+            break;
+        }
+        token.span.start += offset;
+        token.span.end += offset;
+        tokens.push(token);
+    }
+}
+
+/// Replaces every comment by spaces, keeping the line breaks and the byte offsets of
+/// the rest of the text, so that directives inside comments are ignored. An
+/// unterminated block comment is kept for the lexer to report.
+fn strip_comments(text: &str) -> String {
+    let mut bytes = text.as_bytes().to_vec();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote && bytes[i] != b'\n' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    bytes[i] = b' ';
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let Some(end) = text[i + 2..].find("*/").map(|end| i + 2 + end + 2) else {
+                    break;
+                };
+                for byte in &mut bytes[i..end] {
+                    if *byte != b'\n' {
+                        *byte = b' ';
+                    }
+                }
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+
+    // only whole comments were blanked, so the text is still valid UTF-8
+    String::from_utf8(bytes).expect("comments are replaced by whole characters")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The file, literal, line and column of a token.
+    type Located = (String, String, usize, usize);
+
+    fn preprocess(files: &[(&str, &str)]) -> (Vec<Located>, Vec<String>) {
+        let read = |path: &Path| -> io::Result<String> {
+            files
+                .iter()
+                .find(|(name, _)| Path::new(name) == path)
+                .map(|(_, text)| text.to_string())
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))
+        };
+
+        let mut sources = SourceMap::new();
+        let file_id = sources.add(files[0].0, files[0].1);
+        let mut preprocessor = Preprocessor::new(&mut sources, &read);
+        let tokens = preprocessor.run(file_id);
+        let diagnostics: Vec<String> = preprocessor.diagnostics().iter().map(|d| d.message.clone()).collect();
+
+        let tokens = tokens
+            .iter()
+            .map(|token| {
+                let file = sources.file(token.span.file_id).unwrap();
+                let (line, column) = file.line_column(token.span.start);
+                (file.name.clone(), token.literal.clone(), line, column)
+            })
+            .collect();
+        (tokens, diagnostics)
+    }
+
+    fn literals(tokens: &[Located]) -> Vec<&str> {
+        tokens.iter().map(|(_, literal, ..)| literal.as_str()).collect()
+    }
+
+    #[test]
+    fn test_directives() {
+        let (tokens, diagnostics) = preprocess(&[
+            ("src/main.c", "#include \"defs.h\"\n#define SIZE  N\nint a[SIZE];\n#ifdef DEBUG\nint b;\n#else\nint c;\n#endif\n/*\n#define X\n*/ X"),
+            ("src/defs.h", "#ifndef DEFS\n  #define DEFS\n  #define N 10 // ten\nstruct P { int x; };\n#endif\n"),
+        ]);
+
+        assert_eq!(diagnostics, Vec::<String>::new());
+        assert_eq!(
+            literals(&tokens),
+            ["struct", "P", "{", "int", "x", ";", "}", ";", "int", "a", "[", "10", "]", ";", "int", "c", ";", "X", "EOF"]
+        );
+        assert_eq!(tokens[1], (String::from("src/defs.h"), String::from("P"), 4, 8));
+        assert_eq!(tokens[11], (String::from("src/defs.h"), String::from("10"), 3, 13));
+        assert_eq!(tokens[15], (String::from("src/main.c"), String::from("c"), 7, 5));
+        assert_eq!(tokens[17], (String::from("src/main.c"), String::from("X"), 11, 4));
+    }
+
+    #[test]
+    fn test_errors() {
+        let (tokens, diagnostics) = preprocess(&[
+            ("a.c", "#include \"a.c\"\n#include \"missing.h\"\n#pragma once\n#define 1\n#endif\n#ifdef A\n#else\n#else\nint x;"),
+        ]);
+
+        assert_eq!(literals(&tokens), ["int", "x", ";", "EOF"]);
+        assert_eq!(diagnostics, [
+            "#include of 'a.c' nests too deeply",
+            "cannot read 'missing.h': entity not found",
+            "unknown directive #pragma",
+            "expected a macro name",
+            "#endif without #ifdef or #ifndef",
+            "#else after #else",
+            "unterminated conditional directive",
+        ]);
+    }
+}
@@ -1,3 +1,20 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::diagnostics::diagnostics::Span;
+
+/// Size of an address, which is how an array argument is passed.
+pub const PTR_SIZE: i64 = 8;
+
+/// Rounds `offset` up to a multiple of `align`.
+pub fn align_up(offset: i64, align: i64) -> i64 {
+    (offset + align - 1) / align * align
+}
+
+/// A handle to a [`Symbol`] of a [`SymbolTable`], valid for as long as the table lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SymbolId(usize);
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum TypeBase {
     Int,
@@ -10,19 +27,60 @@ pub enum TypeBase {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Type {
     pub type_base: TypeBase,
-    pub s: Option<Box<Symbol>>,
+    /// The structure of a [`TypeBase::Struct`], compared by identity.
+    pub s: Option<SymbolId>,
     pub n_elements: i32,
 }
 
 impl Type {
     pub fn new(type_base: TypeBase, n_elements: i32) -> Type {
-        let mut created_type = Type {
+        Type {
            type_base,
            s: None,
            n_elements,
-        };
+        }
+    }
 
-        created_type
+    /// Whether two declarations of the same variable or function agree: both have the same
+    /// base type and struct, and both are arrays or both are not.
+    pub fn matches(&self, other: &Type) -> bool {
+        self.type_base == other.type_base
+            && (self.n_elements >= 0) == (other.n_elements >= 0)
+            && self.s == other.s
+    }
+}
+
+/// The implicit conversion a value needs to be used where another type is expected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conversion {
+    None,
+    CharToInt,
+    CharToDouble,
+    IntToChar,
+    IntToDouble,
+    DoubleToInt,
+    DoubleToChar,
+}
+
+/// Why a value cannot be used where another type is expected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypeError {
+    ArrayElements,
+    ArrayToNonArray,
+    NonArrayToArray,
+    Incompatible,
+    OtherStruct,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeError::ArrayElements => write!(f, "an array cannot be converted to an array of another type"),
+            TypeError::ArrayToNonArray => write!(f, "an array cannot be converted to a non-array"),
+            TypeError::NonArrayToArray => write!(f, "a non-array cannot be converted to an array"),
+            TypeError::Incompatible => write!(f, "incompatible types"),
+            TypeError::OtherStruct => write!(f, "a structure cannot be converted to another one"),
+        }
     }
 }
 
@@ -49,7 +107,42 @@ pub enum CtVal {
     Str(String),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl CtVal {
+    /// The value of an integer constant, characters counting as their code.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            CtVal::Int(value) => Some(*value),
+            CtVal::Char(value) => Some(*value as i64),
+            CtVal::Double(_) | CtVal::Str(_) => None,
+        }
+    }
+
+    /// Whether the constant counts as true in a condition; strings are not tested.
+    pub fn is_true(&self) -> Option<bool> {
+        match self {
+            CtVal::Int(value) => Some(*value != 0),
+            CtVal::Double(value) => Some(*value != 0.0),
+            CtVal::Char(value) => Some(*value != '\0'),
+            CtVal::Str(_) => None,
+        }
+    }
+
+    /// The constant converted to `type_base` the way the VM cast instructions do it.
+    pub fn cast(&self, type_base: &TypeBase) -> Option<CtVal> {
+        match (self, type_base) {
+            (CtVal::Int(_), TypeBase::Int) | (CtVal::Double(_), TypeBase::Double) | (CtVal::Char(_), TypeBase::Char) => Some(self.clone()),
+            (CtVal::Int(value), TypeBase::Double) => Some(CtVal::Double(*value as f64)),
+            (CtVal::Int(value), TypeBase::Char) => Some(CtVal::Char(*value as u8 as char)),
+            (CtVal::Double(value), TypeBase::Int) => Some(CtVal::Int(*value as i64)),
+            (CtVal::Double(value), TypeBase::Char) => Some(CtVal::Char(*value as i64 as u8 as char)),
+            (CtVal::Char(value), TypeBase::Int) => Some(CtVal::Int(*value as i64)),
+            (CtVal::Char(value), TypeBase::Double) => Some(CtVal::Double(*value as u32 as f64)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct RetVal {
     pub r#type: Option<Type>,
     pub is_lval: bool,
@@ -57,6 +150,27 @@ pub struct RetVal {
     pub ct_val: Option<CtVal>,
 }
 
+impl RetVal {
+    pub fn new() -> RetVal {
+        RetVal {
+            r#type: None,
+            is_lval: false,
+            is_ctval: false,
+            ct_val: None,
+        }
+    }
+
+    /// The value of the expression if it is a constant.
+    pub fn const_val(&self) -> Option<&CtVal> {
+        self.ct_val.as_ref().filter(|_| self.is_ctval)
+    }
+
+    /// The value of the expression if it is an integer constant.
+    pub fn const_int(&self) -> Option<i64> {
+        self.const_val().and_then(CtVal::as_int)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Symbol {
     pub name: String,
@@ -66,6 +180,12 @@ pub struct Symbol {
     pub depth: i32,
     pub args: Option<SymbolTable>,
     pub members: Option<SymbolTable>,
+    pub span: Option<Span>,
+    /// A function prototype or an `extern` variable, defined in another unit or later on.
+    pub external: bool,
+    /// Where a variable lives: from the start of its struct for a member, from the frame
+    /// pointer for a local or an argument, and from the start of the globals of its unit.
+    pub offset: i64,
 }
 
 
@@ -78,7 +198,7 @@ impl Symbol {
                args: Option<SymbolTable>, 
                members: Option<SymbolTable>
                ) -> Symbol {
-        let mut symbol = Symbol {
+        Symbol {
             name,
             class,
             memory,
@@ -86,47 +206,192 @@ impl Symbol {
             depth,
             args,
             members,
-        };
-
-        symbol
+            span: None,
+            external: false,
+            offset: 0,
+        }
     }
 }
 
+/// The symbols of a program, stored in an arena and reached through [`SymbolId`]s.
+/// Each open scope maps names to the symbols declared in it; nested scopes are opened
+/// with [`SymbolTable::enter_scope`] and closed by [`SymbolTable::exit_scope`], which
+/// hides their symbols without invalidating their ids.
 #[derive(Debug, Clone, PartialEq)]
 pub struct SymbolTable {
-    pub table: Vec<Symbol>,
+    symbols: Vec<Symbol>,
+    /// The global scope first, the innermost one last.
+    scopes: Vec<HashMap<String, SymbolId>>,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SymbolTable {
     pub fn new() -> SymbolTable {
-        let mut symbol_table = SymbolTable {
-            table: Vec::new(),
-        };
+        SymbolTable {
+            symbols: Vec::new(),
+            scopes: vec![HashMap::new()],
+        }
+    }
 
-        symbol_table
+    /// The number of open scopes: 0 for the global one.
+    pub fn depth(&self) -> i32 {
+        self.scopes.len() as i32 - 1
     }
 
+    pub fn enter_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Hides every symbol of the innermost scope. The global scope is never closed.
+    pub fn exit_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    /// Declares `symbol` in the innermost scope, shadowing any symbol of the same name.
     pub fn add_symbol(&mut self, symbol: Symbol) -> &mut Symbol {
-        self.table.push(symbol);
+        let id = SymbolId(self.symbols.len());
+        self.scopes.last_mut().unwrap().insert(symbol.name.clone(), id);
+        self.symbols.push(symbol);
 
-        self.table.last_mut().unwrap()
+        self.symbols.last_mut().unwrap()
     }
 
-    pub fn find_symbol(&mut self, name: &str) -> Option<&Symbol> {
-        self.table.iter().rev().find(|symbol| symbol.name == name)
+    pub fn symbol(&self, id: SymbolId) -> &Symbol {
+        &self.symbols[id.0]
+    }
+
+    pub fn symbol_mut(&mut self, id: SymbolId) -> &mut Symbol {
+        &mut self.symbols[id.0]
+    }
+
+    /// Every symbol ever declared, in declaration order, whether still in scope or not.
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> + Clone {
+        self.symbols.iter()
+    }
+
+    /// Finds the innermost symbol named `name`, which shadows those of the outer scopes.
+    pub fn lookup(&self, name: &str) -> Option<SymbolId> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    /// Finds a symbol named `name` declared in the innermost scope only.
+    pub fn lookup_in_scope(&self, name: &str) -> Option<SymbolId> {
+        self.scopes.last().and_then(|scope| scope.get(name).copied())
+    }
+
+    pub fn find_symbol(&self, name: &str) -> Option<&Symbol> {
+        self.lookup(name).map(|id| self.symbol(id))
     }
 
     pub fn find_symbol_mut(&mut self, name: &str) -> Option<&mut Symbol> {
-        self.table.iter_mut().rev().find(|symbol| symbol.name == name)
+        self.lookup(name).map(|id| self.symbol_mut(id))
     }
 
-    pub fn find_symbol_index(&mut self, name: &str) -> Option<usize> {
-        self.table.iter().position(|symbol| symbol.name == name)
+    pub fn find_in_scope(&self, name: &str) -> Option<&Symbol> {
+        self.lookup_in_scope(name).map(|id| self.symbol(id))
     }
 
-    pub fn delete_symbol_after(&mut self, target_symbol: &Symbol) {
-        if let Some(index) = self.find_symbol_index(&target_symbol.name) {
-            self.table.truncate(index + 1);
+    pub fn find_in_scope_mut(&mut self, name: &str) -> Option<&mut Symbol> {
+        self.lookup_in_scope(name).map(|id| self.symbol_mut(id))
+    }
+
+    /// The size in bytes of a value of `r#type`. An array without a dimension has none.
+    pub fn type_size(&self, r#type: &Type) -> i64 {
+        let size = match r#type.type_base {
+            TypeBase::Int | TypeBase::Double => 8,
+            TypeBase::Char => 1,
+            TypeBase::Struct => r#type.s.map_or(0, |s| self.struct_size(s)),
+            TypeBase::Void => 0,
+        };
+        if r#type.n_elements >= 0 { size * r#type.n_elements as i64 } else { size }
+    }
+
+    /// The alignment of `r#type`: that of its elements for an array, and the largest
+    /// one of its members for a struct.
+    pub fn type_align(&self, r#type: &Type) -> i64 {
+        match r#type.type_base {
+            TypeBase::Int | TypeBase::Double => 8,
+            TypeBase::Char | TypeBase::Void => 1,
+            TypeBase::Struct => r#type.s
+                .and_then(|s| self.symbol(s).members.as_ref())
+                .and_then(|members| members.iter().filter_map(|member| member.r#type.as_ref()).map(|t| self.type_align(t)).max())
+                .unwrap_or(1),
         }
     }
+
+    /// The size of the stack slot of an argument of `r#type`.
+    pub fn arg_size(&self, r#type: &Type) -> i64 {
+        if r#type.n_elements >= 0 { PTR_SIZE } else { self.type_size(r#type) }
+    }
+
+    /// Places a value of `r#type` after the `end` bytes already used by a struct, a frame
+    /// or the globals, returning its offset and moving `end` past it.
+    pub fn place(&self, end: &mut i64, r#type: &Type) -> i64 {
+        let offset = align_up(*end, self.type_align(r#type));
+        *end = offset + self.type_size(r#type);
+        offset
+    }
+
+    fn struct_size(&self, id: SymbolId) -> i64 {
+        let Some(members) = &self.symbol(id).members else {
+            return 0;
+        };
+        let end = members.iter()
+            .filter_map(|member| Some(member.offset + self.type_size(member.r#type.as_ref()?)))
+            .max()
+            .unwrap_or(0);
+        let mut r#type = Type::new(TypeBase::Struct, -1);
+        r#type.s = Some(id);
+        align_up(end, self.type_align(&r#type))
+    }
+
+    /// Hides the innermost symbol named `name`.
+    pub fn remove_symbol(&mut self, name: &str) -> Option<SymbolId> {
+        self.scopes.iter_mut().rev().find_map(|scope| scope.remove(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str, depth: i32) -> Symbol {
+        Symbol::new(name.to_string(), Class::Var, Some(Memory::Local), Some(Type::new(TypeBase::Int, -1)), depth, None, None)
+    }
+
+    #[test]
+    fn test_scopes() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(var("x", 0));
+        symbols.add_symbol(var("y", 0));
+
+        symbols.enter_scope();
+        symbols.add_symbol(var("x", 1));
+        assert_eq!(symbols.depth(), 1);
+        assert_eq!(symbols.find_symbol("x").map(|symbol| symbol.depth), Some(1));
+        assert!(symbols.find_in_scope("y").is_none());
+        assert_eq!(symbols.find_symbol("y").map(|symbol| symbol.depth), Some(0));
+
+        symbols.enter_scope();
+        symbols.add_symbol(var("y", 2));
+        symbols.exit_scope();
+        assert_eq!(symbols.find_symbol("y").map(|symbol| symbol.depth), Some(0));
+        assert_eq!(symbols.find_symbol("x").map(|symbol| symbol.depth), Some(1));
+
+        let inner = symbols.lookup("x").unwrap();
+        symbols.exit_scope();
+        symbols.exit_scope();
+        assert_eq!(symbols.depth(), 0);
+        assert_eq!(symbols.find_in_scope("x").map(|symbol| symbol.depth), Some(0));
+        assert_ne!(symbols.lookup("x"), Some(inner));
+        assert_eq!(symbols.symbol(inner).depth, 1);
+        assert_eq!(symbols.iter().count(), 4);
+    }
 }
@@ -1,4 +1,9 @@
+use std::fmt;
+
+use crate::diagnostics::diagnostics::Span;
+
 #[derive(Debug, PartialEq, Clone)]
+#[allow(non_camel_case_types)]
 pub enum TokenType {
     //identifiers
     ID,
@@ -7,6 +12,7 @@ pub enum TokenType {
     CHAR,
     DOUBLE,
     ELSE,
+    EXTERN,
     FOR,
     IF,
     INT,
@@ -44,17 +50,112 @@ pub enum TokenType {
     LESSEQ,
     GREATER,
     GREATEREQ,
-    ILLEGAL,
+    ILLEGAL(IllegalReason),
     EOF,
 }
 
+impl TokenType {
+    /// The name of the kind of token, as written in token dumps.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenType::ID => "ID",
+            TokenType::BREAK => "BREAK",
+            TokenType::CHAR => "CHAR",
+            TokenType::DOUBLE => "DOUBLE",
+            TokenType::ELSE => "ELSE",
+            TokenType::EXTERN => "EXTERN",
+            TokenType::FOR => "FOR",
+            TokenType::IF => "IF",
+            TokenType::INT => "INT",
+            TokenType::RETURN => "RETURN",
+            TokenType::STRUCT => "STRUCT",
+            TokenType::VOID => "VOID",
+            TokenType::WHILE => "WHILE",
+            TokenType::CT_INT => "CT_INT",
+            TokenType::CT_REAL => "CT_REAL",
+            TokenType::CT_CHAR => "CT_CHAR",
+            TokenType::CT_STRING => "CT_STRING",
+            TokenType::COMMA => "COMMA",
+            TokenType::SEMICOLON => "SEMICOLON",
+            TokenType::LPAR => "LPAR",
+            TokenType::RPAR => "RPAR",
+            TokenType::LBRACKET => "LBRACKET",
+            TokenType::RBRACKET => "RBRACKET",
+            TokenType::LACC => "LACC",
+            TokenType::RACC => "RACC",
+            TokenType::ADD => "ADD",
+            TokenType::SUB => "SUB",
+            TokenType::MUL => "MUL",
+            TokenType::DIV => "DIV",
+            TokenType::DOT => "DOT",
+            TokenType::AND => "AND",
+            TokenType::OR => "OR",
+            TokenType::NOT => "NOT",
+            TokenType::ASSIGN => "ASSIGN",
+            TokenType::EQUAL => "EQUAL",
+            TokenType::NOTEQ => "NOTEQ",
+            TokenType::LESS => "LESS",
+            TokenType::LESSEQ => "LESSEQ",
+            TokenType::GREATER => "GREATER",
+            TokenType::GREATEREQ => "GREATEREQ",
+            TokenType::ILLEGAL(_) => "ILLEGAL",
+            TokenType::EOF => "EOF",
+        }
+    }
+}
+
+/// Why the lexer could not turn some text into a valid token.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IllegalReason {
+    UnexpectedCharacter(char),
+    UnterminatedString,
+    UnterminatedChar,
+    UnterminatedComment,
+    /// A character literal holding no or more than one character.
+    InvalidChar,
+    BadEscape(char),
+    MalformedReal,
+    MalformedHex,
+    /// An octal number holding an 8 or a 9.
+    MalformedOctal,
+    IntegerOutOfRange,
+    RealOutOfRange,
+}
+
+impl fmt::Display for IllegalReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IllegalReason::UnexpectedCharacter(ch) => write!(f, "unexpected character '{}'", ch.escape_default()),
+            IllegalReason::UnterminatedString => write!(f, "unterminated string literal"),
+            IllegalReason::UnterminatedChar => write!(f, "unterminated character literal"),
+            IllegalReason::UnterminatedComment => write!(f, "unterminated block comment"),
+            IllegalReason::InvalidChar => write!(f, "a character literal must hold exactly one character"),
+            IllegalReason::BadEscape(ch) => write!(f, "unknown escape sequence '\\{}'", ch.escape_default()),
+            IllegalReason::MalformedReal => write!(f, "malformed real number"),
+            IllegalReason::MalformedHex => write!(f, "hexadecimal number without digits"),
+            IllegalReason::MalformedOctal => write!(f, "invalid digit in octal number"),
+            IllegalReason::IntegerOutOfRange => write!(f, "integer constant does not fit in an int"),
+            IllegalReason::RealOutOfRange => write!(f, "real constant does not fit in a double"),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub r#type: TokenType,
     pub literal: String,
-    pub line: i32,
-    pub column: i32,
+    pub span: Span,
+    pub value: TokenValue,
+}
 
+/// The decoded value of a constant token; the literal keeps its source text.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TokenValue {
+    None,
+    Int(i64),
+    Real(f64),
+    Char(char),
+    Str(String),
 }
 
 pub fn lookup_identifier(identifier: &str) -> TokenType {
@@ -63,6 +164,7 @@ pub fn lookup_identifier(identifier: &str) -> TokenType {
         "char" => TokenType::CHAR,
         "double" => TokenType::DOUBLE,
         "else" => TokenType::ELSE,
+        "extern" => TokenType::EXTERN,
         "for" => TokenType::FOR,
         "if" => TokenType::IF,
         "int" => TokenType::INT,
@@ -1 +1,3 @@
+pub mod natives;
+pub mod trace;
 pub mod vm;
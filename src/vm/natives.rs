@@ -0,0 +1,162 @@
+use super::trace::ValueType;
+use super::vm::{Io, NativeFn, Vm, VmError};
+
+/// The external functions declared by the parser, registered in every [`Vm`], with
+/// the types of the arguments they pop.
+pub const BUILTINS: [(&str, NativeFn, &[ValueType]); 8] = [
+    ("put_s", put_s, &[ValueType::Addr]),
+    ("get_s", get_s, &[ValueType::Addr]),
+    ("put_i", put_i, &[ValueType::Int]),
+    ("get_i", get_i, &[]),
+    ("put_d", put_d, &[ValueType::Double]),
+    ("get_d", get_d, &[]),
+    ("put_c", put_c, &[ValueType::Char]),
+    ("get_c", get_c, &[]),
+];
+
+fn put_s(vm: &mut Vm, io: &mut Io) -> Result<(), VmError> {
+    let addr = vm.popa()?;
+    io.output.write_all(vm.read_cstr(addr)?).ok();
+    io.output.flush().ok();
+    Ok(())
+}
+
+fn get_s(vm: &mut Vm, io: &mut Io) -> Result<(), VmError> {
+    let addr = vm.popa()?;
+    let mut bytes = read_line(io).into_bytes();
+    bytes.push(0);
+    vm.write(addr, &bytes)
+}
+
+fn put_i(vm: &mut Vm, io: &mut Io) -> Result<(), VmError> {
+    writeln!(io.output, "#{}", vm.popi()?).ok();
+    Ok(())
+}
+
+fn get_i(vm: &mut Vm, io: &mut Io) -> Result<(), VmError> {
+    let i = read_line(io).trim().parse().map_err(|_| VmError::BadInput { ip: vm.ip(), expected: "an int" })?;
+    vm.pushi(i)
+}
+
+fn put_d(vm: &mut Vm, io: &mut Io) -> Result<(), VmError> {
+    writeln!(io.output, "#{}", vm.popd()?).ok();
+    Ok(())
+}
+
+fn get_d(vm: &mut Vm, io: &mut Io) -> Result<(), VmError> {
+    let d = read_line(io).trim().parse().map_err(|_| VmError::BadInput { ip: vm.ip(), expected: "a double" })?;
+    vm.pushd(d)
+}
+
+fn put_c(vm: &mut Vm, io: &mut Io) -> Result<(), VmError> {
+    io.output.write_all(&[vm.popc()?]).ok();
+    io.output.flush().ok();
+    Ok(())
+}
+
+fn get_c(vm: &mut Vm, io: &mut Io) -> Result<(), VmError> {
+    vm.pushc(read_line(io).bytes().next().unwrap_or(0))
+}
+
+/// Reads a line without its terminator. At the end of the input the line is empty.
+fn read_line(io: &mut Io) -> String {
+    let mut line = String::new();
+    io.input.read_line(&mut line).ok();
+    line.trim_end_matches(['\r', '\n']).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::vm::Opcode;
+
+    /// Runs `code` followed by `HALT` with `input` as stdin, returning what the program wrote.
+    fn run(input: &str, code: impl FnOnce(&mut Vm)) -> String {
+        let mut vm = Vm::new();
+        code(&mut vm);
+        vm.add_instr(Opcode::OHalt);
+        let mut output = Vec::new();
+        let mut io = Io { input: &mut input.as_bytes(), output: &mut output };
+        let state = vm.run_with(&mut io).unwrap();
+        assert!(state.stack.is_empty());
+        String::from_utf8(output).unwrap()
+    }
+
+    fn call(vm: &mut Vm, name: &str) {
+        let index = vm.native(name).unwrap();
+        vm.add_instr_a(Opcode::OCallExt, index);
+    }
+
+    #[test]
+    fn test_builtins() {
+        let output = run("41\n2.5\nxyz\n", |vm| {
+            call(vm, "get_i");
+            vm.add_instr_i(Opcode::OPushCtI, 1);
+            vm.add_instr(Opcode::OAddI);
+            call(vm, "put_i");
+            call(vm, "get_d");
+            call(vm, "put_d");
+            call(vm, "get_c");
+            call(vm, "put_c");
+        });
+        assert_eq!(output, "#42\n#2.5\nx");
+
+        let output = run("hello\n", |vm| {
+            let buffer = vm.alloc_global(16).unwrap();
+            let greeting = vm.alloc_data(b"> \0").unwrap();
+            vm.add_instr_a(Opcode::OPushCtA, buffer);
+            call(vm, "get_s");
+            vm.add_instr_a(Opcode::OPushCtA, greeting);
+            call(vm, "put_s");
+            vm.add_instr_a(Opcode::OPushCtA, buffer);
+            call(vm, "put_s");
+        });
+        assert_eq!(output, "> hello");
+
+        // at the end of the input get_c returns a nul and get_s an empty string
+        let output = run("", |vm| {
+            call(vm, "get_c");
+            vm.add_instr(Opcode::OCastCI);
+            call(vm, "put_i");
+        });
+        assert_eq!(output, "#0\n");
+
+        let mut vm = Vm::new();
+        vm.add_instr_a(Opcode::OPushCtA, usize::MAX);
+        call(&mut vm, "put_s");
+        let mut io = Io { input: &mut "".as_bytes(), output: &mut Vec::new() };
+        assert_eq!(vm.run_with(&mut io), Err(VmError::BadAddress { ip: 1, addr: usize::MAX }));
+    }
+
+    #[test]
+    fn test_bad_input() {
+        for (input, name, expected) in [
+            ("4x\n", "get_i", "an int"),
+            ("", "get_i", "an int"),
+            ("\n", "get_d", "a double"),
+            ("2.5.1\n", "get_d", "a double"),
+        ] {
+            let mut vm = Vm::new();
+            vm.add_instr_i(Opcode::OPushCtI, 0);
+            call(&mut vm, name);
+            let mut io = Io { input: &mut input.as_bytes(), output: &mut Vec::new() };
+            assert_eq!(vm.run_with(&mut io), Err(VmError::BadInput { ip: 1, expected }));
+        }
+    }
+
+    #[test]
+    fn test_register_native() {
+        fn twice(vm: &mut Vm, _io: &mut Io) -> Result<(), VmError> {
+            let i = vm.popi()?;
+            vm.pushi(2 * i)
+        }
+
+        let output = run("", |vm| {
+            assert_eq!(vm.register_native("twice", twice, &[ValueType::Int]), BUILTINS.len());
+            vm.add_instr_i(Opcode::OPushCtI, 21);
+            call(vm, "twice");
+            call(vm, "put_i");
+        });
+        assert_eq!(output, "#42\n");
+    }
+}
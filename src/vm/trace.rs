@@ -0,0 +1,263 @@
+use std::io::{self, Write};
+
+use super::vm::Arg;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// One aligned, human readable line per step.
+    Text,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+/// The type an instruction takes a value from the stack as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    Int,
+    Double,
+    Char,
+    Addr,
+    /// A number of bytes copied as they are, like a returned or stored value.
+    Bytes(usize),
+}
+
+impl ValueType {
+    /// The number of bytes the value takes on the stack.
+    pub fn size(self) -> usize {
+        match self {
+            ValueType::Char => 1,
+            ValueType::Int | ValueType::Double | ValueType::Addr => 8,
+            ValueType::Bytes(size) => size,
+        }
+    }
+}
+
+/// A value on the stack, read as the type the instruction takes it as.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceValue {
+    Int(i64),
+    Double(f64),
+    Char(u8),
+    Addr(usize),
+    Bytes(Vec<u8>),
+}
+
+impl TraceValue {
+    /// Reads a value of type `ty` from its `ty.size()` bytes.
+    pub fn read(ty: ValueType, bytes: &[u8]) -> TraceValue {
+        match ty {
+            ValueType::Int => TraceValue::Int(i64::from_ne_bytes(bytes.try_into().unwrap())),
+            ValueType::Double => TraceValue::Double(f64::from_ne_bytes(bytes.try_into().unwrap())),
+            ValueType::Char => TraceValue::Char(bytes[0]),
+            ValueType::Addr => TraceValue::Addr(u64::from_ne_bytes(bytes.try_into().unwrap()) as usize),
+            ValueType::Bytes(_) => TraceValue::Bytes(bytes.to_vec()),
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            TraceValue::Int(i) => format!("int {}", i),
+            TraceValue::Double(d) => format!("double {}", d),
+            TraceValue::Char(c) => format!("char '{}'", (*c as char).escape_default()),
+            TraceValue::Addr(addr) => format!("addr @{}", addr),
+            TraceValue::Bytes(bytes) => format!("bytes {}", hex(bytes)),
+        }
+    }
+
+    fn json(&self) -> String {
+        let (ty, value) = match self {
+            TraceValue::Int(i) => ("int", i.to_string()),
+            TraceValue::Double(d) if d.is_finite() => ("double", format!("{:?}", d)),
+            TraceValue::Double(_) => ("double", String::from("null")),
+            TraceValue::Char(c) => ("char", c.to_string()),
+            TraceValue::Addr(addr) => ("addr", addr.to_string()),
+            TraceValue::Bytes(bytes) => ("bytes", format!("\"{}\"", hex(bytes))),
+        };
+        format!(r#"{{"type":"{}","value":{}}}"#, ty, value)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The state of the VM just before it executes an instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    /// The 1-based number of the step.
+    pub step: u64,
+    /// The index of the instruction.
+    pub index: usize,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Arg>,
+    /// The number of bytes on the stack.
+    pub stack_depth: usize,
+    /// The values the instruction takes from the top of the stack, deepest first.
+    /// Those of `DROP` and `INSERT`, which move untyped bytes, are not shown.
+    pub top: Vec<TraceValue>,
+}
+
+impl TraceRecord {
+    fn text(&self) -> String {
+        let operands: Vec<String> = self.operands.iter().map(|arg| match arg {
+            Arg::Int(i) => i.to_string(),
+            Arg::Double(d) => d.to_string(),
+            Arg::Addr(addr) => format!("@{}", addr),
+            Arg::None => String::new(),
+        }).collect();
+        let top: Vec<String> = self.top.iter().map(TraceValue::text).collect();
+        format!(
+            "{:>8} {:>6}  {:<12} {:<16} depth={:<6} top=[{}]",
+            self.step, self.index, self.mnemonic, operands.join(","), self.stack_depth, top.join(", "),
+        )
+    }
+
+    fn json(&self) -> String {
+        let operands: Vec<String> = self.operands.iter().map(|arg| match arg {
+            Arg::Int(i) => i.to_string(),
+            Arg::Double(d) if d.is_finite() => format!("{:?}", d),
+            Arg::Addr(addr) => addr.to_string(),
+            Arg::Double(_) | Arg::None => String::from("null"),
+        }).collect();
+        let top: Vec<String> = self.top.iter().map(TraceValue::json).collect();
+        format!(
+            r#"{{"step":{},"index":{},"mnemonic":"{}","operands":[{}],"stack_depth":{},"top":[{}]}}"#,
+            self.step, self.index, self.mnemonic, operands.join(","), self.stack_depth, top.join(","),
+        )
+    }
+}
+
+/// Writes a [`TraceRecord`] for every executed instruction.
+pub struct Tracer {
+    format: TraceFormat,
+    out: Box<dyn Write>,
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat, out: Box<dyn Write>) -> Tracer {
+        Tracer { format, out }
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let line = match self.format {
+            TraceFormat::Text => record.text(),
+            TraceFormat::JsonLines => record.json(),
+        };
+        writeln!(self.out, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::vm::{Io, Opcode, Vm};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// An output the test can read back after the tracer is moved into the VM.
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        let record = TraceRecord {
+            step: 3,
+            index: 7,
+            mnemonic: "OFFSET",
+            operands: vec![],
+            stack_depth: 17,
+            top: vec![TraceValue::Addr(1032), TraceValue::Int(-1)],
+        };
+        assert_eq!(
+            record.text(),
+            "       3      7  OFFSET                        depth=17     top=[addr @1032, int -1]"
+        );
+        assert_eq!(
+            record.json(),
+            r#"{"step":3,"index":7,"mnemonic":"OFFSET","operands":[],"stack_depth":17,"top":[{"type":"addr","value":1032},{"type":"int","value":-1}]}"#
+        );
+
+        let record = TraceRecord {
+            operands: vec![Arg::Int(16), Arg::Int(8)],
+            mnemonic: "INSERT",
+            top: vec![TraceValue::Double(f64::NAN), TraceValue::Char(b'\n'), TraceValue::Bytes(vec![1, 0xff])],
+            ..record
+        };
+        assert!(record.text().contains("INSERT       16,8 "));
+        assert!(record.text().ends_with("top=[double NaN, char '\\n', bytes 01ff]"));
+        assert!(record.json().contains(
+            r#""top":[{"type":"double","value":null},{"type":"char","value":10},{"type":"bytes","value":"01ff"}]"#
+        ));
+
+        let record = TraceRecord { operands: vec![Arg::Double(2.0)], mnemonic: "PUSHCT_D", top: vec![], ..record };
+        assert!(record.json().contains(r#""operands":[2.0],"stack_depth":17,"top":[]"#));
+    }
+
+    #[test]
+    fn test_typed_top() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::new();
+        vm.set_tracer(Some(Tracer::new(TraceFormat::JsonLines, Box::new(Shared(out.clone())))));
+        let global = vm.alloc_global(16).unwrap();
+        vm.add_instr_i(Opcode::OPushCtC, b'a' as i64);
+        vm.add_instr_d(Opcode::OPushCtD, 2.5);
+        vm.add_instr(Opcode::OCastDC);
+        vm.add_instr(Opcode::OAddC);
+        vm.add_instr_a(Opcode::OPushCtA, global);
+        vm.add_instr_i(Opcode::OPushCtI, 3);
+        vm.add_instr(Opcode::OOffset);
+        vm.add_instr_i(Opcode::OPushCtI, 7);
+        vm.add_instr_i(Opcode::OStore, 8);
+        vm.add_instr_a(Opcode::OCallExt, vm.native("put_c").unwrap());
+        vm.add_instr_i(Opcode::OPushCtI, 5);
+        vm.add_instr_a(Opcode::OCallExt, vm.native("put_i").unwrap());
+        vm.add_instr(Opcode::OHalt);
+        vm.run_with(&mut Io { input: &mut io::empty(), output: &mut io::sink() }).unwrap();
+
+        let out = String::from_utf8(out.borrow().clone()).unwrap();
+        let tops: Vec<&str> = out.lines().map(|line| &line[line.find(r#""top":"#).unwrap() + 6..line.len() - 1]).collect();
+        let stored: String = 7i64.to_ne_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(tops, [
+            "[]",
+            "[]",
+            r#"[{"type":"double","value":2.5}]"#,
+            r#"[{"type":"char","value":97},{"type":"char","value":2}]"#,
+            "[]",
+            "[]",
+            &format!(r#"[{{"type":"addr","value":{}}},{{"type":"int","value":3}}]"#, global),
+            "[]",
+            &format!(r#"[{{"type":"addr","value":{}}},{{"type":"bytes","value":"{}"}}]"#, global + 3, stored),
+            r#"[{"type":"char","value":99}]"#,
+            "[]",
+            r#"[{"type":"int","value":5}]"#,
+            "[]",
+        ]);
+    }
+
+    #[test]
+    fn test_short_stack() {
+        // an instruction about to underflow shows the operands which are there
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::new();
+        vm.set_tracer(Some(Tracer::new(TraceFormat::Text, Box::new(Shared(out.clone())))));
+        vm.add_instr_i(Opcode::OPushCtI, 7);
+        vm.add_instr(Opcode::OAddI);
+        assert!(vm.run_with(&mut Io { input: &mut io::empty(), output: &mut io::sink() }).is_err());
+
+        let out = String::from_utf8(out.borrow().clone()).unwrap();
+        assert!(out.lines().nth(1).unwrap().ends_with("depth=8      top=[int 7]"));
+    }
+}
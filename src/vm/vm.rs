@@ -1,331 +1,1105 @@
-use std::ptr;
-use std::alloc::{alloc, dealloc, Layout};
-use std::mem::size_of;
+use std::fmt;
+use std::io::{self, BufRead, Write};
 
-#[derive(Debug, Clone, Copy)]
+use super::natives;
+use super::trace::{TraceRecord, TraceValue, Tracer, ValueType};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opcode {
+    OAddC,
+    OAddD,
+    OAddI,
     OCall,
     OCallExt,
+    OCastCD,
+    OCastCI,
+    OCastDC,
+    OCastDI,
+    OCastIC,
     OCastID,
+    ODivC,
+    ODivD,
+    ODivI,
     ODrop,
     OEnter,
+    OEqC,
     OEqD,
+    OEqI,
+    OGreaterC,
+    OGreaterD,
+    OGreaterI,
+    OGreaterEqC,
+    OGreaterEqD,
+    OGreaterEqI,
     OHalt,
     OInsert,
+    OJfD,
+    OJfI,
+    OJmp,
+    OJtD,
     OJtI,
+    OLessC,
+    OLessD,
+    OLessI,
+    OLessEqC,
+    OLessEqD,
+    OLessEqI,
     OLoad,
+    OMulC,
+    OMulD,
+    OMulI,
+    ONegC,
+    ONegD,
+    ONegI,
+    /// Ends a non-void function which did not return a value.
+    ONoReturn,
+    ONotC,
+    ONotD,
+    ONotI,
+    ONotEqC,
+    ONotEqD,
+    ONotEqI,
     OOffset,
-    OPushFpAddr,
     OPushCtA,
+    OPushCtC,
+    OPushCtD,
     OPushCtI,
+    OPushFpAddr,
     ORet,
     OStore,
+    OSubC,
     OSubD,
-    OAddI,
+    OSubI,
+}
+
+impl Opcode {
+    /// The mnemonic used in traces and bytecode dumps.
+    pub fn name(self) -> &'static str {
+        match self {
+            Opcode::OAddC => "ADD_C",
+            Opcode::OAddD => "ADD_D",
+            Opcode::OAddI => "ADD_I",
+            Opcode::OCall => "CALL",
+            Opcode::OCallExt => "CALLEXT",
+            Opcode::OCastCD => "CAST_C_D",
+            Opcode::OCastCI => "CAST_C_I",
+            Opcode::OCastDC => "CAST_D_C",
+            Opcode::OCastDI => "CAST_D_I",
+            Opcode::OCastIC => "CAST_I_C",
+            Opcode::OCastID => "CAST_I_D",
+            Opcode::ODivC => "DIV_C",
+            Opcode::ODivD => "DIV_D",
+            Opcode::ODivI => "DIV_I",
+            Opcode::ODrop => "DROP",
+            Opcode::OEnter => "ENTER",
+            Opcode::OEqC => "EQ_C",
+            Opcode::OEqD => "EQ_D",
+            Opcode::OEqI => "EQ_I",
+            Opcode::OGreaterC => "GREATER_C",
+            Opcode::OGreaterD => "GREATER_D",
+            Opcode::OGreaterI => "GREATER_I",
+            Opcode::OGreaterEqC => "GREATEREQ_C",
+            Opcode::OGreaterEqD => "GREATEREQ_D",
+            Opcode::OGreaterEqI => "GREATEREQ_I",
+            Opcode::OHalt => "HALT",
+            Opcode::OInsert => "INSERT",
+            Opcode::OJfD => "JF_D",
+            Opcode::OJfI => "JF_I",
+            Opcode::OJmp => "JMP",
+            Opcode::OJtD => "JT_D",
+            Opcode::OJtI => "JT_I",
+            Opcode::OLessC => "LESS_C",
+            Opcode::OLessD => "LESS_D",
+            Opcode::OLessI => "LESS_I",
+            Opcode::OLessEqC => "LESSEQ_C",
+            Opcode::OLessEqD => "LESSEQ_D",
+            Opcode::OLessEqI => "LESSEQ_I",
+            Opcode::OLoad => "LOAD",
+            Opcode::OMulC => "MUL_C",
+            Opcode::OMulD => "MUL_D",
+            Opcode::OMulI => "MUL_I",
+            Opcode::ONegC => "NEG_C",
+            Opcode::ONegD => "NEG_D",
+            Opcode::ONegI => "NEG_I",
+            Opcode::ONoReturn => "NORETURN",
+            Opcode::ONotC => "NOT_C",
+            Opcode::ONotD => "NOT_D",
+            Opcode::ONotI => "NOT_I",
+            Opcode::ONotEqC => "NOTEQ_C",
+            Opcode::ONotEqD => "NOTEQ_D",
+            Opcode::ONotEqI => "NOTEQ_I",
+            Opcode::OOffset => "OFFSET",
+            Opcode::OPushCtA => "PUSHCT_A",
+            Opcode::OPushCtC => "PUSHCT_C",
+            Opcode::OPushCtD => "PUSHCT_D",
+            Opcode::OPushCtI => "PUSHCT_I",
+            Opcode::OPushFpAddr => "PUSHFPADDR",
+            Opcode::ORet => "RET",
+            Opcode::OStore => "STORE",
+            Opcode::OSubC => "SUB_C",
+            Opcode::OSubD => "SUB_D",
+            Opcode::OSubI => "SUB_I",
+        }
+    }
+
+    /// The types of the values the instruction pops from the stack, deepest first.
+    /// Those of `CALL_EXT`, `RET` and `STORE` depend on their arguments and are
+    /// given by [`Vm::stack_operands`]; `DROP` and `INSERT` move untyped bytes.
+    pub fn stack_operands(self) -> &'static [ValueType] {
+        match self {
+            Opcode::OAddI | Opcode::OSubI | Opcode::OMulI | Opcode::ODivI
+                | Opcode::OEqI | Opcode::ONotEqI | Opcode::OLessI | Opcode::OLessEqI
+                | Opcode::OGreaterI | Opcode::OGreaterEqI => &[ValueType::Int, ValueType::Int],
+            Opcode::OAddD | Opcode::OSubD | Opcode::OMulD | Opcode::ODivD
+                | Opcode::OEqD | Opcode::ONotEqD | Opcode::OLessD | Opcode::OLessEqD
+                | Opcode::OGreaterD | Opcode::OGreaterEqD => &[ValueType::Double, ValueType::Double],
+            Opcode::OAddC | Opcode::OSubC | Opcode::OMulC | Opcode::ODivC
+                | Opcode::OEqC | Opcode::ONotEqC | Opcode::OLessC | Opcode::OLessEqC
+                | Opcode::OGreaterC | Opcode::OGreaterEqC => &[ValueType::Char, ValueType::Char],
+            Opcode::ONegI | Opcode::ONotI | Opcode::OCastID | Opcode::OCastIC
+                | Opcode::OJfI | Opcode::OJtI => &[ValueType::Int],
+            Opcode::ONegD | Opcode::ONotD | Opcode::OCastDI | Opcode::OCastDC
+                | Opcode::OJfD | Opcode::OJtD => &[ValueType::Double],
+            Opcode::ONegC | Opcode::ONotC | Opcode::OCastCI | Opcode::OCastCD => &[ValueType::Char],
+            Opcode::OLoad => &[ValueType::Addr],
+            Opcode::OOffset => &[ValueType::Addr, ValueType::Int],
+            Opcode::OCall | Opcode::OCallExt | Opcode::ODrop | Opcode::OEnter | Opcode::OHalt
+                | Opcode::ONoReturn | Opcode::OInsert | Opcode::OJmp | Opcode::OPushCtA | Opcode::OPushCtC | Opcode::OPushCtD
+                | Opcode::OPushCtI | Opcode::OPushFpAddr | Opcode::ORet | Opcode::OStore => &[],
+        }
+    }
+}
+
+/// An instruction argument. Instruction indices, memory addresses and
+/// external function indices are all stored as `Addr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arg {
+    None,
+    Int(i64),
+    Double(f64),
+    Addr(usize),
 }
 
+impl Arg {
+    // an argument of another kind reads as zero, like the unset arguments of an instruction
+    fn i(self) -> i64 {
+        match self {
+            Arg::Int(i) => i,
+            _ => 0,
+        }
+    }
+
+    fn d(self) -> f64 {
+        match self {
+            Arg::Double(d) => d,
+            _ => 0.0,
+        }
+    }
+
+    fn addr(self) -> usize {
+        match self {
+            Arg::Addr(addr) => addr,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Instr {
-    opcode: Opcode,
-    args: [Arg; 2],
-    last: *mut Instr,
-    next: *mut Instr,
+    pub opcode: Opcode,
+    pub args: [Arg; 2],
 }
 
-pub union Arg {
-    i: i64,
-    d: f64,
-    addr: *mut u8,
+/// A runtime fault. `ip` is the index of the faulting instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VmError {
+    StackOverflow { ip: usize },
+    StackUnderflow { ip: usize },
+    /// There is no instruction at `ip`, or it cannot be executed.
+    InvalidOpcode { ip: usize },
+    BadAddress { ip: usize, addr: usize },
+    DivisionByZero { ip: usize },
+    /// A function returning a value reached its end without a `return`.
+    MissingReturn { ip: usize },
+    /// A line of the input, possibly empty at its end, is not `expected`, like "an int".
+    BadInput { ip: usize, expected: &'static str },
+    /// Allocating `size` more bytes of globals did not fit in the globals segment.
+    GlobalsExhausted { size: usize },
 }
 
-pub static mut INSTRUCTIONS: *mut Instr = ptr::null_mut();
-static mut LAST_INSTRUCTION: *mut Instr = ptr::null_mut();
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::StackOverflow { ip } => write!(f, "stack overflow at instruction {}", ip),
+            VmError::StackUnderflow { ip } => write!(f, "stack underflow at instruction {}", ip),
+            VmError::InvalidOpcode { ip } => write!(f, "invalid instruction at {}", ip),
+            VmError::BadAddress { ip, addr } => write!(f, "invalid memory access to address {} at instruction {}", addr, ip),
+            VmError::DivisionByZero { ip } => write!(f, "division by zero at instruction {}", ip),
+            VmError::MissingReturn { ip } => write!(f, "function ended without returning a value at instruction {}", ip),
+            VmError::BadInput { ip, expected } => write!(f, "expected {} on the input at instruction {}", expected, ip),
+            VmError::GlobalsExhausted { size } => {
+                write!(f, "cannot allocate {} bytes of globals, the limit is {}", size, GLOBAL_SIZE)
+            }
+        }
+    }
+}
 
-static mut SP: *mut u8 = ptr::null_mut();
-static mut STACK: *mut u8 = ptr::null_mut();
-static mut STACK_AFTER: *mut u8 = ptr::null_mut();
+/// What is left when the program halts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitState {
+    /// The number of executed instructions.
+    pub steps: u64,
+    /// The bytes remaining on the stack.
+    pub stack: Vec<u8>,
+}
 
 const STACK_SIZE: usize = 1024;
 const GLOBAL_SIZE: usize = 1024;
+const ADDR_SIZE: usize = 8;
+
+/// The input and output of the running program.
+pub struct Io<'a> {
+    pub input: &'a mut dyn BufRead,
+    pub output: &'a mut dyn Write,
+}
 
-static mut GLOBALS: [u8; GLOBAL_SIZE] = [0; GLOBAL_SIZE];
-static mut N_GLOBALS: usize = 0;
+/// A function implemented by the host, called with [`Opcode::OCallExt`]. It
+/// pops its arguments from the stack and pushes its result.
+pub type NativeFn = fn(&mut Vm, &mut Io) -> Result<(), VmError>;
 
-unsafe fn err(message: &str) {
-    eprintln!("{}", message);
-    std::process::exit(1);
+/// The virtual machine: the code, and a memory holding the globals followed by the stack.
+pub struct Vm {
+    code: Vec<Instr>,
+    memory: Vec<u8>,
+    n_globals: usize,
+    sp: usize,
+    fp: usize,
+    /// The instruction being executed.
+    ip: usize,
+    /// The external functions, with the types of the arguments they pop.
+    natives: Vec<(String, NativeFn, &'static [ValueType])>,
+    tracer: Option<Tracer>,
 }
 
-unsafe fn pushd(d: f64) {
-    if SP.add(size_of::<f64>()) > STACK_AFTER {
-        err("out of stack");
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
     }
-    *(SP as *mut f64) = d;
-    SP = SP.add(size_of::<f64>());
 }
 
-unsafe fn popd() -> f64 {
-    SP = SP.sub(size_of::<f64>());
-    if SP < STACK {
-        err("not enough stack bytes for popd");
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            code: Vec::new(),
+            memory: vec![0; GLOBAL_SIZE + STACK_SIZE],
+            n_globals: 0,
+            sp: GLOBAL_SIZE,
+            fp: GLOBAL_SIZE,
+            ip: 0,
+            natives: natives::BUILTINS.iter().map(|&(name, func, params)| (name.to_string(), func, params)).collect(),
+            tracer: None,
+        }
     }
-    *(SP as *mut f64)
-}
 
-unsafe fn pushi(i: i64) {
-    if SP.add(size_of::<i64>()) > STACK_AFTER {
-        err("out of stack");
+    /// The index of the instruction being executed.
+    pub fn ip(&self) -> usize {
+        self.ip
     }
-    *(SP as *mut i64) = i;
-    SP = SP.add(size_of::<i64>());
-}
 
-unsafe fn popi() -> i64 {
-    SP = SP.sub(size_of::<i64>());
-    if SP < STACK {
-        err("not enough stack bytes for popi");
+    /// Traces every executed instruction to `tracer`, or stops tracing if it is `None`.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
     }
-    *(SP as *mut i64)
-}
 
-unsafe fn pusha(a: *mut u8) {
-    if SP.add(size_of::<*mut u8>()) > STACK_AFTER {
-        err("out of stack");
+    /// Makes `func` callable as the external function `name`, replacing any
+    /// function registered with the same name, and returns its index. `params` are the
+    /// types of the arguments it pops, deepest first, which traces show.
+    pub fn register_native(&mut self, name: &str, func: NativeFn, params: &'static [ValueType]) -> usize {
+        match self.native(name) {
+            Some(index) => {
+                self.natives[index].1 = func;
+                self.natives[index].2 = params;
+                index
+            }
+            None => {
+                self.natives.push((name.to_string(), func, params));
+                self.natives.len() - 1
+            }
+        }
     }
-    *(SP as *mut *mut u8) = a;
-    SP = SP.add(size_of::<*mut u8>());
-}
 
-unsafe fn popa() -> *mut u8 {
-    SP = SP.sub(size_of::<*mut u8>());
-    if SP < STACK {
-        err("not enough stack bytes for popa");
+    /// Returns the index of the external function `name`.
+    pub fn native(&self, name: &str) -> Option<usize> {
+        self.natives.iter().position(|(native_name, ..)| native_name == name)
     }
-    *(SP as *mut *mut u8)
-}
 
-pub unsafe fn create_instr(opcode: Opcode) -> *mut Instr {
-    let layout = Layout::new::<Instr>();
-    let i = alloc(layout) as *mut Instr;
-    if i.is_null() {
-        err("failed to allocate memory for Instr");
+    /// The index the next added instruction will have.
+    pub fn next_index(&self) -> usize {
+        self.code.len()
     }
-    (*i).opcode = opcode;
-    (*i).last = ptr::null_mut();
-    (*i).next = ptr::null_mut();
-    i
-}
 
-pub unsafe fn insert_instr_after(after: *mut Instr, i: *mut Instr) {
-    (*i).next = (*after).next;
-    (*i).last = after;
-    (*after).next = i;
-    if (*i).next.is_null() {
-        LAST_INSTRUCTION = i;
+    pub fn add_instr(&mut self, opcode: Opcode) -> usize {
+        self.code.push(Instr { opcode, args: [Arg::None, Arg::None] });
+        self.code.len() - 1
     }
-}
 
-pub unsafe fn add_instr(opcode: Opcode) -> *mut Instr {
-    let i = create_instr(opcode);
-    if LAST_INSTRUCTION.is_null() {
-        INSTRUCTIONS = i;
-    } else {
-        (*LAST_INSTRUCTION).next = i;
-        (*i).last = LAST_INSTRUCTION;
+    pub fn add_instr_i(&mut self, opcode: Opcode, val: i64) -> usize {
+        let i = self.add_instr(opcode);
+        self.code[i].args[0] = Arg::Int(val);
+        i
     }
-    LAST_INSTRUCTION = i;
-    i
-}
 
-pub unsafe fn add_instr_i(opcode: Opcode, val: i64) -> *mut Instr {
-    let i = create_instr(opcode);
-    (*i).args[0].i = val;
-    if LAST_INSTRUCTION.is_null() {
-        INSTRUCTIONS = i;
-    } else {
-        (*LAST_INSTRUCTION).next = i;
-        (*i).last = LAST_INSTRUCTION;
-    }
-    LAST_INSTRUCTION = i;
-    i
-}
+    pub fn add_instr_ii(&mut self, opcode: Opcode, val1: i64, val2: i64) -> usize {
+        let i = self.add_instr(opcode);
+        self.code[i].args = [Arg::Int(val1), Arg::Int(val2)];
+        i
+    }
 
-pub unsafe fn alloc_global(size: usize) -> *mut u8 {
-    if N_GLOBALS + size > GLOBAL_SIZE {
-        err("insufficient globals space");
+    pub fn add_instr_d(&mut self, opcode: Opcode, val: f64) -> usize {
+        let i = self.add_instr(opcode);
+        self.code[i].args[0] = Arg::Double(val);
+        i
     }
-    let p = GLOBALS.as_mut_ptr().add(N_GLOBALS);
-    N_GLOBALS += size;
-    p
-}
 
-unsafe fn put_i() {
-    println!("#{}", popi());
-}
+    pub fn add_instr_a(&mut self, opcode: Opcode, addr: usize) -> usize {
+        let i = self.add_instr(opcode);
+        self.code[i].args[0] = Arg::Addr(addr);
+        i
+    }
 
-pub unsafe fn run(mut ip: *mut Instr) {
-    let mut i_val1: i64;
-    let mut i_val2: i64;
-    let mut d_val1: f64;
-    let mut d_val2: f64;
-    let mut a_val1: *mut u8;
-    let mut fp: *mut u8 = ptr::null_mut();
-    let mut old_sp: *mut u8;
-    
-    SP = alloc(Layout::from_size_align(STACK_SIZE, 1).unwrap());
-    STACK = SP;
-    STACK_AFTER = SP.add(STACK_SIZE);
-
-    while !ip.is_null() {
-        println!("{:?}/{:?}", ip, SP.offset_from(STACK));
-
-        match (*ip).opcode {
-            Opcode::OCall => {
-                a_val1 = (*ip).args[0].addr;
-                println!("CALL\t{:?}", a_val1);
-                pusha((*ip).next as *mut u8);
-                ip = a_val1 as *mut Instr;
-            }
-            Opcode::OCallExt => {
-                println!("CALLEXT\t{:?}", (*ip).args[0].addr);
-                let func: fn() = std::mem::transmute((*ip).args[0].addr);
-                func();
-                ip = (*ip).next;
-            }
-            Opcode::OCastID => {
-                i_val1 = popi();
-                d_val1 = i_val1 as f64;
-                println!("CAST_I_D\t({} -> {})", i_val1, d_val1);
-                pushd(d_val1);
-                ip = (*ip).next;
-            }
-            Opcode::ODrop => {
-                i_val1 = (*ip).args[0].i;
-                println!("DROP\t{}", i_val1);
-                if SP.sub(i_val1 as usize) < STACK {
-                    err("not enough stack bytes");
-                }
-                SP = SP.sub(i_val1 as usize);
-                ip = (*ip).next;
-            }
-            Opcode::OEnter => {
-                i_val1 = (*ip).args[0].i;
-                println!("ENTER\t{}", i_val1);
-                pusha(fp);
-                fp = SP;
-                SP = SP.add(i_val1 as usize);
-                ip = (*ip).next;
-            }
-            Opcode::OEqD => {
-                d_val1 = popd();
-                d_val2 = popd();
-                println!("EQ_D\t({}=={} -> {})", d_val2, d_val1, (d_val2 == d_val1) as i64);
-                pushi((d_val2 == d_val1) as i64);
-                ip = (*ip).next;
+    /// Points the instruction `i` (a jump or a call) at the instruction `target`.
+    pub fn set_target(&mut self, i: usize, target: usize) {
+        self.code[i].args[0] = Arg::Addr(target);
+    }
+
+    pub fn set_arg_i(&mut self, i: usize, val: i64) {
+        self.code[i].args[0] = Arg::Int(val);
+    }
+
+    pub fn alloc_global(&mut self, size: usize) -> Result<usize, VmError> {
+        if self.n_globals + size > GLOBAL_SIZE {
+            return Err(VmError::GlobalsExhausted { size });
+        }
+        let addr = self.n_globals;
+        self.n_globals += size;
+        Ok(addr)
+    }
+
+    /// Allocates a global initialized with `bytes`.
+    pub fn alloc_data(&mut self, bytes: &[u8]) -> Result<usize, VmError> {
+        let addr = self.alloc_global(bytes.len())?;
+        self.memory[addr..addr + bytes.len()].copy_from_slice(bytes);
+        Ok(addr)
+    }
+
+    /// Returns `len` bytes of memory at `addr`.
+    pub fn read(&self, addr: usize, len: usize) -> Result<&[u8], VmError> {
+        let end = addr.checked_add(len).ok_or(VmError::BadAddress { ip: self.ip, addr })?;
+        self.memory.get(addr..end).ok_or(VmError::BadAddress { ip: self.ip, addr })
+    }
+
+    /// The bytes of the nul-terminated string at `addr`, without the nul.
+    pub fn read_cstr(&self, addr: usize) -> Result<&[u8], VmError> {
+        let bytes = self.memory.get(addr..).ok_or(VmError::BadAddress { ip: self.ip, addr })?;
+        let len = bytes.iter().position(|&byte| byte == 0)
+            .ok_or(VmError::BadAddress { ip: self.ip, addr: self.memory.len() })?;
+        Ok(&bytes[..len])
+    }
+
+    pub fn write(&mut self, addr: usize, bytes: &[u8]) -> Result<(), VmError> {
+        let ip = self.ip;
+        let end = addr.checked_add(bytes.len()).ok_or(VmError::BadAddress { ip, addr })?;
+        let dst = self.memory.get_mut(addr..end).ok_or(VmError::BadAddress { ip, addr })?;
+        dst.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Copies `len` bytes from `src` to `dst`, checking both ranges.
+    fn copy(&mut self, src: usize, dst: usize, len: usize) -> Result<(), VmError> {
+        self.read(src, len)?;
+        self.read(dst, len)?;
+        self.memory.copy_within(src..src + len, dst);
+        Ok(())
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        if self.sp + bytes.len() > self.memory.len() {
+            return Err(VmError::StackOverflow { ip: self.ip });
+        }
+        self.memory[self.sp..self.sp + bytes.len()].copy_from_slice(bytes);
+        self.sp += bytes.len();
+        Ok(())
+    }
+
+    fn pop<const N: usize>(&mut self) -> Result<[u8; N], VmError> {
+        if self.sp < GLOBAL_SIZE + N {
+            return Err(VmError::StackUnderflow { ip: self.ip });
+        }
+        self.sp -= N;
+        Ok(self.memory[self.sp..self.sp + N].try_into().unwrap())
+    }
+
+    pub fn pushi(&mut self, i: i64) -> Result<(), VmError> {
+        self.push(&i.to_ne_bytes())
+    }
+
+    pub fn popi(&mut self) -> Result<i64, VmError> {
+        self.pop().map(i64::from_ne_bytes)
+    }
+
+    pub fn pushd(&mut self, d: f64) -> Result<(), VmError> {
+        self.push(&d.to_ne_bytes())
+    }
+
+    pub fn popd(&mut self) -> Result<f64, VmError> {
+        self.pop().map(f64::from_ne_bytes)
+    }
+
+    pub fn pushc(&mut self, c: u8) -> Result<(), VmError> {
+        self.push(&[c])
+    }
+
+    pub fn popc(&mut self) -> Result<u8, VmError> {
+        self.pop::<1>().map(|bytes| bytes[0])
+    }
+
+    pub fn pusha(&mut self, a: usize) -> Result<(), VmError> {
+        self.push(&(a as u64).to_ne_bytes())
+    }
+
+    pub fn popa(&mut self) -> Result<usize, VmError> {
+        self.pop().map(|bytes| u64::from_ne_bytes(bytes) as usize)
+    }
+
+    /// Reserves `n` bytes on top of the stack, or releases them if `n` is negative.
+    fn grow(&mut self, n: i64) -> Result<(), VmError> {
+        let sp = (self.sp as i64).checked_add(n).ok_or(VmError::StackOverflow { ip: self.ip })?;
+        if sp > self.memory.len() as i64 {
+            return Err(VmError::StackOverflow { ip: self.ip });
+        }
+        if sp < GLOBAL_SIZE as i64 {
+            return Err(VmError::StackUnderflow { ip: self.ip });
+        }
+        self.sp = sp as usize;
+        Ok(())
+    }
+
+    /// Releases `n` bytes from the top of the stack.
+    fn shrink(&mut self, n: i64) -> Result<(), VmError> {
+        self.grow(n.checked_neg().ok_or(VmError::StackUnderflow { ip: self.ip })?)
+    }
+
+    /// Writes the instructions, one per line.
+    pub fn dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (index, instr) in self.code.iter().enumerate() {
+            let args = instr.args;
+            write!(out, "{:5}  {}", index, instr.opcode.name())?;
+            match instr.opcode {
+                Opcode::OCall | Opcode::OJmp | Opcode::OJfI | Opcode::OJfD | Opcode::OJtI | Opcode::OJtD
+                    | Opcode::OPushCtA => write!(out, "\t{}", args[0].addr())?,
+                Opcode::OCallExt => {
+                    write!(out, "\t{}", self.natives.get(args[0].addr()).map_or("?", |(name, ..)| name.as_str()))?
+                }
+                Opcode::OPushCtC => write!(out, "\t'{}'", (args[0].i() as u8 as char).escape_default())?,
+                Opcode::OPushCtD => write!(out, "\t{}", args[0].d())?,
+                Opcode::OInsert | Opcode::ORet => write!(out, "\t{},{}", args[0].i(), args[1].i())?,
+                Opcode::ODrop | Opcode::OEnter | Opcode::OLoad | Opcode::OPushFpAddr
+                    | Opcode::OPushCtI | Opcode::OStore => write!(out, "\t{}", args[0].i())?,
+                _ => (),
             }
-            Opcode::OHalt => {
-                println!("HALT");
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the program from its first instruction until `HALT`, on the standard input and output.
+    pub fn run(&mut self) -> Result<ExitState, VmError> {
+        let mut io = Io { input: &mut io::stdin().lock(), output: &mut io::stdout() };
+        self.run_with(&mut io)
+    }
+
+    /// Runs the program from its first instruction until `HALT`.
+    pub fn run_with(&mut self, io: &mut Io) -> Result<ExitState, VmError> {
+        let result = self.execute(io);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush().ok();
+        }
+        result
+    }
+
+    /// The types of the values `instr` pops from the stack, deepest first: the arguments
+    /// of an external function, the value a function returns or the one being stored.
+    pub fn stack_operands(&self, instr: &Instr) -> Vec<ValueType> {
+        let [arg1, arg2] = instr.args;
+        match instr.opcode {
+            Opcode::OCallExt => self.natives.get(arg1.addr()).map_or(Vec::new(), |native| native.2.to_vec()),
+            Opcode::ORet if arg2.i() > 0 => vec![ValueType::Bytes(arg2.i() as usize)],
+            Opcode::OStore => vec![ValueType::Addr, ValueType::Bytes(arg1.i() as usize)],
+            opcode => opcode.stack_operands().to_vec(),
+        }
+    }
+
+    fn trace(&mut self, instr: &Instr, steps: u64) {
+        if self.tracer.is_none() {
+            return;
+        }
+        let stack_depth = self.sp - GLOBAL_SIZE;
+        // reads the operands from the top down, stopping at the bottom of the stack
+        let mut top = Vec::new();
+        let mut sp = self.sp;
+        for ty in self.stack_operands(instr).into_iter().rev() {
+            let Some(start) = sp.checked_sub(ty.size()).filter(|&start| start >= GLOBAL_SIZE) else {
                 break;
+            };
+            top.push(TraceValue::read(ty, &self.memory[start..sp]));
+            sp = start;
+        }
+        top.reverse();
+        let record = TraceRecord {
+            step: steps,
+            index: self.ip,
+            mnemonic: instr.opcode.name(),
+            operands: instr.args.iter().copied().filter(|arg| *arg != Arg::None).collect(),
+            stack_depth,
+            top,
+        };
+        // a trace which cannot be written must not stop the program
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(&record).ok();
+        }
+    }
+
+    fn execute(&mut self, io: &mut Io) -> Result<ExitState, VmError> {
+        let mut i_val1: i64;
+        let mut i_val2: i64;
+        let mut d_val1: f64;
+        let mut d_val2: f64;
+        let mut c_val1: u8;
+        let mut c_val2: u8;
+        let mut a_val1: usize;
+        let mut old_sp: usize;
+        let mut ip = 0;
+        let mut steps = 0;
+
+        self.sp = GLOBAL_SIZE;
+        self.fp = GLOBAL_SIZE;
+
+        loop {
+            self.ip = ip;
+            let Some(&instr) = self.code.get(ip) else {
+                return Err(VmError::InvalidOpcode { ip });
+            };
+            steps += 1;
+            self.trace(&instr, steps);
+
+            let opcode = instr.opcode;
+            let args = instr.args;
+            ip += 1;
+            match opcode {
+                Opcode::OAddI | Opcode::OSubI | Opcode::OMulI | Opcode::ODivI => {
+                    i_val1 = self.popi()?;
+                    i_val2 = self.popi()?;
+                    let result = match opcode {
+                        Opcode::OAddI => i_val2.wrapping_add(i_val1),
+                        Opcode::OSubI => i_val2.wrapping_sub(i_val1),
+                        Opcode::OMulI => i_val2.wrapping_mul(i_val1),
+                        _ => {
+                            if i_val1 == 0 {
+                                return Err(VmError::DivisionByZero { ip: self.ip });
+                            }
+                            i_val2.wrapping_div(i_val1)
+                        }
+                    };
+                    self.pushi(result)?;
+                }
+                Opcode::OAddD | Opcode::OSubD | Opcode::OMulD | Opcode::ODivD => {
+                    d_val1 = self.popd()?;
+                    d_val2 = self.popd()?;
+                    let result = match opcode {
+                        Opcode::OAddD => d_val2 + d_val1,
+                        Opcode::OSubD => d_val2 - d_val1,
+                        Opcode::OMulD => d_val2 * d_val1,
+                        _ => d_val2 / d_val1,
+                    };
+                    self.pushd(result)?;
+                }
+                Opcode::OAddC | Opcode::OSubC | Opcode::OMulC | Opcode::ODivC => {
+                    c_val1 = self.popc()?;
+                    c_val2 = self.popc()?;
+                    let result = match opcode {
+                        Opcode::OAddC => c_val2.wrapping_add(c_val1),
+                        Opcode::OSubC => c_val2.wrapping_sub(c_val1),
+                        Opcode::OMulC => c_val2.wrapping_mul(c_val1),
+                        _ => {
+                            if c_val1 == 0 {
+                                return Err(VmError::DivisionByZero { ip: self.ip });
+                            }
+                            c_val2 / c_val1
+                        }
+                    };
+                    self.pushc(result)?;
+                }
+                Opcode::OEqI | Opcode::ONotEqI | Opcode::OLessI | Opcode::OLessEqI
+                    | Opcode::OGreaterI | Opcode::OGreaterEqI => {
+                    i_val1 = self.popi()?;
+                    i_val2 = self.popi()?;
+                    let result = compare(opcode, i_val2, i_val1) as i64;
+                    self.pushi(result)?;
+                }
+                Opcode::OEqD | Opcode::ONotEqD | Opcode::OLessD | Opcode::OLessEqD
+                    | Opcode::OGreaterD | Opcode::OGreaterEqD => {
+                    d_val1 = self.popd()?;
+                    d_val2 = self.popd()?;
+                    let result = compare(opcode, d_val2, d_val1) as i64;
+                    self.pushi(result)?;
+                }
+                Opcode::OEqC | Opcode::ONotEqC | Opcode::OLessC | Opcode::OLessEqC
+                    | Opcode::OGreaterC | Opcode::OGreaterEqC => {
+                    c_val1 = self.popc()?;
+                    c_val2 = self.popc()?;
+                    let result = compare(opcode, c_val2, c_val1) as i64;
+                    self.pushi(result)?;
+                }
+                Opcode::ONegI => {
+                    i_val1 = self.popi()?;
+                    self.pushi(i_val1.wrapping_neg())?;
+                }
+                Opcode::ONegD => {
+                    d_val1 = self.popd()?;
+                    self.pushd(-d_val1)?;
+                }
+                Opcode::ONegC => {
+                    c_val1 = self.popc()?;
+                    self.pushc(c_val1.wrapping_neg())?;
+                }
+                Opcode::ONotI => {
+                    i_val1 = self.popi()?;
+                    self.pushi((i_val1 == 0) as i64)?;
+                }
+                Opcode::ONotD => {
+                    d_val1 = self.popd()?;
+                    self.pushi((d_val1 == 0.0) as i64)?;
+                }
+                Opcode::ONotC => {
+                    c_val1 = self.popc()?;
+                    self.pushi((c_val1 == 0) as i64)?;
+                }
+                Opcode::OCall => {
+                    a_val1 = args[0].addr();
+                    self.pusha(ip)?;
+                    ip = a_val1;
+                }
+                Opcode::OCallExt => {
+                    a_val1 = args[0].addr();
+                    match self.natives.get(a_val1) {
+                        Some(&(_, func, _)) => func(self, io)?,
+                        None => return Err(VmError::InvalidOpcode { ip: self.ip }),
+                    }
+                }
+                Opcode::OCastID => {
+                    i_val1 = self.popi()?;
+                    d_val1 = i_val1 as f64;
+                    self.pushd(d_val1)?;
+                }
+                Opcode::OCastIC => {
+                    i_val1 = self.popi()?;
+                    self.pushc(i_val1 as u8)?;
+                }
+                Opcode::OCastDI => {
+                    d_val1 = self.popd()?;
+                    i_val1 = d_val1 as i64;
+                    self.pushi(i_val1)?;
+                }
+                Opcode::OCastDC => {
+                    d_val1 = self.popd()?;
+                    c_val1 = d_val1 as i64 as u8;
+                    self.pushc(c_val1)?;
+                }
+                Opcode::OCastCI => {
+                    c_val1 = self.popc()?;
+                    self.pushi(c_val1 as i64)?;
+                }
+                Opcode::OCastCD => {
+                    c_val1 = self.popc()?;
+                    self.pushd(c_val1 as f64)?;
+                }
+                Opcode::ODrop => {
+                    i_val1 = args[0].i();
+                    self.shrink(i_val1)?;
+                }
+                Opcode::OEnter => {
+                    i_val1 = args[0].i();
+                    self.pusha(self.fp)?;
+                    self.fp = self.sp;
+                    self.grow(i_val1)?;
+                }
+                Opcode::OHalt => {
+                    break;
+                }
+                Opcode::ONoReturn => {
+                    return Err(VmError::MissingReturn { ip: self.ip });
+                }
+                Opcode::OInsert => {
+                    i_val1 = args[0].i();
+                    i_val2 = args[1].i();
+                    let (len, gap) = (i_val1 as usize, i_val2 as usize);
+                    let src = self.sp.checked_sub(len).filter(|&src| src >= GLOBAL_SIZE)
+                        .ok_or(VmError::StackUnderflow { ip: self.ip })?;
+                    let dst = src.checked_add(gap).ok_or(VmError::BadAddress { ip: self.ip, addr: src })?;
+                    old_sp = self.sp;
+                    self.grow(i_val2)?;
+                    // moves the top len bytes up by gap and copies the top gap bytes into the gap
+                    self.copy(src, dst, len)?;
+                    self.copy(old_sp, src, gap)?;
+                }
+                Opcode::OJmp => {
+                    ip = args[0].addr();
+                }
+                Opcode::OJfI | Opcode::OJtI => {
+                    i_val1 = self.popi()?;
+                    if (i_val1 != 0) == (opcode == Opcode::OJtI) {
+                        ip = args[0].addr();
+                    }
+                }
+                Opcode::OJfD | Opcode::OJtD => {
+                    d_val1 = self.popd()?;
+                    if (d_val1 != 0.0) == (opcode == Opcode::OJtD) {
+                        ip = args[0].addr();
+                    }
+                }
+                Opcode::OLoad => {
+                    i_val1 = args[0].i();
+                    a_val1 = self.popa()?;
+                    let bytes = self.read(a_val1, i_val1 as usize)?.to_vec();
+                    self.push(&bytes)?;
+                }
+                Opcode::OOffset => {
+                    i_val1 = self.popi()?;
+                    a_val1 = self.popa()?;
+                    self.pusha(a_val1.wrapping_add_signed(i_val1 as isize))?;
+                }
+                Opcode::OPushFpAddr => {
+                    i_val1 = args[0].i();
+                    a_val1 = self.fp.wrapping_add_signed(i_val1 as isize);
+                    self.pusha(a_val1)?;
+                }
+                Opcode::OPushCtA => {
+                    a_val1 = args[0].addr();
+                    self.pusha(a_val1)?;
+                }
+                Opcode::OPushCtC => {
+                    c_val1 = args[0].i() as u8;
+                    self.pushc(c_val1)?;
+                }
+                Opcode::OPushCtD => {
+                    d_val1 = args[0].d();
+                    self.pushd(d_val1)?;
+                }
+                Opcode::OPushCtI => {
+                    i_val1 = args[0].i();
+                    self.pushi(i_val1)?;
+                }
+                Opcode::ORet => {
+                    i_val1 = args[0].i();
+                    i_val2 = args[1].i();
+                    old_sp = self.sp;
+                    self.sp = self.fp;
+                    self.fp = self.popa()?;
+                    ip = self.popa()?;
+                    self.shrink(i_val1)?;
+                    let dst = self.sp;
+                    self.grow(i_val2)?;
+                    let src = old_sp.checked_sub(i_val2 as usize).ok_or(VmError::StackUnderflow { ip: self.ip })?;
+                    self.copy(src, dst, i_val2 as usize)?;
+                }
+                Opcode::OStore => {
+                    i_val1 = args[0].i();
+                    let len = i_val1 as usize;
+                    let addr_at = self.sp.checked_sub(len).and_then(|sp| sp.checked_sub(ADDR_SIZE))
+                        .filter(|&addr_at| addr_at >= GLOBAL_SIZE)
+                        .ok_or(VmError::StackUnderflow { ip: self.ip })?;
+                    a_val1 = u64::from_ne_bytes(self.memory[addr_at..addr_at + ADDR_SIZE].try_into().unwrap()) as usize;
+                    self.copy(self.sp - len, a_val1, len)?;
+                    self.sp = addr_at;
+                }
             }
-            Opcode::OInsert => {
-                i_val1 = (*ip).args[0].i;
-                i_val2 = (*ip).args[1].i;
-                println!("INSERT\t{},{}", i_val1, i_val2);
-                if SP.add(i_val2 as usize) > STACK_AFTER {
-                    err("out of stack");
-                }
-                ptr::copy(SP.sub(i_val1 as usize), SP.add(i_val2 as usize).sub(i_val1 as usize), i_val1 as usize);
-                ptr::copy(SP.sub(i_val1 as usize), SP.sub(i_val1 as usize).add(i_val2 as usize), i_val2 as usize);
-                SP = SP.add(i_val2 as usize);
-                ip = (*ip).next;
-            }
-            Opcode::OJtI => {
-                i_val1 = popi();
-                println!("JT\t{:?}\t({})", (*ip).args[0].addr, i_val1);
-                ip = if i_val1 != 0 {
-                    (*ip).args[0].addr as *mut Instr
-                } else {
-                    (*ip).next
-                };
-            }
-            Opcode::OLoad => {
-                i_val1 = (*ip).args[0].i;
-                a_val1 = popa();
-                println!("LOAD\t{}\t({:?})", i_val1, a_val1);
-                if SP.add(i_val1 as usize) > STACK_AFTER {
-                    err("out of stack");
-                }
-                ptr::copy_nonoverlapping(a_val1, SP, i_val1 as usize);
-                SP = SP.add(i_val1 as usize);
-                ip = (*ip).next;
-            }
-            Opcode::OOffset => {
-                i_val1 = popi();
-                a_val1 = popa();
-                println!("({}+{} -> {:?})", a_val1 as usize, i_val1, (a_val1 as usize + i_val1 as usize) as *mut u8);
-                pusha((a_val1 as usize + i_val1 as usize) as *mut u8);
-                ip = (*ip).next;
-            }
-            Opcode::OPushFpAddr => {
-                i_val1 = (*ip).args[0].i;
-                println!("PUSHFPADDR\t{}\t({:?})", i_val1, (fp.offset(i_val1 as isize)));
-                pusha(fp.offset(i_val1 as isize));
-                ip = (*ip).next;
-            }
-            Opcode::OPushCtA => {
-                a_val1 = (*ip).args[0].addr;
-                println!("PUSHCT_A\t{:?}", a_val1);
-                pusha(a_val1);
-                ip = (*ip).next;
-            }
-            Opcode::OPushCtI => {
-                i_val1 = (*ip).args[0].i;
-                println!("PUSHCT_I\t{:p}", i_val1 as *const ());
-                pushi(i_val1);
-                ip = (*ip).next;
-            }
-            Opcode::ORet => {
-                i_val1 = (*ip).args[0].i;
-                i_val2 = (*ip).args[1].i;
-                println!("RET\t{},{}", i_val1, i_val2);
-                old_sp = SP;
-                SP = fp;
-                fp = popa();
-                ip = popa() as *mut Instr;
-                if SP.sub(i_val1 as usize) < STACK {
-                    err("not enough stack bytes");
-                }
-                SP = SP.sub(i_val1 as usize);
-                ptr::copy(SP, old_sp.sub(i_val2 as usize), i_val2 as usize);
-                SP = SP.add(i_val2 as usize);
-            }
-            Opcode::OStore => {
-                i_val1 = (*ip).args[0].i;
-                if SP.sub(size_of::<*mut u8>().wrapping_add(i_val1 as usize)) < STACK {
-                    err("not enough stack bytes for SET");
-                }
-                a_val1 = *(SP.sub(size_of::<*mut u8>().wrapping_add(i_val1 as usize)) as *mut *mut u8);
-                println!("STORE\t{}\t({:?})", i_val1, a_val1);
-                ptr::copy_nonoverlapping(SP.sub(i_val1 as usize), a_val1, i_val1 as usize);
-                SP = SP.sub(size_of::<*mut u8>().wrapping_add(i_val1 as usize));
-                ip = (*ip).next;
-            }
-            Opcode::OSubD => {
-                d_val1 = popd();
-                d_val2 = popd();
-                println!("SUB_D\t({}-{} -> {})", d_val2, d_val1, d_val2 - d_val1);
-                pushd(d_val2 - d_val1);
-                ip = (*ip).next;
-            }
-            Opcode::OAddI => {
-                d_val1 = popi() as f64;
-                d_val2 = popi() as f64;
-                println!("ADD_I\t({}+{} -> {})", d_val2, d_val1, d_val2 + d_val1);
-                pushd(d_val2 + d_val1);
-                ip = (*ip).next;
-            }
-            _ => {
-                err(&format!("invalid opcode: {:?}", (*ip).opcode));
-            }
         }
+
+        Ok(ExitState { steps, stack: self.memory[GLOBAL_SIZE..self.sp].to_vec() })
+    }
+}
+
+/// Applies the relational operator of `opcode`, whatever its operand type.
+fn compare<T: PartialOrd>(opcode: Opcode, a: T, b: T) -> bool {
+    match opcode {
+        Opcode::OEqI | Opcode::OEqD | Opcode::OEqC => a == b,
+        Opcode::ONotEqI | Opcode::ONotEqD | Opcode::ONotEqC => a != b,
+        Opcode::OLessI | Opcode::OLessD | Opcode::OLessC => a < b,
+        Opcode::OLessEqI | Opcode::OLessEqD | Opcode::OLessEqC => a <= b,
+        Opcode::OGreaterI | Opcode::OGreaterD | Opcode::OGreaterC => a > b,
+        _ => a >= b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the instructions added by `code` and returns what they leave on the stack.
+    fn eval(code: impl FnOnce(&mut Vm)) -> Vec<u8> {
+        let mut vm = Vm::new();
+        code(&mut vm);
+        vm.add_instr(Opcode::OHalt);
+        vm.run().unwrap().stack
+    }
+
+    fn eval_i(code: impl FnOnce(&mut Vm)) -> i64 {
+        i64::from_ne_bytes(eval(code).try_into().unwrap())
+    }
+
+    fn eval_d(code: impl FnOnce(&mut Vm)) -> f64 {
+        f64::from_ne_bytes(eval(code).try_into().unwrap())
+    }
+
+    fn eval_c(code: impl FnOnce(&mut Vm)) -> u8 {
+        let stack = eval(code);
+        assert_eq!(stack.len(), 1);
+        stack[0]
+    }
+
+    #[test]
+    fn test_int_ops() {
+        let cases = [
+            (Opcode::OAddI, 7, 3, 10), (Opcode::OSubI, 7, 3, 4), (Opcode::OMulI, 7, 3, 21),
+            (Opcode::ODivI, 7, 3, 2), (Opcode::OEqI, 7, 3, 0), (Opcode::ONotEqI, 7, 3, 1),
+            (Opcode::OLessI, 3, 7, 1), (Opcode::OLessEqI, 7, 7, 1), (Opcode::OGreaterI, 3, 7, 0),
+            (Opcode::OGreaterEqI, 7, 3, 1),
+        ];
+        for (opcode, a, b, expected) in cases {
+            let result = eval_i(|vm| {
+                vm.add_instr_i(Opcode::OPushCtI, a);
+                vm.add_instr_i(Opcode::OPushCtI, b);
+                vm.add_instr(opcode);
+            });
+            assert_eq!(result, expected, "{}", opcode.name());
+        }
+    }
+
+    #[test]
+    fn test_double_ops() {
+        let cases = [
+            (Opcode::OAddD, 7.5, 2.5, 10.0), (Opcode::OSubD, 7.5, 2.5, 5.0),
+            (Opcode::OMulD, 7.5, 2.0, 15.0), (Opcode::ODivD, 7.5, 2.5, 3.0),
+        ];
+        for (opcode, a, b, expected) in cases {
+            let result = eval_d(|vm| {
+                vm.add_instr_d(Opcode::OPushCtD, a);
+                vm.add_instr_d(Opcode::OPushCtD, b);
+                vm.add_instr(opcode);
+            });
+            assert_eq!(result, expected, "{}", opcode.name());
+        }
+
+        let cases = [
+            (Opcode::OEqD, 2.5, 2.5, 1), (Opcode::ONotEqD, 2.5, 2.5, 0), (Opcode::OLessD, 2.5, 7.5, 1),
+            (Opcode::OLessEqD, 7.5, 2.5, 0), (Opcode::OGreaterD, 7.5, 2.5, 1), (Opcode::OGreaterEqD, 2.5, 7.5, 0),
+        ];
+        for (opcode, a, b, expected) in cases {
+            let result = eval_i(|vm| {
+                vm.add_instr_d(Opcode::OPushCtD, a);
+                vm.add_instr_d(Opcode::OPushCtD, b);
+                vm.add_instr(opcode);
+            });
+            assert_eq!(result, expected, "{}", opcode.name());
+        }
+    }
+
+    #[test]
+    fn test_char_ops() {
+        let cases = [
+            (Opcode::OAddC, b'a', 2, b'c'), (Opcode::OSubC, b'c', b'a', 2),
+            (Opcode::OMulC, 6, 7, 42), (Opcode::ODivC, b'z', 2, 61),
+        ];
+        for (opcode, a, b, expected) in cases {
+            let result = eval_c(|vm| {
+                vm.add_instr_i(Opcode::OPushCtC, a as i64);
+                vm.add_instr_i(Opcode::OPushCtC, b as i64);
+                vm.add_instr(opcode);
+            });
+            assert_eq!(result, expected, "{}", opcode.name());
+        }
+
+        let cases = [
+            (Opcode::OEqC, b'a', b'a', 1), (Opcode::ONotEqC, b'a', b'b', 1), (Opcode::OLessC, b'b', b'a', 0),
+            (Opcode::OLessEqC, b'a', b'b', 1), (Opcode::OGreaterC, b'b', b'a', 1), (Opcode::OGreaterEqC, b'a', b'b', 0),
+        ];
+        for (opcode, a, b, expected) in cases {
+            let result = eval_i(|vm| {
+                vm.add_instr_i(Opcode::OPushCtC, a as i64);
+                vm.add_instr_i(Opcode::OPushCtC, b as i64);
+                vm.add_instr(opcode);
+            });
+            assert_eq!(result, expected, "{}", opcode.name());
+        }
+    }
+
+    #[test]
+    fn test_unary_ops() {
+        assert_eq!(eval_i(|vm| { vm.add_instr_i(Opcode::OPushCtI, 5); vm.add_instr(Opcode::ONegI); }), -5);
+        assert_eq!(eval_d(|vm| { vm.add_instr_d(Opcode::OPushCtD, 1.5); vm.add_instr(Opcode::ONegD); }), -1.5);
+        assert_eq!(eval_c(|vm| { vm.add_instr_i(Opcode::OPushCtC, 1); vm.add_instr(Opcode::ONegC); }), 255);
+        assert_eq!(eval_i(|vm| { vm.add_instr_i(Opcode::OPushCtI, 5); vm.add_instr(Opcode::ONotI); }), 0);
+        assert_eq!(eval_i(|vm| { vm.add_instr_d(Opcode::OPushCtD, 0.0); vm.add_instr(Opcode::ONotD); }), 1);
+        assert_eq!(eval_i(|vm| { vm.add_instr_i(Opcode::OPushCtC, 0); vm.add_instr(Opcode::ONotC); }), 1);
+    }
+
+    #[test]
+    fn test_casts() {
+        assert_eq!(eval_d(|vm| { vm.add_instr_i(Opcode::OPushCtI, 3); vm.add_instr(Opcode::OCastID); }), 3.0);
+        assert_eq!(eval_c(|vm| { vm.add_instr_i(Opcode::OPushCtI, 65); vm.add_instr(Opcode::OCastIC); }), b'A');
+        assert_eq!(eval_i(|vm| { vm.add_instr_d(Opcode::OPushCtD, -2.7); vm.add_instr(Opcode::OCastDI); }), -2);
+        assert_eq!(eval_c(|vm| { vm.add_instr_d(Opcode::OPushCtD, 66.9); vm.add_instr(Opcode::OCastDC); }), b'B');
+        assert_eq!(eval_i(|vm| { vm.add_instr_i(Opcode::OPushCtC, b'0' as i64); vm.add_instr(Opcode::OCastCI); }), 48);
+        assert_eq!(eval_d(|vm| { vm.add_instr_i(Opcode::OPushCtC, 200); vm.add_instr(Opcode::OCastCD); }), 200.0);
+    }
+
+    #[test]
+    fn test_jumps() {
+        let branch = |jump: Opcode, cond: f64| eval_i(|vm| {
+            match jump {
+                Opcode::OJfD | Opcode::OJtD => vm.add_instr_d(Opcode::OPushCtD, cond),
+                _ => vm.add_instr_i(Opcode::OPushCtI, cond as i64),
+            };
+            let jump = vm.add_instr(jump);
+            vm.add_instr_i(Opcode::OPushCtI, 1);
+            let skip = vm.add_instr(Opcode::OJmp);
+            let target = vm.add_instr_i(Opcode::OPushCtI, 2);
+            vm.set_target(jump, target);
+            vm.set_target(skip, vm.next_index());
+        });
+        assert_eq!(branch(Opcode::OJfI, 0.0), 2);
+        assert_eq!(branch(Opcode::OJtI, 0.0), 1);
+        assert_eq!(branch(Opcode::OJfD, 0.5), 1);
+        assert_eq!(branch(Opcode::OJtD, 0.5), 2);
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut vm = Vm::new();
+        let global = vm.alloc_global(8).unwrap();
+        // global = 40 + 2, through a call to a function taking one int argument
+        vm.add_instr_a(Opcode::OPushCtA, global);
+        vm.add_instr_i(Opcode::OPushCtI, 40);
+        let call = vm.add_instr(Opcode::OCall);
+        vm.add_instr_i(Opcode::OStore, 8);
+        vm.add_instr(Opcode::OHalt);
+        let func = vm.add_instr_i(Opcode::OEnter, 8);
+        vm.add_instr_i(Opcode::OPushFpAddr, 0);
+        vm.add_instr_i(Opcode::OPushFpAddr, -24);
+        vm.add_instr_i(Opcode::OLoad, 8);
+        vm.add_instr_i(Opcode::OPushCtI, 2);
+        vm.add_instr(Opcode::OAddI);
+        vm.add_instr_ii(Opcode::OInsert, 16, 8);
+        vm.add_instr_i(Opcode::OStore, 8);
+        vm.add_instr_ii(Opcode::ORet, 8, 8);
+        vm.set_target(call, func);
+
+        let state = vm.run().unwrap();
+        assert!(state.stack.is_empty());
+        assert_eq!(state.steps, 14);
+        assert_eq!(vm.read(global, 8).unwrap(), 42i64.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_errors() {
+        let run = |code: &dyn Fn(&mut Vm)| {
+            let mut vm = Vm::new();
+            code(&mut vm);
+            vm.add_instr(Opcode::OHalt);
+            vm.run()
+        };
+
+        let result = run(&|vm| {
+            vm.add_instr_a(Opcode::OPushCtA, usize::MAX - 2);
+            vm.add_instr_i(Opcode::OLoad, 8);
+        });
+        assert_eq!(result, Err(VmError::BadAddress { ip: 1, addr: usize::MAX - 2 }));
+
+        let result = run(&|vm| {
+            vm.add_instr_i(Opcode::OPushCtI, 1);
+            vm.add_instr_i(Opcode::OPushCtI, 0);
+            vm.add_instr(Opcode::ODivI);
+        });
+        assert_eq!(result, Err(VmError::DivisionByZero { ip: 2 }));
+
+        let result = run(&|vm| {
+            vm.add_instr_i(Opcode::OPushCtI, 1);
+            vm.add_instr(Opcode::ONoReturn);
+        });
+        assert_eq!(result, Err(VmError::MissingReturn { ip: 1 }));
+
+        let result = run(&|vm| {
+            vm.add_instr_i(Opcode::OPushCtC, 1);
+            vm.add_instr(Opcode::OAddC);
+        });
+        assert_eq!(result, Err(VmError::StackUnderflow { ip: 1 }));
+
+        let result = run(&|vm| {
+            vm.add_instr_i(Opcode::OEnter, STACK_SIZE as i64);
+        });
+        assert_eq!(result, Err(VmError::StackOverflow { ip: 0 }));
+
+        let result = run(&|vm| {
+            let call = vm.add_instr(Opcode::OCall);
+            vm.add_instr(Opcode::OHalt);
+            vm.set_target(call, call);
+        });
+        assert_eq!(result, Err(VmError::StackOverflow { ip: 0 }));
+
+        let result = run(&|vm| {
+            vm.add_instr_a(Opcode::OJmp, 100);
+        });
+        assert_eq!(result, Err(VmError::InvalidOpcode { ip: 100 }));
+
+        // negative sizes must not wrap around the address space
+        let result = run(&|vm| {
+            vm.add_instr_i(Opcode::OPushCtI, 1);
+            vm.add_instr_i(Opcode::OStore, -1);
+        });
+        assert_eq!(result, Err(VmError::StackUnderflow { ip: 1 }));
+
+        let result = run(&|vm| {
+            vm.add_instr_i(Opcode::OPushCtI, 1);
+            vm.add_instr_ii(Opcode::OInsert, -1, 0);
+        });
+        assert_eq!(result, Err(VmError::StackUnderflow { ip: 1 }));
+
+        let result = run(&|vm| {
+            vm.add_instr_i(Opcode::OPushCtI, 1);
+            vm.add_instr_ii(Opcode::OInsert, 8, -1);
+        });
+        assert_eq!(result, Err(VmError::BadAddress { ip: 1, addr: GLOBAL_SIZE }));
+
+        let result = run(&|vm| {
+            vm.add_instr_i(Opcode::ODrop, i64::MIN);
+        });
+        assert_eq!(result, Err(VmError::StackUnderflow { ip: 0 }));
+
+        let result = run(&|vm| {
+            vm.add_instr_i(Opcode::OEnter, i64::MAX);
+        });
+        assert_eq!(result, Err(VmError::StackOverflow { ip: 0 }));
+
+        let result = run(&|vm| {
+            let call = vm.add_instr(Opcode::OCall);
+            vm.add_instr(Opcode::OHalt);
+            let ret = vm.add_instr_ii(Opcode::ORet, 0, -1);
+            vm.set_target(call, ret);
+        });
+        assert_eq!(result, Err(VmError::StackUnderflow { ip: 2 }));
+
+        let mut vm = Vm::new();
+        assert!(vm.alloc_global(GLOBAL_SIZE - 1).is_ok());
+        assert_eq!(vm.alloc_global(2), Err(VmError::GlobalsExhausted { size: 2 }));
     }
 }